@@ -1,34 +1,66 @@
+use std::ops::Range;
 use std::sync::mpsc::{channel, Receiver, Sender};
 
-use egui::text::CCursorRange;
-use egui::util::cache::{ComputerMut, FrameCache};
-use egui::{
-    text::{CursorRange, LayoutJob},
-    text_selection::text_cursor_state::{ccursor_next_word, ccursor_previous_word},
-    vec2, Align2, Event, EventFilter, FontId, Key, Margin, NumExt, Sense, Shape, TextBuffer, Vec2,
-};
-use egui::{Color32, Rect, TextFormat};
-use epaint::text::cursor::PCursor;
+use egui::text::{CCursor, CCursorRange};
+use egui::Rect;
+use egui::{vec2, Event, EventFilter, FontId, Key, Margin, NumExt, Sense, Shape, Vec2};
 use relative_path::PathExt;
 
+use crate::buffer::Buffer;
+use crate::command::Command;
+use crate::comment;
+use crate::emacs::{self, Emacs};
+use crate::history::{EditAction, EditKind, History};
+use crate::indent::{self, IndentEdit, Indentation};
+use crate::keymap::{Keymap, Matched};
+use crate::language::Language;
+use crate::line_ending::{self, LineEnding, LineEndings};
+use crate::lines::{self, LineOperation, LinesEdit};
+use crate::motion::Motion;
+use crate::palette::{Outcome, Palette};
+use crate::selection::{Block, Selections};
+use crate::view::{self, LineLayout};
+use crate::vim::{self, Vim, VimKey};
+
+/// Presses closer together than this, in seconds, count as a double or triple click.
+const MAX_DOUBLE_CLICK_DELAY: f64 = 0.3;
+
+/// How far apart, in points, presses can be and still count as a double or triple click.
+const MAX_CLICK_DIST: f32 = 6.0;
+
+/// A press of the primary pointer button in the text.
+#[derive(Clone, Copy)]
+struct Press {
+    /// When the press happened, as given by `egui::InputState::time`.
+    time: f64,
+
+    pos: egui::Pos2,
+
+    /// 1 for a single click, 2 for a double click and 3 for a triple click.
+    clicks: usize,
+
+    /// What the press selected, which dragging extends from. Just the anchor for a Shift click.
+    selection: CCursorRange,
+}
+
+/// Which editor the keys work like.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+enum Profile {
+    #[default]
+    Standard,
+
+    /// Keys are commands unless in insert mode.
+    Vim,
+
+    /// Ctrl and Alt keys move, kill and yank like in Emacs.
+    Emacs,
+}
+
 struct FileMessage {
     file: relative_path::RelativePathBuf,
     text: String,
 }
 
-enum EditAction {
-    Delete {
-        text: String,
-        selection_after: CCursorRange,
-    },
-    InsertAndDelete {
-        inserted_text: String,
-        deleted_text: String,
-        selection_after_delete: CCursorRange,
-        selection_before: CCursorRange,
-    },
-}
-
 /// We derive Deserialize/Serialize so we can persist app state on shutdown.
 #[derive(serde::Deserialize, serde::Serialize)]
 #[serde(default)] // if we add new fields, give them default values when deserializing old state
@@ -40,10 +72,30 @@ pub struct TemplateApp {
     cwd: std::path::PathBuf,
 
     #[serde(skip)]
-    text: String,
+    text: Buffer,
+
+    #[serde(skip)]
+    selections: Selections,
+
+    /// The rectangular selection `selections` was made from, as long as it can still be extended.
+    #[serde(skip)]
+    block: Option<Block>,
+
+    /// The text last copied from a rectangular selection, so it can be pasted as one.
+    #[serde(skip)]
+    block_clipboard: Option<String>,
+
+    #[serde(skip)]
+    last_press: Option<Press>,
+
+    /// Where vertical movement keeps each cursor horizontally, so moving through a short line
+    /// doesn't lose the position, and the selections it was recorded for. Any other change to the
+    /// selections, like moving sideways or editing, makes it stale.
+    #[serde(skip)]
+    preferred_x: Option<(Selections, Vec<f32>)>,
 
     #[serde(skip)]
-    selection: CursorRange,
+    line_layout: LineLayout,
 
     #[serde(skip)]
     file_channel: (Sender<FileMessage>, Receiver<FileMessage>),
@@ -52,7 +104,40 @@ pub struct TemplateApp {
     autofocus: bool,
 
     #[serde(skip)]
-    history: Vec<EditAction>,
+    history: History,
+
+    /// Commands to run, queued by shortcuts, menus and anything else that triggers them.
+    #[serde(skip)]
+    commands: Vec<Command>,
+
+    #[serde(skip)]
+    keymap: Keymap,
+
+    /// The command palette, while it's open.
+    #[serde(skip)]
+    palette: Option<Palette>,
+
+    #[serde(skip)]
+    vim: Vim,
+
+    #[serde(skip)]
+    emacs: Emacs,
+
+    /// How the open file is indented.
+    #[serde(skip)]
+    indentation: Indentation,
+
+    /// What the lines of the open file end with when it's saved.
+    #[serde(skip)]
+    line_ending: LineEnding,
+
+    /// Whether the lines of the open file ended in different ways when it was opened.
+    #[serde(skip)]
+    mixed_line_endings: bool,
+
+    show_history: bool,
+
+    profile: Profile,
 }
 
 impl Default for TemplateApp {
@@ -60,11 +145,26 @@ impl Default for TemplateApp {
         Self {
             file: relative_path::RelativePath::new(".").to_relative_path_buf(),
             cwd: std::env::current_dir().expect("Could not get current directory"),
-            text: "".to_owned(),
-            selection: CursorRange::default(),
+            text: Buffer::default(),
+            selections: Selections::default(),
+            block: None,
+            block_clipboard: None,
+            last_press: None,
+            preferred_x: None,
+            line_layout: LineLayout::default(),
             file_channel: channel(),
             autofocus: true,
-            history: Vec::new(),
+            history: History::default(),
+            commands: Vec::new(),
+            keymap: Keymap::default(),
+            palette: None,
+            vim: Vim::default(),
+            emacs: Emacs::default(),
+            indentation: Indentation::default(),
+            line_ending: LineEnding::default(),
+            mixed_line_endings: false,
+            show_history: false,
+            profile: Profile::Standard,
         }
     }
 }
@@ -99,6 +199,8 @@ impl TemplateApp {
 
         cc.egui_ctx.set_fonts(fonts);
 
+        let is_mac = cc.egui_ctx.os() == egui::os::OperatingSystem::Mac;
+
         // FIXME: This may be a slightly wonky way to open the file? The branching seems excessive at least.
         let config = if let Some(file) = file {
             let file = if std::path::Path::new(&file).is_absolute() {
@@ -136,9 +238,15 @@ impl TemplateApp {
                         file.as_str()
                     )));
 
+                let (text, indentation, line_endings) = load_text(&text);
+
                 Self {
+                    history: History::new(&text),
                     text,
                     file: file.to_relative_path_buf(),
+                    indentation,
+                    line_ending: line_endings.most_common(),
+                    mixed_line_endings: line_endings.is_mixed(),
                     ..Default::default()
                 }
             } else {
@@ -152,15 +260,711 @@ impl TemplateApp {
         // Load previous app state (if any).
         // Note that you must enable the `persistence` feature for this to work.
         if let Some(storage) = cc.storage {
+            let history = load_history(storage, &config.file.to_path(&cwd), &config.text)
+                .unwrap_or(config.history);
+
             // FIXME: Is there a better way to merge these structs? This seems slightly off.
             return Self {
                 text: config.text,
                 file: config.file,
+                history,
+                indentation: config.indentation,
+                line_ending: config.line_ending,
+                mixed_line_endings: config.mixed_line_endings,
+                keymap: Keymap::load(is_mac),
                 ..eframe::get_value(storage, eframe::APP_KEY).unwrap_or_default()
             };
         }
 
-        config
+        Self {
+            keymap: Keymap::load(is_mac),
+            ..config
+        }
+    }
+
+    /// Makes an edit at each of `selections` and records it in the undo history, grouped with the
+    /// edit before it if `kind` is given. Returns the selections after the edit.
+    fn edit(
+        &mut self,
+        selections: &Selections,
+        kind: Option<EditKind>,
+        time: f64,
+        edit: impl FnMut(&Buffer, CCursorRange) -> Option<EditAction>,
+    ) -> Option<Selections> {
+        self.history
+            .edit(&mut self.text, selections, kind, time, edit)
+    }
+
+    /// Replaces each selection with a new line, indented like the line it's split from, as one
+    /// edit. Returns the selections after it.
+    fn insert_newline(&mut self, time: f64) -> Option<Selections> {
+        let language = Language::from_path(self.file.as_str());
+        let selections_before = self.selections.clone();
+
+        // How far before the end of the inserted text each cursor ends up.
+        let mut backs = Vec::new();
+        let (action, selections) = EditAction::at_each_selection(
+            &mut self.text,
+            &selections_before,
+            |text, selection| {
+                let (inserted, back) = indent::newline(text, selection, language, self.indentation);
+                backs.push(back);
+                Some(EditAction::replace_selection(text, selection, &inserted))
+            },
+        )?;
+
+        let mut backs = backs.into_iter();
+        let selections = selections.map(|selection| {
+            CCursorRange::one(selection.primary - backs.next().unwrap_or_default())
+        });
+
+        self.history
+            .push(action.leaving(selections_before, selections.clone()), time);
+        Some(selections)
+    }
+
+    /// Indents every line with a selection on it by a level, or outdents them if `outdent`, as
+    /// one edit. Returns the selections after it.
+    fn indent_lines(&mut self, outdent: bool, time: f64) -> Option<Selections> {
+        let edits = indent::indent_lines(&self.text, &self.selections, self.indentation, outdent);
+        self.apply_indent_edits(&edits, time)
+    }
+
+    /// Makes `edits` as one edit, keeping the selections on the same text. Returns the selections
+    /// after it.
+    fn apply_indent_edits(&mut self, edits: &[IndentEdit], time: f64) -> Option<Selections> {
+        let selections_after = self.selections.map(|selection| CCursorRange {
+            primary: CCursor::new(indent::shift_index(selection.primary.index, edits)),
+            secondary: CCursor::new(indent::shift_index(selection.secondary.index, edits)),
+        });
+
+        self.replace_ranges(
+            edits.iter().map(|edit| {
+                (
+                    edit.start..edit.start + edit.removed,
+                    edit.inserted.as_str(),
+                )
+            }),
+            selections_after,
+            time,
+        )
+    }
+
+    /// Makes `operation` on the lines the selections are on, as one edit. Returns the selections
+    /// after it.
+    fn edit_lines(&mut self, operation: LineOperation, time: f64) -> Option<Selections> {
+        let (edits, selections_after) = lines::edit_lines(&self.text, &self.selections, operation)?;
+        self.apply_lines_edits(&edits, selections_after, time)
+    }
+
+    /// Toggles line comments on the lines the selections are on, or block comments around the
+    /// selections if `block`, as one edit. Returns the selections after it.
+    fn toggle_comments(&mut self, block: bool, time: f64) -> Option<Selections> {
+        let language = Language::from_path(self.file.as_str());
+        let (edits, selections_after) = if block {
+            comment::toggle_block_comments(&self.text, &self.selections, language)
+        } else {
+            comment::toggle_line_comments(&self.text, &self.selections, language)
+        }?;
+        self.apply_lines_edits(&edits, selections_after, time)
+    }
+
+    /// Makes `edits` as one edit leaving `selections_after`. Returns them.
+    fn apply_lines_edits(
+        &mut self,
+        edits: &[LinesEdit],
+        selections_after: Selections,
+        time: f64,
+    ) -> Option<Selections> {
+        self.replace_ranges(
+            edits
+                .iter()
+                .map(|edit| (edit.range.clone(), edit.text.as_str())),
+            selections_after,
+            time,
+        )
+    }
+
+    /// Replaces each of `replacements`, which are sorted and don't overlap, as one edit leaving
+    /// `selections_after`. Returns them, or `None` if there's nothing to replace.
+    fn replace_ranges<'a>(
+        &mut self,
+        replacements: impl Iterator<Item = (Range<usize>, &'a str)>,
+        selections_after: Selections,
+        time: f64,
+    ) -> Option<Selections> {
+        let (ranges, mut inserted): (Vec<_>, Vec<_>) = replacements
+            .map(|(range, text)| {
+                (
+                    CCursorRange::two(CCursor::new(range.start), CCursor::new(range.end)),
+                    text,
+                )
+            })
+            .unzip();
+        if ranges.is_empty() {
+            return None;
+        }
+
+        inserted.reverse();
+        let (action, _) = EditAction::at_each_selection(
+            &mut self.text,
+            &Selections::new(ranges, 0),
+            |text, selection| {
+                let inserted = inserted.pop().unwrap_or_default();
+                Some(EditAction::replace_selection(text, selection, inserted))
+            },
+        )?;
+
+        self.history.push(
+            action.leaving(self.selections.clone(), selections_after.clone()),
+            time,
+        );
+        Some(selections_after)
+    }
+
+    /// Copies the selected text to the clipboard.
+    fn copy(&mut self, ui: &egui::Ui) {
+        let text = self.copied_text();
+        self.block_clipboard = self.block.is_some().then(|| text.clone());
+        self.copy_text(ui, text);
+    }
+
+    /// Copies `text` to the system clipboard, which Vim's `+` and `*` registers put from.
+    fn copy_text(&mut self, ui: &egui::Ui, text: String) {
+        self.vim.set_clipboard(&text);
+        ui.ctx().copy_text(text);
+    }
+
+    /// The text to copy: what's selected, one line per selection, or the lines the cursors are on
+    /// if nothing is.
+    fn copied_text(&self) -> String {
+        if self.block.is_some() {
+            // Keep the rows of a rectangular selection that are past the end of their line.
+            self.selections
+                .ranges()
+                .iter()
+                .map(|selection| self.text.selected_text(selection))
+                .collect::<Vec<_>>()
+                .join("\n")
+        } else if self.selections.ranges().iter().all(is_empty) {
+            self.line_selections()
+                .ranges()
+                .iter()
+                .map(|selection| self.text.selected_text(selection))
+                .collect()
+        } else {
+            self.selections
+                .ranges()
+                .iter()
+                .filter(|selection| !is_empty(selection))
+                .map(|selection| self.text.selected_text(selection))
+                .collect::<Vec<_>>()
+                .join("\n")
+        }
+    }
+
+    /// The whole lines the cursors are on, including their newlines. Cursors on the same line
+    /// select it only once.
+    fn line_selections(&self) -> Selections {
+        self.selections
+            .map(|selection| self.selection_at(selection.primary, 3))
+    }
+
+    /// What clicking `clicks` times at `ccursor` selects: nothing, the word there, or the whole
+    /// line including its newline.
+    fn selection_at(&self, ccursor: CCursor, clicks: usize) -> CCursorRange {
+        match clicks {
+            1 => CCursorRange::one(ccursor),
+            2 => self.text.word_at(ccursor),
+            _ => {
+                let line = self.text.char_to_line(ccursor.index);
+                let range = self.text.line_char_range_with_newline(line);
+
+                CCursorRange::two(CCursor::new(range.start), CCursor::new(range.end))
+            }
+        }
+    }
+
+    /// The line and horizontal position of `ccursor`, as a corner of a rectangular selection.
+    fn block_corner(&mut self, ui: &egui::Ui, ccursor: CCursor) -> (usize, f32) {
+        let line = self.text.char_to_line(ccursor.index);
+        let x = self.line_layout.cursor_rect(ui, &self.text, ccursor).min.x;
+
+        (line, x)
+    }
+
+    /// One selection for each line of `block`. The one on the line of its head is the main one.
+    fn block_selections(&mut self, ui: &egui::Ui, block: Block) -> Selections {
+        let (anchor_line, anchor_x) = block.anchor;
+        let (head_line, head_x) = block.head;
+        let lines = anchor_line.min(head_line)..=anchor_line.max(head_line);
+
+        let ranges = lines
+            .clone()
+            .map(|line| {
+                CCursorRange::two(
+                    self.line_layout
+                        .cursor_in_line_at_x(ui, &self.text, line, anchor_x),
+                    self.line_layout
+                        .cursor_in_line_at_x(ui, &self.text, line, head_x),
+                )
+            })
+            .collect();
+
+        Selections::new(ranges, head_line - lines.start())
+    }
+
+    /// Pastes `text`, copied from a rectangular selection, as a rectangle with its top left corner
+    /// at the cursor. Lines that end before the rectangle are padded with spaces, and lines are
+    /// added to the end of the buffer if it's not long enough.
+    fn paste_block(&mut self, ui: &egui::Ui, text: &str, time: f64) -> Option<Selections> {
+        let (first_line, x) = self.block_corner(ui, self.selections.main().primary);
+        let char_width = ui.fonts(|f| f.glyph_width(&FontId::monospace(14.0), ' '));
+        let columns = |width: f32| (width / char_width).round().at_least(0.0) as usize;
+
+        let mut ranges = Vec::new();
+        let mut rows = Vec::new();
+        for (i, row) in text.lines().enumerate() {
+            let line = first_line + i;
+
+            if line < self.text.len_lines() {
+                let ccursor = self
+                    .line_layout
+                    .cursor_in_line_at_x(ui, &self.text, line, x);
+                let end_x = self.line_layout.cursor_rect(ui, &self.text, ccursor).min.x;
+
+                ranges.push(CCursorRange::one(ccursor));
+                rows.push(" ".repeat(columns(x - end_x)) + row);
+            } else if let Some(last) = rows.last_mut() {
+                last.push('\n');
+                last.push_str(&" ".repeat(columns(x)));
+                last.push_str(row);
+            }
+        }
+
+        if ranges.is_empty() {
+            return None;
+        }
+
+        let mut rows = rows.into_iter();
+        self.edit(
+            &Selections::new(ranges, 0),
+            None,
+            time,
+            |text, selection| {
+                let row = rows.next().unwrap_or_default();
+                Some(EditAction::replace_selection(text, selection, &row))
+            },
+        )
+    }
+
+    /// Runs `command`, returning the new selections if it changes them.
+    fn run(&mut self, ui: &egui::Ui, command: Command) -> Option<Selections> {
+        log::debug!("Running command {}", command.id());
+
+        match command {
+            Command::Open => {
+                open_file_with_native_dialog(ui, self.file_channel.0.clone(), self.cwd.clone());
+                None
+            }
+            Command::Save => {
+                save_text_to_file(self.file.as_str(), &self.text, self.line_ending);
+                self.history.mark_saved(&self.text);
+                self.mixed_line_endings = false;
+                None
+            }
+            Command::Quit => {
+                ui.ctx().send_viewport_cmd(egui::ViewportCommand::Close);
+                None
+            }
+            Command::Undo => self.history.undo(&mut self.text),
+            Command::Redo => self.history.redo(&mut self.text),
+            Command::SelectAll => {
+                self.history.seal();
+
+                Some(Selections::one(CCursorRange::two(
+                    CCursor::new(0),
+                    CCursor::new(self.text.len_chars()),
+                )))
+            }
+            Command::SelectNextOccurrence => {
+                self.history.seal();
+                Some(self.select_next_occurrence())
+            }
+            Command::AddCursorAbove => self.add_cursor_above(ui),
+            Command::AddCursorBelow => self.add_cursor_below(ui),
+            Command::IndentWithSpaces | Command::IndentWithTabs => {
+                let tabs = command == Command::IndentWithTabs;
+                let edits = indent::convert_indentation(&self.text, self.indentation, tabs);
+                self.indentation.tabs = tabs;
+
+                // Each tab is a level of indentation now, so show it as wide as the spaces were.
+                if tabs {
+                    self.indentation.tab_width = self.indentation.width;
+                }
+                self.apply_indent_edits(&edits, unix_time())
+            }
+            Command::UseLfLineEndings | Command::UseCrlfLineEndings => {
+                self.line_ending = if command == Command::UseLfLineEndings {
+                    LineEnding::Lf
+                } else {
+                    LineEnding::Crlf
+                };
+                self.mixed_line_endings = false;
+                None
+            }
+            Command::DuplicateLines
+            | Command::MoveLinesUp
+            | Command::MoveLinesDown
+            | Command::DeleteLines
+            | Command::JoinLines
+            | Command::InsertLineAbove
+            | Command::InsertLineBelow
+            | Command::SortLines
+            | Command::SortLinesCaseInsensitive
+            | Command::SortLinesNumerically
+            | Command::ReverseLines
+            | Command::UniqueLines
+            | Command::TrimTrailingWhitespace => {
+                let operation = command.line_operation()?;
+                self.edit_lines(operation, unix_time())
+            }
+            Command::ToggleLineComment => self.toggle_comments(false, unix_time()),
+            Command::ToggleBlockComment => self.toggle_comments(true, unix_time()),
+            Command::ReloadKeymap => {
+                self.keymap = Keymap::load(ui.ctx().os() == egui::os::OperatingSystem::Mac);
+                None
+            }
+            Command::ShowCommandPalette => {
+                self.palette = Some(Palette::default());
+                // The palette has already been drawn this frame.
+                ui.ctx().request_repaint();
+                None
+            }
+            Command::ToggleHistory => {
+                self.show_history = !self.show_history;
+                // The panel has already been drawn this frame.
+                ui.ctx().request_repaint();
+                None
+            }
+            Command::ToggleVimMode | Command::ToggleEmacsKeys => {
+                let profile = if command == Command::ToggleVimMode {
+                    Profile::Vim
+                } else {
+                    Profile::Emacs
+                };
+                self.profile = if self.profile == profile {
+                    Profile::Standard
+                } else {
+                    profile
+                };
+                // Leaving Vim mode leaves insert mode, and the change it was part of.
+                self.vim = Vim::default();
+                self.history.end_change();
+                self.emacs = Emacs::default();
+                ui.ctx().request_repaint();
+                None
+            }
+        }
+    }
+
+    /// Runs a command bound with Emacs keys, returning the new selections if it changes them.
+    fn run_emacs(&mut self, ui: &egui::Ui, action: emacs::Action, time: f64) -> Option<Selections> {
+        match action {
+            emacs::Action::SetMark | emacs::Action::Quit => {
+                self.emacs.mark_active = action == emacs::Action::SetMark;
+                Some(
+                    self.selections
+                        .map(|selection| CCursorRange::one(selection.primary)),
+                )
+            }
+            emacs::Action::KillLine => {
+                // Kill to the end of the line, or the newline if already there.
+                let selections = self.selections.map(|selection| {
+                    let ccursor = selection.primary;
+                    let line_end = self.text.line_end(ccursor);
+                    if line_end == ccursor {
+                        CCursorRange::two(ccursor, self.text.clamp(ccursor + 1))
+                    } else {
+                        CCursorRange::two(ccursor, line_end)
+                    }
+                });
+                self.kill(ui, &selections, time)
+            }
+            emacs::Action::KillRegion => {
+                let selections = self.selections.clone();
+                self.kill(ui, &selections, time)
+            }
+            emacs::Action::CopyRegion => {
+                let text = self.selected_text(&self.selections)?;
+                self.emacs.save(&text);
+                self.copy_text(ui, text);
+                Some(
+                    self.selections
+                        .map(|selection| CCursorRange::one(selection.primary)),
+                )
+            }
+            emacs::Action::Yank => {
+                let text = self.emacs.yank()?;
+                let selections = self.selections.clone();
+                self.edit(&selections, None, time, |buffer, selection| {
+                    Some(EditAction::replace_selection(buffer, selection, &text))
+                })
+            }
+            emacs::Action::YankPop => {
+                let (yanked_len, text) = self.emacs.yank_pop()?;
+                let selections = self.selections.map(|selection| {
+                    CCursorRange::two(selection.primary - yanked_len, selection.primary)
+                });
+                self.edit(&selections, None, time, |buffer, selection| {
+                    Some(EditAction::replace_selection(buffer, selection, &text))
+                })
+            }
+        }
+    }
+
+    /// The text of `selections`, one line per selection, if any of them select anything.
+    fn selected_text(&self, selections: &Selections) -> Option<String> {
+        let text = selections
+            .ranges()
+            .iter()
+            .filter(|selection| !is_empty(selection))
+            .map(|selection| self.text.selected_text(selection))
+            .collect::<Vec<_>>();
+        (!text.is_empty()).then(|| text.join("\n"))
+    }
+
+    /// Deletes `selections` into the kill ring, which is also copied to the clipboard.
+    fn kill(&mut self, ui: &egui::Ui, selections: &Selections, time: f64) -> Option<Selections> {
+        let text = self.selected_text(selections)?;
+        self.emacs.kill(&text);
+        if let Some(latest) = self.emacs.latest().map(str::to_owned) {
+            self.copy_text(ui, latest);
+        }
+
+        self.edit(selections, None, time, |buffer, selection| {
+            let [min, max] = selection.sorted();
+            (min != max).then(|| EditAction::delete(buffer, selection, min.index..max.index))
+        })
+    }
+
+    /// Handles a key pressed in Vim mode, returning the new selections if it changes them.
+    fn run_vim(&mut self, ui: &egui::Ui, key: VimKey, time: f64) -> Option<Selections> {
+        let actions = self.vim.handle(
+            key,
+            &self.text,
+            self.selections.main().primary,
+            Language::from_path(self.file.as_str()),
+            self.indentation,
+        );
+
+        let mut new_selections = None;
+        for action in actions {
+            match action {
+                vim::Action::Select(selection) => {
+                    new_selections = Some(Selections::one(selection));
+                }
+                vim::Action::Edit { range, text } => {
+                    let selection =
+                        CCursorRange::two(CCursor::new(range.start), CCursor::new(range.end));
+
+                    new_selections = self
+                        .edit(
+                            &Selections::one(selection),
+                            None,
+                            time,
+                            |buffer, selection| {
+                                Some(if text.is_empty() {
+                                    EditAction::delete(buffer, selection, range.clone())
+                                } else {
+                                    EditAction::replace_selection(buffer, selection, &text)
+                                })
+                            },
+                        )
+                        .or(new_selections);
+                }
+                vim::Action::Undo | vim::Action::Redo => {
+                    let selections = if action == vim::Action::Undo {
+                        self.history.undo(&mut self.text)
+                    } else {
+                        self.history.redo(&mut self.text)
+                    };
+
+                    // Vim leaves just the cursor, at the start of what changed.
+                    if let Some(selections) = selections {
+                        let [min, _] = selections.main().sorted();
+                        new_selections = Some(Selections::one(CCursorRange::one(min)));
+                    }
+                }
+                vim::Action::StartChange => self.history.start_change(),
+                vim::Action::EndChange => self.history.end_change(),
+                vim::Action::Copy(text) => ui.ctx().copy_text(text),
+                vim::Action::Run(command) => {
+                    self.commands.push(command);
+                    // The commands for this frame have already been run.
+                    ui.ctx().request_repaint();
+                }
+            }
+        }
+
+        new_selections
+    }
+
+    /// A button in the status bar showing how the file is indented, with a menu to change it.
+    fn indentation_menu(&mut self, ui: &mut egui::Ui) {
+        let indentation = self.indentation;
+        let label = if indentation.tabs {
+            format!("Tabs: {}", indentation.tab_width)
+        } else {
+            format!("Spaces: {}", indentation.width)
+        };
+
+        ui.menu_button(label, |ui| {
+            let indentation = &mut self.indentation;
+            ui.radio_value(&mut indentation.tabs, false, "Indent with spaces");
+            ui.radio_value(&mut indentation.tabs, true, "Indent with tabs");
+
+            ui.separator();
+            ui.horizontal(|ui| {
+                ui.label("Indent width");
+                ui.add(egui::DragValue::new(&mut indentation.width).clamp_range(1..=16));
+            });
+            ui.horizontal(|ui| {
+                ui.label("Tab width");
+                ui.add(egui::DragValue::new(&mut indentation.tab_width).clamp_range(1..=16));
+            });
+
+            ui.separator();
+            self.command_button(ui, "Convert to spaces", Command::IndentWithSpaces, true);
+            self.command_button(ui, "Convert to tabs", Command::IndentWithTabs, true);
+        });
+    }
+
+    /// A button in the status bar showing the line ending the file is saved with, with a menu to
+    /// change it.
+    fn line_ending_menu(&mut self, ui: &mut egui::Ui) {
+        let name = self.line_ending.name();
+        let label = if self.mixed_line_endings {
+            format!("Mixed: {name}")
+        } else {
+            name.to_owned()
+        };
+
+        ui.menu_button(label, |ui| {
+            if self.mixed_line_endings {
+                ui.label(format!(
+                    "Lines end in different ways, and are saved with {name}"
+                ));
+                ui.separator();
+            }
+
+            self.command_button(ui, "LF", Command::UseLfLineEndings, true);
+            self.command_button(ui, "CRLF", Command::UseCrlfLineEndings, true);
+        });
+    }
+
+    /// A menu button that runs `command`, showing its shortcut.
+    fn command_button(&mut self, ui: &mut egui::Ui, label: &str, command: Command, enabled: bool) {
+        let mut button = egui::Button::new(label);
+        if let Some(shortcut) = self.keymap.shortcut_text(ui.ctx(), command) {
+            button = button.shortcut_text(shortcut);
+        }
+
+        if ui
+            .add_enabled(enabled, button)
+            .on_hover_text(command.description())
+            .clicked()
+        {
+            self.commands.push(command);
+            ui.close_menu();
+        }
+    }
+
+    /// The horizontal position of each cursor that vertical movement keeps it at: where it was
+    /// before moving vertically if it's still where that left it, and where it is otherwise.
+    fn preferred_x(&mut self, ui: &egui::Ui) -> Vec<f32> {
+        match &self.preferred_x {
+            Some((selections, xs)) if *selections == self.selections => xs.clone(),
+            _ => self
+                .selections
+                .ranges()
+                .iter()
+                .map(|selection| {
+                    self.line_layout
+                        .cursor_rect(ui, &self.text, selection.primary)
+                        .center()
+                        .x
+                })
+                .collect(),
+        }
+    }
+
+    /// Adds a cursor on the line above the topmost cursor.
+    fn add_cursor_above(&mut self, ui: &egui::Ui) -> Option<Selections> {
+        let top = self.selections.ranges()[0].primary;
+        if self.text.char_to_line(top.index) == 0 {
+            return None;
+        }
+
+        let mut selections = self.selections.clone();
+        selections.add(CCursorRange::one(
+            self.line_layout.cursor_up_one_line(ui, &self.text, top),
+        ));
+        Some(selections)
+    }
+
+    /// Adds a cursor on the line below the bottommost cursor.
+    fn add_cursor_below(&mut self, ui: &egui::Ui) -> Option<Selections> {
+        let bottom = self.selections.ranges()[self.selections.len() - 1].primary;
+        if self.text.char_to_line(bottom.index) + 1 >= self.text.len_lines() {
+            return None;
+        }
+
+        let mut selections = self.selections.clone();
+        selections.add(CCursorRange::one(
+            self.line_layout
+                .cursor_down_one_line(ui, &self.text, bottom),
+        ));
+        Some(selections)
+    }
+
+    /// Selects the word at the main cursor if nothing is selected, and otherwise adds a selection
+    /// at the next occurrence of the selected text that isn't selected yet.
+    fn select_next_occurrence(&self) -> Selections {
+        let mut selections = self.selections.clone();
+
+        let main = selections.main();
+        if is_empty(&main) {
+            selections.set_main(self.text.word_at(main.primary));
+            return selections;
+        }
+
+        let needle = self.text.selected_text(&main);
+        let needle_len = needle.chars().count();
+        let mut from = main.sorted()[1].index;
+
+        // Every selection might already be at an occurrence, so give up after trying them all.
+        for _ in 0..=selections.len() {
+            let Some(start) = self.text.find(&needle, from) else {
+                break;
+            };
+
+            let is_selected = selections.ranges().iter().any(|selection| {
+                let [min, max] = selection.sorted();
+                min.index == start && max.index == start + needle_len
+            });
+            if !is_selected {
+                selections.add(CCursorRange::two(
+                    CCursor::new(start),
+                    CCursor::new(start + needle_len),
+                ));
+                break;
+            }
+
+            from = start + needle_len;
+        }
+
+        selections
     }
 }
 
@@ -168,16 +972,34 @@ impl eframe::App for TemplateApp {
     /// Called by the frame work to save state before shutdown.
     fn save(&mut self, storage: &mut dyn eframe::Storage) {
         eframe::set_value(storage, eframe::APP_KEY, self);
+        save_history(storage, &self.file.to_path(&self.cwd), &self.history);
     }
 
     /// Called each time the UI needs repainting, which may be many times per second.
-    fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
+    fn update(&mut self, ctx: &egui::Context, frame: &mut eframe::Frame) {
         // Put your widgets into a `SidePanel`, `TopBottomPanel`, `CentralPanel`, `Window` or `Area`.
         // For inspiration and more examples, go to https://emilk.github.io/egui
 
         if let Ok(msg) = self.file_channel.1.try_recv() {
-            self.text = msg.text;
+            if let Some(storage) = frame.storage_mut() {
+                save_history(storage, &self.file.to_path(&self.cwd), &self.history);
+            }
+
+            let (text, indentation, line_endings) = load_text(&msg.text);
+            self.text = text;
             self.file = msg.file;
+            self.selections = Selections::default();
+            self.indentation = indentation;
+            self.line_ending = line_endings.most_common();
+            self.mixed_line_endings = line_endings.is_mixed();
+            self.block = None;
+            self.line_layout = LineLayout::default();
+            self.history = frame
+                .storage()
+                .and_then(|storage| {
+                    load_history(storage, &self.file.to_path(&self.cwd), &self.text)
+                })
+                .unwrap_or_else(|| History::new(&self.text));
 
             ctx.send_viewport_cmd(egui::ViewportCommand::Title(format!(
                 "egui_edit - {}",
@@ -185,47 +1007,109 @@ impl eframe::App for TemplateApp {
             )));
         }
 
+        // Shortcuts work wherever the focus is, so take them before any widget sees them. Except
+        // in the command palette, where the keys are for editing the query.
+        ctx.input_mut(|i| {
+            if self.palette.is_some() {
+                return;
+            }
+
+            i.events.retain(|event| match event {
+                Event::Key {
+                    key,
+                    pressed: true,
+                    modifiers,
+                    ..
+                } if self.profile == Profile::Emacs
+                    && emacs::handles(*key, *modifiers, &self.keymap) =>
+                {
+                    true
+                }
+                Event::Key {
+                    key,
+                    pressed: true,
+                    modifiers,
+                    ..
+                } => match self.keymap.press(*key, *modifiers) {
+                    Matched::Command(command) => {
+                        self.commands.push(command);
+                        false
+                    }
+                    Matched::Sequence => false,
+                    Matched::Unbound => true,
+                },
+                _ => true,
+            });
+        });
+
         egui::TopBottomPanel::top("top_panel").show(ctx, |ui| {
             // The top panel is often a good place for a menu bar:
 
             egui::menu::bar(ui, |ui| {
                 ui.menu_button("File", |ui| {
-                    if ui
-                        .add(egui::Button::new("Open…").shortcut_text(
-                            egui::KeyboardShortcut::new(egui::Modifiers::COMMAND, Key::O).format(
-                                &egui::ModifierNames::SYMBOLS,
-                                egui::os::OperatingSystem::from_target_os()
-                                    == egui::os::OperatingSystem::Mac,
-                            ),
-                        ))
-                        .clicked()
-                    {
-                        open_file_with_native_dialog(
-                            ui,
-                            self.file_channel.0.clone(),
-                            self.cwd.clone(),
-                        );
+                    self.command_button(ui, "Open…", Command::Open, true);
+                    self.command_button(ui, "Save file", Command::Save, true);
+
+                    ui.separator();
+
+                    self.command_button(ui, "Quit", Command::Quit, true);
+                });
+
+                ui.menu_button("Edit", |ui| {
+                    let can_undo = self.history.can_undo();
+                    self.command_button(ui, "Undo", Command::Undo, can_undo);
+                    let can_redo = self.history.can_redo();
+                    self.command_button(ui, "Redo", Command::Redo, can_redo);
+
+                    ui.separator();
+
+                    ui.menu_button("Lines", |ui| {
+                        self.command_button(ui, "Duplicate", Command::DuplicateLines, true);
+                        self.command_button(ui, "Move up", Command::MoveLinesUp, true);
+                        self.command_button(ui, "Move down", Command::MoveLinesDown, true);
+                        self.command_button(ui, "Delete", Command::DeleteLines, true);
+                        self.command_button(ui, "Join", Command::JoinLines, true);
+                        self.command_button(ui, "Insert above", Command::InsertLineAbove, true);
+                        self.command_button(ui, "Insert below", Command::InsertLineBelow, true);
+
+                        ui.separator();
+
+                        self.command_button(ui, "Sort", Command::SortLines, true);
+                        let case_insensitive = Command::SortLinesCaseInsensitive;
+                        self.command_button(ui, "Sort, ignoring case", case_insensitive, true);
+                        let numerically = Command::SortLinesNumerically;
+                        self.command_button(ui, "Sort numerically", numerically, true);
+                        self.command_button(ui, "Reverse", Command::ReverseLines, true);
+                        self.command_button(ui, "Remove duplicates", Command::UniqueLines, true);
+                        let trim = Command::TrimTrailingWhitespace;
+                        self.command_button(ui, "Trim trailing whitespace", trim, true);
+                    });
+
+                    let line_comment = Command::ToggleLineComment;
+                    self.command_button(ui, "Toggle line comment", line_comment, true);
+                    let block_comment = Command::ToggleBlockComment;
+                    self.command_button(ui, "Toggle block comment", block_comment, true);
+                });
+
+                ui.menu_button("View", |ui| {
+                    self.command_button(ui, "Command palette…", Command::ShowCommandPalette, true);
+
+                    let mut show_history = self.show_history;
+                    if ui.checkbox(&mut show_history, "History").clicked() {
+                        self.commands.push(Command::ToggleHistory);
                         ui.close_menu();
                     }
 
-                    if ui
-                        .add(egui::Button::new("Save file").shortcut_text(
-                            egui::KeyboardShortcut::new(egui::Modifiers::COMMAND, Key::S).format(
-                                &egui::ModifierNames::SYMBOLS,
-                                egui::os::OperatingSystem::from_target_os()
-                                    == egui::os::OperatingSystem::Mac,
-                            ),
-                        ))
-                        .clicked()
-                    {
-                        save_text_to_file(self.file.as_str(), self.text.as_str());
+                    let mut vim_mode = self.profile == Profile::Vim;
+                    if ui.checkbox(&mut vim_mode, "Vim mode").clicked() {
+                        self.commands.push(Command::ToggleVimMode);
                         ui.close_menu();
                     }
 
-                    ui.separator();
-
-                    if ui.button("Quit").clicked() {
-                        ctx.send_viewport_cmd(egui::ViewportCommand::Close);
+                    let mut emacs_keys = self.profile == Profile::Emacs;
+                    if ui.checkbox(&mut emacs_keys, "Emacs keys").clicked() {
+                        self.commands.push(Command::ToggleEmacsKeys);
+                        ui.close_menu();
                     }
                 });
                 ui.add_space(16.0);
@@ -241,13 +1125,80 @@ impl eframe::App for TemplateApp {
             ui.with_layout(egui::Layout::bottom_up(egui::Align::LEFT), |ui| {
                 powered_by_egui_and_eframe(ui);
                 egui::warn_if_debug_build(ui);
+
+                ui.horizontal(|ui| {
+                    self.indentation_menu(ui);
+                    self.line_ending_menu(ui);
+
+                    match self.profile {
+                        Profile::Vim => {
+                            ui.monospace(self.vim.status());
+                        }
+                        Profile::Emacs if self.emacs.mark_active => {
+                            ui.label("Mark set");
+                        }
+                        _ => {}
+                    }
+
+                    if let Some(pending) = self.keymap.pending_text(ctx) {
+                        ui.label(format!("{pending} was pressed, waiting for the next key…"));
+                    }
+
+                    let problems = self.keymap.problems();
+                    if !problems.is_empty() {
+                        ui.colored_label(
+                            ui.visuals().warn_fg_color,
+                            format!("⚠ {} problem(s) with the keymap", problems.len()),
+                        )
+                        .on_hover_text(problems.join("\n"));
+                    }
+                });
             });
         });
 
+        egui::SidePanel::right("history_panel").show_animated(ctx, self.show_history, |ui| {
+            ui.heading("History");
+            ui.separator();
+
+            let now = unix_time();
+            egui::ScrollArea::vertical().show(ui, |ui| {
+                for node in self.history.tree() {
+                    ui.horizontal(|ui| {
+                        ui.add_space(node.depth as f32 * ui.spacing().indent);
+
+                        if ui.selectable_label(node.is_current, &node.label).clicked() {
+                            if let Some(selections) = self.history.jump_to(node.id, &mut self.text)
+                            {
+                                self.selections = selections;
+                            }
+                        }
+
+                        if let Some(time) = node.time {
+                            ui.weak(format_age(now - time));
+                        }
+                    });
+                }
+            });
+
+            // Keep the ages up to date.
+            ui.ctx()
+                .request_repaint_after(std::time::Duration::from_secs(1));
+        });
+
+        if let Some(palette) = &mut self.palette {
+            if let Some(outcome) = palette.show(ctx, &self.keymap) {
+                if let Outcome::Run(command) = outcome {
+                    self.commands.push(command);
+                }
+                self.palette = None;
+                self.autofocus = true;
+            }
+        }
+
         egui::CentralPanel::default().show(ctx, |ui| {
             // The central panel the region left after adding TopPanel's and SidePanel's
 
-            egui::ScrollArea::both().show(ui, |ui| {
+            egui::ScrollArea::both().show_viewport(ui, |ui, viewport| {
                 // =============================
                 // Set up the available layout.
                 // =============================
@@ -257,26 +1208,25 @@ impl eframe::App for TemplateApp {
                 let max_rect = margin.shrink_rect(available);
                 let mut content_ui = ui.child_ui(max_rect, egui::Layout::default());
 
-                let font_id = FontId::new(14.0, egui::FontFamily::Monospace);
+                // The part of the content that is scrolled into view, in screen coordinates.
+                let visible_rect = viewport.translate(ui.max_rect().min.to_vec2());
 
-                // =============================
-                // Layout function for the text, incl. syntax highlighting.
-                // =============================
-                let layouter = |ui: &egui::Ui, text: &str, _wrap_width: f32| {
-                    let layout_job = highlight(ui.ctx(), text, ui.visuals().dark_mode);
-                    ui.fonts(|f| f.layout_job(layout_job))
-                };
+                let font_id = FontId::new(14.0, egui::FontFamily::Monospace);
 
                 // =============================
                 // Calculate dimensions.
                 // =============================
                 let row_height = content_ui.fonts(|f| f.row_height(&font_id));
 
+                self.line_layout.begin_frame(
+                    &content_ui,
+                    &mut self.text,
+                    row_height,
+                    self.indentation.tab_width,
+                );
+
                 const MIN_WIDTH: f32 = 24.0;
                 let available_width = content_ui.available_width().at_least(MIN_WIDTH);
-                let wrap_width = available_width;
-
-                let mut galley = layouter(&content_ui, &self.text, wrap_width);
 
                 // Clip all text.
                 let desired_width = available_width;
@@ -284,8 +1234,8 @@ impl eframe::App for TemplateApp {
                 // Default values form the TextGui TextEdit.
                 let at_least = Vec2::ZERO - Margin::symmetric(4.0, 2.0).sum();
                 let desired_size = vec2(
-                    galley.size().x.max(desired_width),
-                    galley.size().y.max(desired_height),
+                    self.line_layout.widest().max(desired_width),
+                    self.line_layout.total_height().max(desired_height),
                 )
                 .at_least(at_least);
 
@@ -293,39 +1243,25 @@ impl eframe::App for TemplateApp {
 
                 let painter = content_ui.painter_at(rect.expand(1.0)); // expand to avoid clipping cursor.
 
-                let galley_pos = Align2::LEFT_TOP
-                    .align_size_within_rect(galley.size(), rect)
-                    .intersect(rect) // limit pos to the response rect area
-                    .min;
+                let galley_pos = rect.min;
 
                 // ---
                 // Cursor positions and dimensions.
                 // ---
 
-                // FIXME: support multiple cursors/selections.
-                let mut cursor_pos = galley
-                    .pos_from_cursor(&self.selection.primary)
-                    .translate(galley_pos.to_vec2());
-
-                // Handle completely empty galleys
-                cursor_pos.max.y = cursor_pos.max.y.at_least(cursor_pos.min.y + row_height);
-                // Expand to slightly above and below the text.
-                cursor_pos = cursor_pos.expand(1.5);
-
-                let cursor_stroke = ui.visuals().text_cursor;
-                let top = cursor_pos.center_top();
-                let bottom = cursor_pos.center_bottom();
-
-                // Turn on IME if we have focus.
-                // IME is supposed to be on when the user is editing text.
-                if content_ui.memory(|m| m.has_focus(id)) {
-                    content_ui.output_mut(|o| {
-                        o.ime = Some(egui::output::IMEOutput {
-                            rect,
-                            cursor_rect: cursor_pos,
-                        })
-                    })
-                }
+                let cursor_rect = |ui: &egui::Ui,
+                                   text: &Buffer,
+                                   line_layout: &mut LineLayout,
+                                   ccursor: CCursor| {
+                    let mut cursor_pos = line_layout
+                        .cursor_rect(ui, text, ccursor)
+                        .translate(galley_pos.to_vec2());
+
+                    // Handle completely empty lines
+                    cursor_pos.max.y = cursor_pos.max.y.at_least(cursor_pos.min.y + row_height);
+                    // Expand to slightly above and below the text.
+                    cursor_pos.expand(1.5)
+                };
 
                 // =============================
                 // Autofocus the text widget on startup.
@@ -359,16 +1295,108 @@ impl eframe::App for TemplateApp {
                 }
 
                 if let Some(pointer_pos) = content_ui.ctx().pointer_interact_pos() {
-                    if response.is_pointer_button_down_on() && response.dragged() {
-                        self.selection = CursorRange {
-                            primary: galley.cursor_from_pos(pointer_pos - response.rect.min),
-                            secondary: self.selection.secondary,
-                        };
-                    } else if response.is_pointer_button_down_on() {
-                        self.selection = CursorRange {
-                            primary: galley.cursor_from_pos(pointer_pos - response.rect.min),
-                            secondary: galley.cursor_from_pos(pointer_pos - response.rect.min),
-                        };
+                    if response.is_pointer_button_down_on() {
+                        let ccursor = self.line_layout.cursor_from_pos(
+                            &content_ui,
+                            &self.text,
+                            pointer_pos - galley_pos,
+                        );
+
+                        let pos = pointer_pos - galley_pos;
+                        let corner = (self.line_layout.line_at(pos.y), pos.x);
+
+                        if content_ui.input(|i| i.pointer.primary_pressed()) {
+                            let time = content_ui.input(|i| i.time);
+
+                            // Double clicking selects a word, and triple clicking a whole line.
+                            let clicks = match self.last_press {
+                                Some(press)
+                                    if time - press.time < MAX_DOUBLE_CLICK_DELAY
+                                        && press.pos.distance(pointer_pos) < MAX_CLICK_DIST =>
+                                {
+                                    press.clicks % 3 + 1
+                                }
+                                _ => 1,
+                            };
+                            let mut selection = self.selection_at(ccursor, clicks);
+
+                            let modifiers = content_ui.input(|i| i.modifiers);
+                            if modifiers.shift {
+                                // Shift click extends the selection from where it started.
+                                let anchor = CCursorRange::one(self.selections.main().secondary);
+                                self.selections.set_main(extend(anchor, selection));
+                                selection = anchor;
+                            } else if modifiers.alt || modifiers.command {
+                                // Alt or Ctrl (Cmd on Mac) click adds a cursor instead of moving
+                                // it.
+                                self.selections.add(selection);
+                            } else {
+                                self.selections = Selections::one(selection);
+                            }
+
+                            // Vim's visual selection or Emacs's mark is replaced by whatever
+                            // was clicked.
+                            match self.profile {
+                                Profile::Vim => self.vim.reset(),
+                                Profile::Emacs => self.emacs.finish(None, false),
+                                Profile::Standard => {}
+                            }
+
+                            self.last_press = Some(Press {
+                                time,
+                                pos: pointer_pos,
+                                clicks,
+                                selection,
+                            });
+
+                            // Dragging after an Alt click makes a rectangular selection.
+                            self.block = (modifiers.alt && !modifiers.shift).then_some(Block {
+                                anchor: corner,
+                                head: corner,
+                            });
+                        } else if let Some(mut block) = self.block {
+                            if response.dragged() {
+                                block.head = corner;
+                                self.block = Some(block);
+                                self.selections = self.block_selections(&content_ui, block);
+                            }
+                        } else if let Some(press) = self.last_press {
+                            // Dragging extends the selection that was added last, by words or
+                            // lines if that's what the click selected.
+                            self.selections.set_main(extend(
+                                press.selection,
+                                self.selection_at(ccursor, press.clicks),
+                            ));
+                        }
+
+                        self.history.seal();
+                    }
+                }
+
+                // ---
+                // Commands.
+                // ---
+
+                let set_selections = |app: &mut Self, selections: Selections| {
+                    app.line_layout.sync(&mut app.text);
+                    app.selections = selections.clamp(&app.text);
+
+                    // Scroll to the cursor to make sure it's in view after its position changed.
+                    content_ui.scroll_to_rect(
+                        cursor_rect(
+                            &content_ui,
+                            &app.text,
+                            &mut app.line_layout,
+                            app.selections.main().primary,
+                        ),
+                        None,
+                    )
+                };
+
+                for command in std::mem::take(&mut self.commands) {
+                    if let Some(new_selections) = self.run(&content_ui, command) {
+                        self.block = None;
+                        set_selections(self, new_selections);
                     }
                 }
 
@@ -380,7 +1408,7 @@ impl eframe::App for TemplateApp {
                     horizontal_arrows: true,
                     vertical_arrows: true,
                     tab: true,
-                    ..Default::default()
+                    escape: true,
                 };
 
                 if content_ui.memory(|m| m.has_focus(id)) {
@@ -388,484 +1416,416 @@ impl eframe::App for TemplateApp {
 
                     content_ui.memory_mut(|m| m.set_focus_lock_filter(id, event_filter));
 
+                    let time = unix_time();
+
+                    // Whether the last event was a Meta key with Emacs keys, which on a Mac also
+                    // types a character that should be ignored.
+                    let mut after_meta = false;
+
                     for event in &events {
-                        let new_ccursor_range = match event {
-                            Event::Copy => {
-                                // FIXME: If selection is empty, copy the line (i.e. paragraph in egui terms).
-                                if !self.selection.is_empty() {
-                                    let text_to_copy =
-                                        self.selection.slice_str(self.text.as_str()).to_owned();
+                        let is_emacs = self.profile == Profile::Emacs;
+                        let (motion, emacs_action) = match event {
+                            Event::Key {
+                                key,
+                                pressed: true,
+                                modifiers,
+                                ..
+                            } => (
+                                Motion::from_key(*key, *modifiers).or_else(|| {
+                                    is_emacs.then(|| emacs::motion(*key, *modifiers)).flatten()
+                                }),
+                                is_emacs
+                                    .then(|| emacs::Action::from_key(*key, *modifiers))
+                                    .flatten(),
+                            ),
+                            _ => (None, None),
+                        };
+                        let swallowed =
+                            std::mem::take(&mut after_meta) && matches!(event, Event::Text(_));
 
-                                    content_ui.ctx().copy_text(text_to_copy);
-                                } else {
-                                    let min = galley.from_pcursor(PCursor {
-                                        paragraph: self.selection.primary.pcursor.paragraph,
-                                        offset: 0,
-                                        prefer_next_row: true,
-                                    });
-                                    let max = galley.from_pcursor(PCursor {
-                                        paragraph: self.selection.primary.pcursor.paragraph + 1,
-                                        offset: 0,
-                                        prefer_next_row: true,
-                                    });
+                        // Moving the cursor around ends the current undo group.
+                        if motion.is_some() {
+                            self.history.seal();
+                        }
 
-                                    let text_to_copy = CursorRange::two(min, max)
-                                        .slice_str(self.text.as_str())
-                                        .to_owned();
+                        // Whether the event extended the rectangular selection, rather than
+                        // replacing it with some other selection.
+                        let mut extends_block = false;
 
-                                    content_ui.ctx().copy_text(text_to_copy);
-                                }
+                        // Where vertical movement should keep the cursors, if the event moved
+                        // them vertically.
+                        let mut preferred_x = None;
+
+                        // egui only reads the system clipboard to paste, so that's when Vim's
+                        // clipboard registers find out about text copied in other programs.
+                        if let Event::Paste(text) = event {
+                            self.vim.set_clipboard(text);
+                        }
+
+                        // In Vim mode, keys are commands, except in insert mode where they type
+                        // like they always do until Escape is pressed.
+                        let vim_key = if self.profile != Profile::Vim {
+                            None
+                        } else if self.vim.mode() == vim::Mode::Insert {
+                            self.vim.record(event);
+                            VimKey::from_event(event).filter(|&key| key == VimKey::Escape)
+                        } else {
+                            VimKey::from_event(event)
+                        };
+
+                        let new_selections = match event {
+                            _ if vim_key.is_some() => {
+                                vim_key.and_then(|key| self.run_vim(&content_ui, key, time))
+                            }
+                            Event::Text(_) | Event::Key { .. } | Event::Paste(_) | Event::Cut
+                                if self.profile == Profile::Vim
+                                    && self.vim.mode() != vim::Mode::Insert =>
+                            {
+                                None
+                            }
+                            _ if swallowed => None,
+                            Event::Key { .. } if emacs_action.is_some() => emacs_action
+                                .and_then(|action| self.run_emacs(&content_ui, action, time)),
+                            Event::Copy => {
+                                self.copy(&content_ui);
                                 None
                             }
                             Event::Cut => {
-                                // FIXME: If selection is empty, copy the line (i.e. paragraph in egui terms).
-                                let (text_to_cut, ccursor_range) = if !self.selection.is_empty() {
-                                    let text_to_cut =
-                                        self.selection.slice_str(self.text.as_str()).to_owned();
-                                    let ccursor = self.text.delete_selected(&self.selection);
-
-                                    content_ui.ctx().copy_text(text_to_cut.to_owned());
+                                self.copy(&content_ui);
 
-                                    (text_to_cut, CCursorRange::one(ccursor))
+                                // Cut whole lines if nothing is selected.
+                                let selections = if self.selections.ranges().iter().all(is_empty) {
+                                    self.line_selections()
                                 } else {
-                                    let min = galley.from_pcursor(PCursor {
-                                        paragraph: self.selection.primary.pcursor.paragraph,
-                                        offset: 0,
-                                        prefer_next_row: true,
-                                    });
-                                    let max = galley.from_pcursor(PCursor {
-                                        paragraph: self.selection.primary.pcursor.paragraph + 1,
-                                        offset: 0,
-                                        prefer_next_row: true,
-                                    });
-                                    let cursor_range = CursorRange::two(min, max);
-
-                                    let text_to_cut =
-                                        cursor_range.slice_str(self.text.as_str()).to_owned();
-                                    let ccursor = self.text.delete_selected(&cursor_range);
-
-                                    content_ui.ctx().copy_text(text_to_cut.to_owned());
-
-                                    (text_to_cut, CCursorRange::one(ccursor))
+                                    self.selections.clone()
                                 };
 
-                                self.history.push(EditAction::Delete {
-                                    text: text_to_cut.to_owned(),
-                                    selection_after: ccursor_range,
-                                });
-
-                                Some(ccursor_range)
+                                self.edit(&selections, None, time, EditAction::delete_selection)
+                            }
+                            Event::Paste(text_to_insert)
+                                if self.selections.len() == 1
+                                    && is_empty(&self.selections.main())
+                                    && self.block_clipboard.as_ref() == Some(text_to_insert) =>
+                            {
+                                self.paste_block(&content_ui, text_to_insert, time)
                             }
                             Event::Paste(text_to_insert) => {
                                 if !text_to_insert.is_empty() {
-                                    let deleted_text =
-                                        self.selection.slice_str(&self.text).to_owned();
-
-                                    let mut ccursor = self.text.delete_selected(&self.selection);
-
-                                    // Push edit action onto the edit history stack.
-                                    self.history.push(EditAction::InsertAndDelete {
-                                        inserted_text: text_to_insert.to_owned(),
-                                        deleted_text,
-                                        selection_after_delete: CCursorRange::one(ccursor),
-                                        selection_before: self.selection.as_ccursor_range(),
-                                    });
-
-                                    self.text.insert_text_at(
-                                        &mut ccursor,
-                                        text_to_insert,
-                                        usize::MAX,
-                                    );
+                                    // Paste a line at each cursor if there's one for each of them,
+                                    // e.g. when they were copied from as many cursors.
+                                    let lines = text_to_insert.lines().collect::<Vec<_>>();
+                                    let mut lines = (self.selections.len() > 1
+                                        && lines.len() == self.selections.len())
+                                    .then(|| lines.into_iter());
+
+                                    let selections = self.selections.clone();
+                                    self.edit(&selections, None, time, |text, selection| {
+                                        let text_to_insert = match &mut lines {
+                                            Some(lines) => lines.next().unwrap_or_default(),
+                                            None => text_to_insert,
+                                        };
 
-                                    Some(CCursorRange::one(ccursor))
+                                        Some(EditAction::replace_selection(
+                                            text,
+                                            selection,
+                                            text_to_insert,
+                                        ))
+                                    })
                                 } else {
                                     None
                                 }
                             }
                             Event::Text(text_to_insert) => {
-                                if !text_to_insert.is_empty()
-                                    && text_to_insert != "\n"
-                                    && text_to_insert != "\r"
-                                {
-                                    let deleted_text =
-                                        self.selection.slice_str(&self.text).to_owned();
-
-                                    let mut ccursor = self.text.delete_selected(&self.selection);
-
-                                    // Push edit action onto the edit history stack.
-                                    self.history.push(EditAction::InsertAndDelete {
-                                        inserted_text: text_to_insert.to_owned(),
-                                        deleted_text,
-                                        selection_after_delete: CCursorRange::one(ccursor),
-                                        selection_before: self.selection.as_ccursor_range(),
-                                    });
-
-                                    self.text.insert_text_at(
-                                        &mut ccursor,
-                                        text_to_insert,
-                                        usize::MAX,
-                                    );
-
-                                    Some(CCursorRange::one(ccursor))
-                                } else {
-                                    None
-                                }
+                                // Group the edit with the characters typed just before it.
+                                let selections = self.selections.clone();
+                                self.edit(
+                                    &selections,
+                                    Some(EditKind::Typing),
+                                    time,
+                                    |text, selection| {
+                                        EditAction::typing(text, selection, text_to_insert)
+                                    },
+                                )
                             }
                             Event::Key {
                                 key: Key::Tab,
                                 pressed: true,
-                                ..
-                            } => {
-                                let deleted_text = self.selection.slice_str(&self.text).to_owned();
-
-                                let mut ccursor = self.text.delete_selected(&self.selection);
-
-                                self.history.push(EditAction::InsertAndDelete {
-                                    inserted_text: "\t".to_owned(),
-                                    deleted_text,
-                                    selection_after_delete: CCursorRange::one(ccursor),
-                                    selection_before: self.selection.as_ccursor_range(),
-                                });
-
-                                self.text.insert_text_at(&mut ccursor, "\t", usize::MAX);
-
-                                Some(CCursorRange::one(ccursor))
-                            }
-                            Event::Key {
-                                key: Key::Enter,
-                                pressed: true,
-                                ..
-                            } => {
-                                let deleted_text = self.selection.slice_str(&self.text).to_owned();
-
-                                let mut ccursor = self.text.delete_selected(&self.selection);
-
-                                self.history.push(EditAction::InsertAndDelete {
-                                    inserted_text: "\n".to_owned(),
-                                    deleted_text,
-                                    selection_after_delete: CCursorRange::one(ccursor),
-                                    selection_before: self.selection.as_ccursor_range(),
-                                });
-
-                                self.text.insert_text_at(&mut ccursor, "\n", usize::MAX);
-
-                                Some(CCursorRange::one(ccursor))
-                            }
-                            Event::Key {
-                                key: Key::Backspace,
-                                pressed: true,
                                 modifiers,
                                 ..
                             } => {
-                                let (deleted_text, ccursor) = if modifiers.mac_cmd {
-                                    let [min, max] = self.selection.sorted_cursors();
-                                    let min = galley.from_pcursor(PCursor {
-                                        paragraph: min.pcursor.paragraph,
-                                        offset: 0,
-                                        prefer_next_row: true,
+                                // Tab indents the selected lines if a selection spans several.
+                                let spans_lines =
+                                    self.selections.ranges().iter().any(|selection| {
+                                        let [min, max] = selection.sorted();
+                                        self.text.char_to_line(min.index)
+                                            != self.text.char_to_line(max.index)
                                     });
 
-                                    (
-                                        CursorRange::two(min, max).slice_str(&self.text).to_owned(),
-                                        self.text.delete_paragraph_before_cursor(
-                                            &galley,
-                                            &self.selection,
-                                        ),
-                                    )
-                                } else if let Some(cursor) = self.selection.single() {
-                                    if modifiers.alt {
-                                        let min = galley.from_ccursor(ccursor_previous_word(
-                                            &self.text,
-                                            cursor.ccursor,
-                                        ));
-
-                                        (
-                                            CursorRange::two(min, cursor)
-                                                .slice_str(&self.text)
-                                                .to_owned(),
-                                            self.text.delete_previous_word(cursor.ccursor),
-                                        )
-                                    } else {
-                                        let deleted_text = if cursor.ccursor.index > 0 {
-                                            let min_cursor =
-                                                galley.from_ccursor(cursor.ccursor - 1);
-
-                                            CursorRange::two(min_cursor, cursor)
-                                                .slice_str(&self.text)
-                                                .to_owned()
-                                        } else {
-                                            "".to_owned()
-                                        };
-
-                                        (
-                                            deleted_text,
-                                            self.text.delete_previous_char(cursor.ccursor),
-                                        )
-                                    }
-                                } else {
-                                    (
-                                        self.selection.slice_str(&self.text).to_owned(),
-                                        self.text.delete_selected(&self.selection),
-                                    )
-                                };
-
-                                self.history.push(EditAction::Delete {
-                                    text: deleted_text,
-                                    selection_after: CCursorRange::one(ccursor),
-                                });
-
-                                Some(CCursorRange::one(ccursor))
-                            }
-                            Event::Key {
-                                key: Key::ArrowLeft,
-                                pressed: true,
-                                modifiers,
-                                ..
-                            } => {
-                                if modifiers.is_none() {
-                                    Some(CCursorRange::one(
-                                        galley
-                                            .cursor_left_one_character(&self.selection.primary)
-                                            .ccursor,
-                                    ))
-                                } else if modifiers.alt {
-                                    Some(CCursorRange::one(ccursor_previous_word(
-                                        &self.text,
-                                        self.selection.primary.ccursor,
-                                    )))
-                                } else if modifiers.mac_cmd {
-                                    Some(CCursorRange::one(
-                                        galley.cursor_begin_of_row(&self.selection.primary).ccursor,
-                                    ))
-                                } else if modifiers.shift {
-                                    Some(CCursorRange::two(
-                                        self.selection.secondary.ccursor,
-                                        galley
-                                            .cursor_left_one_character(&self.selection.primary)
-                                            .ccursor,
-                                    ))
+                                if modifiers.shift || spans_lines {
+                                    self.indent_lines(modifiers.shift, time)
                                 } else {
-                                    None
+                                    let indentation = self.indentation;
+                                    let selections = self.selections.clone();
+                                    self.edit(&selections, None, time, |text, selection| {
+                                        let [min, _] = selection.sorted();
+                                        let tab = indentation.tab_at(text, min);
+                                        Some(EditAction::replace_selection(text, selection, &tab))
+                                    })
                                 }
                             }
                             Event::Key {
-                                key: Key::ArrowRight,
+                                key: Key::Enter,
                                 pressed: true,
-                                modifiers,
                                 ..
-                            } => {
-                                if modifiers.is_none() {
-                                    Some(CCursorRange::one(
-                                        galley
-                                            .cursor_right_one_character(&self.selection.primary)
-                                            .ccursor,
-                                    ))
-                                } else if modifiers.alt {
-                                    Some(CCursorRange::one(ccursor_next_word(
-                                        &self.text,
-                                        self.selection.primary.ccursor,
-                                    )))
-                                } else if modifiers.mac_cmd {
-                                    Some(CCursorRange::one(
-                                        galley.cursor_end_of_row(&self.selection.primary).ccursor,
-                                    ))
-                                } else if modifiers.shift {
-                                    Some(CCursorRange::two(
-                                        self.selection.secondary.ccursor,
-                                        galley
-                                            .cursor_right_one_character(&self.selection.primary)
-                                            .ccursor,
-                                    ))
-                                } else {
-                                    None
-                                }
-                            }
+                            } => self.insert_newline(time),
                             Event::Key {
-                                key: Key::ArrowUp,
+                                key: Key::Backspace,
                                 pressed: true,
                                 modifiers,
                                 ..
                             } => {
-                                if modifiers.is_none() {
-                                    Some(CCursorRange::one(
-                                        galley.cursor_up_one_row(&self.selection.primary).ccursor,
-                                    ))
-                                } else if modifiers.mac_cmd {
-                                    Some(CCursorRange::one(galley.begin().ccursor))
-                                } else if modifiers.shift {
-                                    Some(CCursorRange::two(
-                                        self.selection.secondary.ccursor,
-                                        galley.cursor_up_one_row(&self.selection.primary).ccursor,
-                                    ))
-                                } else {
-                                    None
-                                }
+                                // Only plain backspaces are grouped together in the undo history.
+                                let kind =
+                                    EditKind::Backspace.when_plain(*modifiers, &self.selections);
+
+                                let indentation = self.indentation;
+                                let selections = self.selections.clone();
+                                self.edit(&selections, kind, time, |text, selection| {
+                                    EditAction::backspace(text, selection, *modifiers, indentation)
+                                })
                             }
                             Event::Key {
-                                key: Key::ArrowDown,
+                                key: Key::Delete,
                                 pressed: true,
                                 modifiers,
                                 ..
                             } => {
-                                if modifiers.is_none() {
-                                    Some(CCursorRange::one(
-                                        galley.cursor_down_one_row(&self.selection.primary).ccursor,
-                                    ))
-                                } else if modifiers.mac_cmd {
-                                    Some(CCursorRange::one(galley.end().ccursor))
-                                } else if modifiers.shift {
-                                    Some(CCursorRange::two(
-                                        self.selection.secondary.ccursor,
-                                        galley.cursor_down_one_row(&self.selection.primary).ccursor,
-                                    ))
-                                } else {
-                                    None
-                                }
+                                // Only plain deletes are grouped together in the undo history.
+                                let kind =
+                                    EditKind::Delete.when_plain(*modifiers, &self.selections);
+
+                                let selections = self.selections.clone();
+                                self.edit(&selections, kind, time, |text, selection| {
+                                    EditAction::delete_forward(text, selection, *modifiers)
+                                })
                             }
                             Event::Key {
-                                key: Key::Z,
+                                key:
+                                    key @ (Key::ArrowLeft
+                                    | Key::ArrowRight
+                                    | Key::ArrowUp
+                                    | Key::ArrowDown),
                                 pressed: true,
                                 modifiers,
                                 ..
-                            } if modifiers.command_only() => match self.history.pop() {
-                                Some(EditAction::InsertAndDelete {
-                                    inserted_text,
-                                    deleted_text,
-                                    selection_after_delete,
-                                    selection_before,
-                                }) => {
-                                    // Delete the inserted text.
-                                    let mut ccursor = self.text.delete_selected(&CursorRange::two(
-                                        galley.from_ccursor(selection_after_delete.primary),
-                                        galley.from_ccursor(
-                                            selection_after_delete.primary + inserted_text.len(),
-                                        ),
-                                    ));
-
-                                    // Insert deleted text back.
-                                    if !deleted_text.is_empty() {
-                                        self.text.insert_text_at(
-                                            &mut ccursor,
-                                            &deleted_text,
-                                            usize::MAX,
-                                        );
+                            } if modifiers.shift && modifiers.alt && !modifiers.command => {
+                                let mut block = self.block.unwrap_or_else(|| {
+                                    let main = self.selections.main();
+                                    Block {
+                                        anchor: self.block_corner(&content_ui, main.secondary),
+                                        head: self.block_corner(&content_ui, main.primary),
                                     }
+                                });
 
-                                    Some(selection_before)
+                                let char_width = content_ui.fonts(|f| f.glyph_width(&font_id, ' '));
+                                let (line, x) = &mut block.head;
+                                match key {
+                                    Key::ArrowLeft => *x = (*x - char_width).at_least(0.0),
+                                    Key::ArrowRight => *x += char_width,
+                                    Key::ArrowUp => *line = line.saturating_sub(1),
+                                    _ => *line = (*line + 1).min(self.text.len_lines() - 1),
                                 }
 
-                                Some(EditAction::Delete {
-                                    text,
-                                    selection_after,
-                                }) => {
-                                    self.text.insert_text_at(
-                                        &mut selection_after.primary.clone(),
-                                        &text,
-                                        usize::MAX,
-                                    );
-
-                                    Some(CCursorRange::one(selection_after.primary + text.len()))
-                                }
+                                self.block = Some(block);
+                                extends_block = true;
+                                Some(self.block_selections(&content_ui, block))
+                            }
+                            Event::Key { modifiers, .. } if motion.is_some() => {
+                                motion.map(|motion| {
+                                    let xs =
+                                        motion.is_vertical().then(|| self.preferred_x(&content_ui));
+                                    let mut next_x = xs.iter().flatten().copied();
+                                    preferred_x = xs.clone();
+
+                                    self.selections.map(|selection| {
+                                        let primary = motion.apply(
+                                            &content_ui,
+                                            &self.text,
+                                            &mut self.line_layout,
+                                            selection.primary,
+                                            next_x.next(),
+                                            visible_rect.height(),
+                                        );
 
-                                _ => None,
-                            },
-                            Event::Key {
-                                key: Key::A,
-                                pressed: true,
-                                modifiers,
-                                ..
-                            } if modifiers.command_only() => Some(CCursorRange::two(
-                                galley.begin().ccursor,
-                                galley.end().ccursor,
-                            )),
-                            // FIXME: Open should happen in the whole app, not just from editor.
-                            Event::Key {
-                                key: Key::O,
-                                pressed: true,
-                                modifiers,
-                                ..
-                            } if modifiers.command_only() => {
-                                open_file_with_native_dialog(
-                                    &content_ui,
-                                    self.file_channel.0.clone(),
-                                    self.cwd.clone(),
-                                );
-                                None
+                                        if modifiers.shift || self.emacs.mark_active {
+                                            CCursorRange::two(selection.secondary, primary)
+                                        } else {
+                                            CCursorRange::one(primary)
+                                        }
+                                    })
+                                })
                             }
-                            // FIXME: Save should happen in the whole app, not just from editor.
                             Event::Key {
-                                key: Key::S,
+                                key: Key::Escape,
                                 pressed: true,
-                                modifiers,
                                 ..
-                            } if modifiers.command_only() => {
-                                save_text_to_file(self.file.as_str(), self.text.as_str());
-                                None
+                            } => {
+                                let mut selections = self.selections.clone();
+                                selections.collapse();
+                                Some(selections)
                             }
                             _ => None,
                         };
 
-                        if let Some(new_ccursor_range) = new_ccursor_range {
-                            galley = layouter(&content_ui, &self.text, wrap_width);
-                            self.selection = CursorRange {
-                                primary: galley.from_ccursor(new_ccursor_range.primary),
-                                secondary: galley.from_ccursor(new_ccursor_range.secondary),
-                            };
+                        if is_emacs && !swallowed {
+                            let is_input = matches!(
+                                event,
+                                Event::Key { pressed: true, .. }
+                                    | Event::Text(_)
+                                    | Event::Paste(_)
+                                    | Event::Cut
+                            );
+                            if is_input {
+                                self.emacs.finish(emacs_action, motion.is_some());
+                            }
+                            after_meta = matches!(
+                                emacs_action,
+                                Some(emacs::Action::CopyRegion | emacs::Action::YankPop)
+                            );
+                        }
+
+                        if let Some(new_selections) = new_selections {
+                            if !extends_block {
+                                self.block = None;
+                            }
+
+                            set_selections(self, new_selections);
 
-                            // Scroll to the cursor to make sure it's in view after its position changed.
-                            content_ui.scroll_to_rect(cursor_pos, None)
+                            if let Some(xs) = preferred_x {
+                                if xs.len() == self.selections.len() {
+                                    self.preferred_x = Some((self.selections.clone(), xs));
+                                }
+                            }
                         }
                     }
                 }
 
-                // =============================
-                // Draw the text.
-                // =============================
-                if content_ui.is_rect_visible(rect) {
-                    painter.galley(galley_pos, galley.clone(), egui::Color32::WHITE);
+                let cursor_pos = cursor_rect(
+                    &content_ui,
+                    &self.text,
+                    &mut self.line_layout,
+                    self.selections.main().primary,
+                );
+
+                let cursor_stroke = ui.visuals().text_cursor;
+
+                // Turn on IME if we have focus.
+                // IME is supposed to be on when the user is editing text.
+                if content_ui.memory(|m| m.has_focus(id)) {
+                    content_ui.output_mut(|o| {
+                        o.ime = Some(egui::output::IMEOutput {
+                            rect,
+                            cursor_rect: cursor_pos,
+                        })
+                    })
                 }
 
                 // =============================
-                // Draw the cursor.
+                // Draw the text.
                 // =============================
 
-                // The selection.
-                if !self.selection.is_empty() {
-                    // We paint the cursor selection on top of the text, so make it transparent:
-                    let color = content_ui.visuals().selection.bg_fill.linear_multiply(0.5);
-                    let [min, max] = self.selection.sorted_cursors();
-                    let min = min.rcursor;
-                    let max = max.rcursor;
-
-                    for ri in min.row..=max.row {
-                        let row = &galley.rows[ri];
-                        let left = if ri == min.row {
-                            row.x_offset(min.column)
+                // Only lay out the lines that are scrolled into view, plus a few on either side.
+                let last_line = self.text.len_lines() - 1;
+                let first_visible_line = self
+                    .line_layout
+                    .line_at(visible_rect.min.y - galley_pos.y)
+                    .saturating_sub(view::OVERSCAN);
+                let last_visible_line =
+                    (self.line_layout.line_at(visible_rect.max.y - galley_pos.y) + view::OVERSCAN)
+                        .min(last_line);
+
+                // We paint the cursor selection on top of the text, so make it transparent:
+                let selection_color = content_ui.visuals().selection.bg_fill.linear_multiply(0.5);
+
+                for line in first_visible_line..=last_visible_line {
+                    let galley = self.line_layout.galley(&content_ui, &self.text, line);
+
+                    let line_pos = galley_pos + vec2(0.0, self.line_layout.top(line));
+
+                    if content_ui.is_rect_visible(Rect::from_min_size(line_pos, galley.size())) {
+                        painter.galley(line_pos, galley.clone(), egui::Color32::WHITE);
+                    }
+
+                    // =============================
+                    // Draw the selections.
+                    // =============================
+                    let line_range = self.text.line_char_range(line);
+                    let row = &galley.rows[0];
+
+                    for selection in self.selections.ranges() {
+                        let [selection_min, selection_max] = selection.sorted();
+                        if selection_min.index == selection_max.index
+                            || selection_max.index < line_range.start
+                            || selection_min.index > line_range.end
+                        {
+                            continue;
+                        }
+
+                        let left = if selection_min.index >= line_range.start {
+                            row.x_offset(selection_min.index - line_range.start)
                         } else {
                             row.rect.left()
                         };
-                        let right = if ri == max.row {
-                            row.x_offset(max.column)
+                        let right = if selection_max.index <= line_range.end {
+                            row.x_offset(selection_max.index - line_range.start)
                         } else {
-                            let newline_size = if row.ends_with_newline {
-                                row.height() / 2.0 // visualize that we select the newline
-                            } else {
-                                0.0
-                            };
-                            row.rect.right() + newline_size
+                            // visualize that we select the newline
+                            row.rect.right() + row.height() / 2.0
                         };
                         let rect = Rect::from_min_max(
-                            galley_pos + vec2(left, row.min_y()),
-                            galley_pos + vec2(right, row.max_y()),
+                            line_pos + vec2(left, row.min_y()),
+                            line_pos + vec2(right, row.max_y()),
                         );
 
-                        painter.rect_filled(rect, 0.0, color);
+                        painter.rect_filled(rect, 0.0, selection_color);
                     }
                 }
 
-                // The cursor itself.
+                // =============================
+                // Draw the cursors.
+                // =============================
                 if content_ui.memory(|m| m.has_focus(id)) {
-                    painter.line_segment([top, bottom], (cursor_stroke.width, cursor_stroke.color));
+                    for selection in self.selections.ranges() {
+                        let line = self.text.char_to_line(selection.primary.index);
+                        if line < first_visible_line || line > last_visible_line {
+                            continue;
+                        }
+
+                        let cursor_pos = cursor_rect(
+                            &content_ui,
+                            &self.text,
+                            &mut self.line_layout,
+                            selection.primary,
+                        );
+                        if self.profile == Profile::Vim && self.vim.mode() == vim::Mode::Normal {
+                            // The cursor is on a character rather than between two.
+                            let char_width = content_ui.fonts(|f| f.glyph_width(&font_id, ' '));
+                            let block = Rect::from_min_size(
+                                cursor_pos.center_top(),
+                                vec2(char_width, cursor_pos.height()),
+                            );
+                            painter.rect_filled(
+                                block,
+                                0.0,
+                                cursor_stroke.color.linear_multiply(0.5),
+                            );
+                        } else {
+                            painter.line_segment(
+                                [cursor_pos.center_top(), cursor_pos.center_bottom()],
+                                (cursor_stroke.width, cursor_stroke.color),
+                            );
+                        }
+                    }
                 }
 
                 // =============================
@@ -919,6 +1879,42 @@ impl eframe::App for TemplateApp {
     }
 }
 
+/// `origin` extended to cover `target`, with the cursor on the side of `target`.
+fn extend(origin: CCursorRange, target: CCursorRange) -> CCursorRange {
+    let [start, end] = origin.sorted();
+    let [min, max] = target.sorted();
+
+    if min.index < start.index {
+        CCursorRange::two(end, min)
+    } else {
+        CCursorRange::two(start, max)
+    }
+}
+
+/// Whether the selection is just a cursor.
+fn is_empty(ccursor_range: &CCursorRange) -> bool {
+    ccursor_range.primary == ccursor_range.secondary
+}
+
+/// The current time, in seconds since the Unix epoch.
+fn unix_time() -> f64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0.0, |duration| duration.as_secs_f64())
+}
+
+/// How long ago something happened, e.g. "5 s ago".
+fn format_age(seconds: f64) -> String {
+    let seconds = seconds.max(0.0) as u64;
+    if seconds < 60 {
+        format!("{seconds} s ago")
+    } else if seconds < 60 * 60 {
+        format!("{} min ago", seconds / 60)
+    } else {
+        format!("{} h ago", seconds / (60 * 60))
+    }
+}
+
 fn powered_by_egui_and_eframe(ui: &mut egui::Ui) {
     ui.horizontal(|ui| {
         ui.spacing_mut().item_spacing.x = 0.0;
@@ -958,70 +1954,67 @@ fn open_file_with_native_dialog(
     });
 }
 
-fn save_text_to_file(file: &str, text: &str) {
+/// The buffer for the text of a file, and how the file is indented and its lines end.
+fn load_text(text: &str) -> (Buffer, Indentation, LineEndings) {
+    let normalized = line_ending::normalize(text);
+    (
+        Buffer::new(&normalized),
+        Indentation::detect(&normalized),
+        LineEndings::count(text),
+    )
+}
+
+fn save_text_to_file(file: &str, text: &Buffer, line_ending: LineEnding) {
     assert!(!file.is_empty());
 
     // FIXME: Show a message if the file can't be saved.
-    std::fs::write(file, text).expect("Could not save file");
+    let file = std::fs::File::create(file).expect("Could not save file");
+    text.write_to(std::io::BufWriter::new(file), line_ending)
+        .expect("Could not save file");
 }
 
-fn highlight(ctx: &egui::Context, text: &str, in_dark_mode: bool) -> LayoutJob {
-    #[derive(Default)]
-    struct CodeHighlighter {}
-    impl ComputerMut<(&str, bool), LayoutJob> for CodeHighlighter {
-        fn compute(&mut self, (s, in_dark_mode): (&str, bool)) -> LayoutJob {
-            let mut job = LayoutJob::default();
+/// How many files' undo histories are kept. The ones saved least recently are dropped first.
+const MAX_SAVED_HISTORIES: usize = 32;
 
-            let words = s.split(' ');
-            let word_count = words.clone().count();
+/// The key the files with a saved undo history are listed under, most recently saved first.
+const SAVED_HISTORIES_KEY: &str = "histories";
 
-            for (i, word) in words.enumerate() {
-                let color = if i % 2 == 0 {
-                    if in_dark_mode {
-                        Color32::LIGHT_RED
-                    } else {
-                        Color32::RED
-                    }
-                } else if in_dark_mode {
-                    Color32::LIGHT_BLUE
-                } else {
-                    Color32::BLUE
-                };
-
-                job.append(
-                    word,
-                    0.0,
-                    TextFormat {
-                        font_id: FontId::monospace(14.0),
-                        color,
-                        ..Default::default()
-                    },
-                );
-
-                if i != word_count - 1 {
-                    job.append(
-                        " ",
-                        0.0,
-                        TextFormat {
-                            font_id: FontId::monospace(14.0),
-                            ..Default::default()
-                        },
-                    );
-                }
-            }
+/// The key the undo history of the file at `path` is saved under.
+fn history_key(path: &std::path::Path) -> String {
+    let path = std::fs::canonicalize(path).unwrap_or_else(|_| path.to_owned());
+    format!("history {}", path.display())
+}
 
-            job.wrap.max_width = f32::INFINITY;
-            job
+/// Saves the undo history of the file at `path`, so it can be picked up again next time the file
+/// is opened.
+///
+/// Storage can't forget a key, so histories are dropped by saving nothing under it instead.
+fn save_history(storage: &mut dyn eframe::Storage, path: &std::path::Path, history: &History) {
+    let key = history_key(path);
+    let mut keys: Vec<String> = eframe::get_value(storage, SAVED_HISTORIES_KEY).unwrap_or_default();
+    keys.retain(|saved| *saved != key);
+
+    // Nothing to save if no file is open, or nothing has been done to it yet. This also drops a
+    // history that was saved for different contents of the file, which couldn't be loaded.
+    if path.is_file() && (history.can_undo() || history.can_redo()) {
+        eframe::set_value(storage, &key, history);
+        keys.insert(0, key);
+    } else {
+        storage.set_string(&key, String::new());
+    }
 
-            // LayoutJob::simple(
-            //     s.into(),
-            //     FontId::monospace(14.0),
-            //     Color32::LIGHT_GRAY,
-            //     f32::INFINITY,
-            // )
-        }
+    for key in keys.drain(keys.len().min(MAX_SAVED_HISTORIES)..) {
+        storage.set_string(&key, String::new());
     }
-    type HighlightCache = FrameCache<LayoutJob, CodeHighlighter>;
+    eframe::set_value(storage, SAVED_HISTORIES_KEY, &keys);
+}
 
-    ctx.memory_mut(|m| m.caches.cache::<HighlightCache>().get((text, in_dark_mode)))
+/// The undo history saved for the file at `path`, if the file still has the same contents as
+/// when it was saved. Otherwise it's dropped the next time the file's history is saved.
+fn load_history(
+    storage: &dyn eframe::Storage,
+    path: &std::path::Path,
+    text: &Buffer,
+) -> Option<History> {
+    eframe::get_value::<History>(storage, &history_key(path))?.restore(text)
 }
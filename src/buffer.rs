@@ -0,0 +1,385 @@
+use std::ops::Range;
+
+use egui::text::{CCursor, CCursorRange};
+use egui::text_selection::text_cursor_state::is_word_char;
+use ropey::Rope;
+
+use crate::line_ending::LineEnding;
+
+/// The document model of the editor.
+///
+/// Text is stored in a rope so inserting and deleting in the middle of large files doesn't shift
+/// the whole buffer. All positions are *character* indices, the same as `CCursor::index`.
+///
+/// Only `\n` is treated as a line break, which matches how egui splits text into paragraphs.
+#[derive(Clone, Default)]
+pub struct Buffer {
+    rope: Rope,
+
+    /// Line-level changes made since the last call to [`Buffer::take_line_edits`].
+    line_edits: Vec<LineEdit>,
+}
+
+/// Describes how an edit changed the lines of a [`Buffer`].
+///
+/// The contents of `line` changed, `removed` lines after it were removed, and then `inserted` new
+/// lines were added after it.
+#[derive(Clone, Copy, Debug)]
+pub struct LineEdit {
+    pub line: usize,
+    pub removed: usize,
+    pub inserted: usize,
+}
+
+impl Buffer {
+    pub fn new(text: &str) -> Self {
+        Self {
+            rope: Rope::from_str(text),
+            line_edits: Vec::new(),
+        }
+    }
+
+    /// Number of characters in the buffer.
+    pub fn len_chars(&self) -> usize {
+        self.rope.len_chars()
+    }
+
+    /// Number of lines in the buffer. An empty buffer has one line, and a trailing newline starts a
+    /// new, empty, line.
+    pub fn len_lines(&self) -> usize {
+        self.rope.len_lines()
+    }
+
+    /// The line containing the character at `char_index`.
+    pub fn char_to_line(&self, char_index: usize) -> usize {
+        self.rope.char_to_line(char_index.min(self.len_chars()))
+    }
+
+    /// The character index of the start of `line`.
+    pub fn line_to_char(&self, line: usize) -> usize {
+        self.rope.line_to_char(line.min(self.len_lines()))
+    }
+
+    /// The character range of `line`, excluding its trailing newline.
+    pub fn line_char_range(&self, line: usize) -> Range<usize> {
+        let start = self.line_to_char(line);
+        let line_len = self.rope.line(line).len_chars();
+        let has_newline = line + 1 < self.len_lines();
+
+        start..start + line_len - usize::from(has_newline)
+    }
+
+    /// The character range of `line`, including its trailing newline.
+    pub fn line_char_range_with_newline(&self, line: usize) -> Range<usize> {
+        self.line_to_char(line)..self.line_to_char(line + 1)
+    }
+
+    /// The text of `line`, without its trailing newline.
+    pub fn line_text(&self, line: usize) -> String {
+        self.slice(self.line_char_range(line))
+    }
+
+    /// The start of the line the cursor is on.
+    pub fn line_start(&self, ccursor: CCursor) -> CCursor {
+        CCursor::new(self.line_to_char(self.char_to_line(ccursor.index)))
+    }
+
+    /// The end of the line the cursor is on, before the newline.
+    pub fn line_end(&self, ccursor: CCursor) -> CCursor {
+        CCursor::new(self.line_char_range(self.char_to_line(ccursor.index)).end)
+    }
+
+    /// Number of spaces and tabs at the start of `line`.
+    pub fn indent_len(&self, line: usize) -> usize {
+        self.rope
+            .line(line)
+            .chars()
+            .take_while(|&c| c == ' ' || c == '\t')
+            .count()
+    }
+
+    /// Where Home moves the cursor: to the first non-whitespace character of its line, or to the
+    /// start of the line if it's already there.
+    pub fn smart_line_start(&self, ccursor: CCursor) -> CCursor {
+        let line = self.char_to_line(ccursor.index);
+        let start = self.line_to_char(line);
+        let indent_end = start + self.indent_len(line);
+
+        if ccursor.index == indent_end {
+            CCursor::new(start)
+        } else {
+            CCursor::new(indent_end)
+        }
+    }
+
+    /// Clamps the cursor to the buffer.
+    pub fn clamp(&self, ccursor: CCursor) -> CCursor {
+        CCursor {
+            index: ccursor.index.min(self.len_chars()),
+            prefer_next_row: ccursor.prefer_next_row,
+        }
+    }
+
+    /// Takes the line-level changes made since the last call.
+    ///
+    /// Used to keep per-line bookkeeping in sync with the buffer without re-scanning it.
+    pub fn take_line_edits(&mut self) -> Vec<LineEdit> {
+        std::mem::take(&mut self.line_edits)
+    }
+
+    /// The character at `char_index`, if it's in the buffer.
+    pub fn char_at(&self, char_index: usize) -> Option<char> {
+        self.rope.get_char(char_index)
+    }
+
+    /// The text of the given character range.
+    pub fn slice(&self, char_range: Range<usize>) -> String {
+        self.rope.slice(char_range).to_string()
+    }
+
+    /// The text covered by `ccursor_range`.
+    pub fn selected_text(&self, ccursor_range: &CCursorRange) -> String {
+        let [min, max] = ccursor_range.sorted();
+        self.slice(min.index..max.index)
+    }
+
+    /// A hash of the text, for telling whether it's still the same as it was at some earlier point.
+    ///
+    /// It's the 64-bit FNV-1a hash of the UTF-8 bytes, which stays the same between builds, so
+    /// hashes can be saved and compared later.
+    pub fn content_hash(&self) -> u64 {
+        const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+        const PRIME: u64 = 0x0000_0100_0000_01b3;
+
+        self.rope.bytes().fold(OFFSET_BASIS, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(PRIME)
+        })
+    }
+
+    /// Writes the buffer without first collecting it into a `String`, ending lines with
+    /// `line_ending`.
+    pub fn write_to<W: std::io::Write>(
+        &self,
+        mut writer: W,
+        line_ending: LineEnding,
+    ) -> std::io::Result<()> {
+        for chunk in self.rope.chunks() {
+            for (i, part) in chunk.split('\n').enumerate() {
+                if i > 0 {
+                    writer.write_all(line_ending.as_str().as_bytes())?;
+                }
+                writer.write_all(part.as_bytes())?;
+            }
+        }
+        writer.flush()
+    }
+
+    /// Inserts `text` at `ccursor` and moves the cursor to the end of the inserted text.
+    pub fn insert_text_at(&mut self, ccursor: &mut CCursor, text: &str) {
+        let index = ccursor.index.min(self.len_chars());
+        let line = self.rope.char_to_line(index);
+
+        self.rope.insert(index, text);
+        ccursor.index = index + text.chars().count();
+
+        self.line_edits.push(LineEdit {
+            line,
+            removed: 0,
+            inserted: self.rope.char_to_line(ccursor.index) - line,
+        });
+    }
+
+    /// Deletes the character range, clamped to the buffer.
+    pub fn delete_char_range(&mut self, char_range: Range<usize>) {
+        let end = char_range.end.min(self.len_chars());
+        let start = char_range.start.min(end);
+        let line = self.rope.char_to_line(start);
+        let removed = self.rope.char_to_line(end) - line;
+
+        self.rope.remove(start..end);
+
+        self.line_edits.push(LineEdit {
+            line,
+            removed,
+            inserted: 0,
+        });
+    }
+
+    /// Same as egui's `ccursor_next_word`, but walks the rope instead of a `&str`.
+    pub fn next_word(&self, ccursor: CCursor) -> CCursor {
+        let index = ccursor.index.min(self.len_chars());
+
+        CCursor {
+            index: index + word_boundary_distance(self.rope.chars_at(index)),
+            prefer_next_row: false,
+        }
+    }
+
+    /// Same as egui's `ccursor_previous_word`, but walks the rope instead of a `&str`.
+    pub fn previous_word(&self, ccursor: CCursor) -> CCursor {
+        let index = ccursor.index.min(self.len_chars());
+
+        CCursor {
+            index: index - word_boundary_distance(self.rope.chars_at(index).reversed()),
+            prefer_next_row: true,
+        }
+    }
+
+    /// Same as egui's `select_word_at`: the word the cursor is in or next to, or the run of
+    /// non-word characters if there's no word there.
+    pub fn word_at(&self, ccursor: CCursor) -> CCursorRange {
+        let index = ccursor.index.min(self.len_chars());
+        let ccursor = CCursor::new(index);
+
+        if index == 0 {
+            return CCursorRange::two(ccursor, self.next_word(ccursor));
+        }
+
+        let mut chars = self.rope.chars_at(index - 1);
+        match (chars.next(), chars.next()) {
+            (Some(before), Some(after)) => {
+                if is_word_char(before) && is_word_char(after) {
+                    let min = self.previous_word(ccursor + 1);
+                    CCursorRange::two(min, self.next_word(min))
+                } else if is_word_char(before) {
+                    let min = self.previous_word(ccursor);
+                    CCursorRange::two(min, self.next_word(min))
+                } else if is_word_char(after) {
+                    CCursorRange::two(ccursor, self.next_word(ccursor))
+                } else {
+                    CCursorRange::two(self.previous_word(ccursor), self.next_word(ccursor))
+                }
+            }
+            _ => CCursorRange::two(self.previous_word(ccursor), ccursor),
+        }
+    }
+
+    /// The start of the first occurrence of `needle` at or after `from`, wrapping around to the
+    /// start of the buffer if there's none after it.
+    pub fn find(&self, needle: &str, from: usize) -> Option<usize> {
+        let needle_len = needle.chars().count();
+        let first = needle.chars().next()?;
+        let last_start = self.len_chars().checked_sub(needle_len)?;
+
+        let matches_at = |start: usize| self.rope.slice(start..start + needle_len) == needle;
+
+        let from = from.min(last_start + 1);
+        let after = self
+            .rope
+            .chars_at(from)
+            .enumerate()
+            .take(last_start + 1 - from)
+            .find(|&(i, c)| c == first && matches_at(from + i))
+            .map(|(i, _)| from + i);
+
+        after.or_else(|| {
+            self.rope
+                .chars()
+                .take(from)
+                .enumerate()
+                .find(|&(i, c)| c == first && matches_at(i))
+                .map(|(i, _)| i)
+        })
+    }
+}
+
+impl std::fmt::Display for Buffer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for chunk in self.rope.chunks() {
+            f.write_str(chunk)?;
+        }
+        Ok(())
+    }
+}
+
+/// How many characters to skip to reach the next word boundary.
+///
+/// Mirrors egui's word navigation: always step over the first character, then keep going while
+/// the characters are of the same kind (word or non-word) as the second one.
+fn word_boundary_distance(mut it: impl Iterator<Item = char>) -> usize {
+    let mut distance = 0;
+    if it.next().is_some() {
+        distance += 1;
+
+        if let Some(second) = it.next() {
+            distance += 1;
+            for next in it {
+                if is_word_char(next) != is_word_char(second) {
+                    break;
+                }
+                distance += 1;
+            }
+        }
+    }
+    distance
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lines_are_written_with_the_line_ending() {
+        let buffer = Buffer::new("one\ntwo\n");
+
+        let mut lf = Vec::new();
+        buffer.write_to(&mut lf, LineEnding::Lf).unwrap();
+        assert_eq!(lf, b"one\ntwo\n");
+
+        let mut crlf = Vec::new();
+        buffer.write_to(&mut crlf, LineEnding::Crlf).unwrap();
+        assert_eq!(crlf, b"one\r\ntwo\r\n");
+    }
+
+    #[test]
+    fn content_hash_is_fnv_1a() {
+        assert_eq!(Buffer::new("").content_hash(), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(Buffer::new("foobar").content_hash(), 0x8594_4171_f739_67e8);
+
+        // However the rope happens to split the text into chunks.
+        let text = "line\n".repeat(1000);
+        let mut buffer = Buffer::new(&text[..2500]);
+        buffer.insert_text_at(&mut CCursor::new(2500), &text[2500..]);
+        assert_eq!(buffer.content_hash(), Buffer::new(&text).content_hash());
+    }
+
+    #[test]
+    fn find_wraps_around() {
+        let buffer = Buffer::new("one two one two");
+
+        assert_eq!(buffer.find("two", 0), Some(4));
+        assert_eq!(buffer.find("two", 5), Some(12));
+        assert_eq!(buffer.find("one", 9), Some(0));
+        assert_eq!(buffer.find("one", 15), Some(0));
+        assert_eq!(buffer.find("three", 0), None);
+        assert_eq!(buffer.find("", 0), None);
+    }
+
+    #[test]
+    fn smart_line_start_toggles_between_indent_and_line_start() {
+        let buffer = Buffer::new("one\n  \ttwo\n   ");
+        let home = |index| buffer.smart_line_start(CCursor::new(index)).index;
+
+        assert_eq!(home(2), 0);
+        assert_eq!(home(0), 0);
+        assert_eq!(home(10), 7);
+        assert_eq!(home(7), 4);
+        assert_eq!(home(4), 7);
+        assert_eq!(home(13), 14);
+    }
+
+    #[test]
+    fn word_at_selects_the_word_under_the_cursor() {
+        let buffer = Buffer::new("hello, world");
+        let word = |index| {
+            let [min, max] = buffer.word_at(CCursor::new(index)).sorted();
+            buffer.slice(min.index..max.index)
+        };
+
+        assert_eq!(word(0), "hello");
+        assert_eq!(word(3), "hello");
+        assert_eq!(word(5), "hello");
+        assert_eq!(word(9), "world");
+        assert_eq!(word(12), "world");
+    }
+}
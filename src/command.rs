@@ -0,0 +1,252 @@
+use egui::{Key, KeyboardShortcut, Modifiers};
+
+use crate::lines::{LineOperation, SortOrder};
+
+const COMMAND_SHIFT: Modifiers = Modifiers::COMMAND.plus(Modifiers::SHIFT);
+const COMMAND_ALT: Modifiers = Modifiers::COMMAND.plus(Modifiers::ALT);
+
+/// An action of the editor that isn't tied to a single way of triggering it.
+///
+/// Menus, keyboard shortcuts and anything else that triggers a command only queue it, and all
+/// commands are run from the same place, so they behave the same however they're triggered.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Command {
+    Open,
+    Save,
+    Quit,
+    Undo,
+    Redo,
+    SelectAll,
+    SelectNextOccurrence,
+    AddCursorAbove,
+    AddCursorBelow,
+    DuplicateLines,
+    MoveLinesUp,
+    MoveLinesDown,
+    DeleteLines,
+    JoinLines,
+    InsertLineAbove,
+    InsertLineBelow,
+    SortLines,
+    SortLinesCaseInsensitive,
+    SortLinesNumerically,
+    ReverseLines,
+    UniqueLines,
+    TrimTrailingWhitespace,
+    ToggleLineComment,
+    ToggleBlockComment,
+    IndentWithSpaces,
+    IndentWithTabs,
+    UseLfLineEndings,
+    UseCrlfLineEndings,
+    ToggleHistory,
+    ToggleVimMode,
+    ToggleEmacsKeys,
+    ShowCommandPalette,
+    ReloadKeymap,
+}
+
+impl Command {
+    /// Every command.
+    pub const ALL: &'static [Self] = &[
+        Self::Open,
+        Self::Save,
+        Self::Quit,
+        Self::Undo,
+        Self::Redo,
+        Self::SelectAll,
+        Self::SelectNextOccurrence,
+        Self::AddCursorAbove,
+        Self::AddCursorBelow,
+        Self::DuplicateLines,
+        Self::MoveLinesUp,
+        Self::MoveLinesDown,
+        Self::DeleteLines,
+        Self::JoinLines,
+        Self::InsertLineAbove,
+        Self::InsertLineBelow,
+        Self::SortLines,
+        Self::SortLinesCaseInsensitive,
+        Self::SortLinesNumerically,
+        Self::ReverseLines,
+        Self::UniqueLines,
+        Self::TrimTrailingWhitespace,
+        Self::ToggleLineComment,
+        Self::ToggleBlockComment,
+        Self::IndentWithSpaces,
+        Self::IndentWithTabs,
+        Self::UseLfLineEndings,
+        Self::UseCrlfLineEndings,
+        Self::ToggleHistory,
+        Self::ToggleVimMode,
+        Self::ToggleEmacsKeys,
+        Self::ShowCommandPalette,
+        Self::ReloadKeymap,
+    ];
+
+    /// A name for the command that stays the same between versions.
+    pub fn id(self) -> &'static str {
+        match self {
+            Self::Open => "file.open",
+            Self::Save => "file.save",
+            Self::Quit => "file.quit",
+            Self::Undo => "edit.undo",
+            Self::Redo => "edit.redo",
+            Self::SelectAll => "selection.select_all",
+            Self::SelectNextOccurrence => "selection.select_next_occurrence",
+            Self::AddCursorAbove => "selection.add_cursor_above",
+            Self::AddCursorBelow => "selection.add_cursor_below",
+            Self::DuplicateLines => "edit.duplicate_lines",
+            Self::MoveLinesUp => "edit.move_lines_up",
+            Self::MoveLinesDown => "edit.move_lines_down",
+            Self::DeleteLines => "edit.delete_lines",
+            Self::JoinLines => "edit.join_lines",
+            Self::InsertLineAbove => "edit.insert_line_above",
+            Self::InsertLineBelow => "edit.insert_line_below",
+            Self::SortLines => "edit.sort_lines",
+            Self::SortLinesCaseInsensitive => "edit.sort_lines_case_insensitive",
+            Self::SortLinesNumerically => "edit.sort_lines_numerically",
+            Self::ReverseLines => "edit.reverse_lines",
+            Self::UniqueLines => "edit.unique_lines",
+            Self::TrimTrailingWhitespace => "edit.trim_trailing_whitespace",
+            Self::ToggleLineComment => "edit.toggle_line_comment",
+            Self::ToggleBlockComment => "edit.toggle_block_comment",
+            Self::IndentWithSpaces => "edit.indent_with_spaces",
+            Self::IndentWithTabs => "edit.indent_with_tabs",
+            Self::UseLfLineEndings => "file.use_lf_line_endings",
+            Self::UseCrlfLineEndings => "file.use_crlf_line_endings",
+            Self::ToggleHistory => "view.toggle_history",
+            Self::ToggleVimMode => "settings.toggle_vim_mode",
+            Self::ToggleEmacsKeys => "settings.toggle_emacs_keys",
+            Self::ShowCommandPalette => "view.command_palette",
+            Self::ReloadKeymap => "settings.reload_keymap",
+        }
+    }
+
+    /// What the command does, in a few words.
+    pub fn description(self) -> &'static str {
+        match self {
+            Self::Open => "Open a file",
+            Self::Save => "Save the file",
+            Self::Quit => "Quit",
+            Self::Undo => "Undo the last edit",
+            Self::Redo => "Redo the last undone edit",
+            Self::SelectAll => "Select all",
+            Self::SelectNextOccurrence => "Select the next occurrence of the selection",
+            Self::AddCursorAbove => "Add a cursor on the line above",
+            Self::AddCursorBelow => "Add a cursor on the line below",
+            Self::DuplicateLines => "Duplicate the selected lines",
+            Self::MoveLinesUp => "Move the selected lines up",
+            Self::MoveLinesDown => "Move the selected lines down",
+            Self::DeleteLines => "Delete the selected lines",
+            Self::JoinLines => "Join the selected lines, or the line with the next",
+            Self::InsertLineAbove => "Insert a line above",
+            Self::InsertLineBelow => "Insert a line below",
+            Self::SortLines => "Sort the selected lines, or all lines",
+            Self::SortLinesCaseInsensitive => "Sort lines, ignoring case",
+            Self::SortLinesNumerically => "Sort lines by the number they start with",
+            Self::ReverseLines => "Reverse the order of lines",
+            Self::UniqueLines => "Remove duplicate lines",
+            Self::TrimTrailingWhitespace => "Remove whitespace from the ends of lines",
+            Self::ToggleLineComment => "Comment out the selected lines, or uncomment them",
+            Self::ToggleBlockComment => "Wrap the selection in a block comment, or unwrap it",
+            Self::IndentWithSpaces => "Convert the indentation to spaces",
+            Self::IndentWithTabs => "Convert the indentation to tabs",
+            Self::UseLfLineEndings => "Save with LF line endings",
+            Self::UseCrlfLineEndings => "Save with CRLF line endings",
+            Self::ToggleHistory => "Show or hide the undo history",
+            Self::ToggleVimMode => "Turn Vim mode on or off",
+            Self::ToggleEmacsKeys => "Turn Emacs keys on or off",
+            Self::ShowCommandPalette => "Show all commands",
+            Self::ReloadKeymap => "Reload the keymap file",
+        }
+    }
+
+    /// The shortcuts the command is bound to unless the keymap says otherwise, the one to show in
+    /// menus first.
+    pub fn default_shortcuts(self) -> Vec<KeyboardShortcut> {
+        match self {
+            Self::Open => vec![KeyboardShortcut::new(Modifiers::COMMAND, Key::O)],
+            Self::Save => vec![KeyboardShortcut::new(Modifiers::COMMAND, Key::S)],
+            Self::Quit
+            | Self::IndentWithSpaces
+            | Self::IndentWithTabs
+            | Self::SortLines
+            | Self::SortLinesCaseInsensitive
+            | Self::SortLinesNumerically
+            | Self::ReverseLines
+            | Self::UniqueLines
+            | Self::TrimTrailingWhitespace
+            | Self::UseLfLineEndings
+            | Self::UseCrlfLineEndings
+            | Self::ToggleHistory
+            | Self::ToggleVimMode
+            | Self::ToggleEmacsKeys
+            | Self::ReloadKeymap => vec![],
+            Self::Undo => vec![KeyboardShortcut::new(Modifiers::COMMAND, Key::Z)],
+            Self::Redo => vec![
+                KeyboardShortcut::new(COMMAND_SHIFT, Key::Z),
+                KeyboardShortcut::new(Modifiers::COMMAND, Key::Y),
+            ],
+            Self::SelectAll => vec![KeyboardShortcut::new(Modifiers::COMMAND, Key::A)],
+            Self::SelectNextOccurrence => vec![KeyboardShortcut::new(Modifiers::COMMAND, Key::D)],
+            Self::AddCursorAbove => vec![KeyboardShortcut::new(COMMAND_ALT, Key::ArrowUp)],
+            Self::AddCursorBelow => vec![KeyboardShortcut::new(COMMAND_ALT, Key::ArrowDown)],
+            Self::DuplicateLines => vec![KeyboardShortcut::new(COMMAND_SHIFT, Key::D)],
+            Self::MoveLinesUp => vec![KeyboardShortcut::new(Modifiers::ALT, Key::ArrowUp)],
+            Self::MoveLinesDown => vec![KeyboardShortcut::new(Modifiers::ALT, Key::ArrowDown)],
+            Self::DeleteLines => vec![KeyboardShortcut::new(COMMAND_SHIFT, Key::K)],
+            Self::JoinLines => vec![KeyboardShortcut::new(Modifiers::COMMAND, Key::J)],
+            Self::InsertLineAbove => vec![KeyboardShortcut::new(COMMAND_SHIFT, Key::Enter)],
+            Self::InsertLineBelow => vec![KeyboardShortcut::new(Modifiers::COMMAND, Key::Enter)],
+            Self::ToggleLineComment => vec![KeyboardShortcut::new(Modifiers::COMMAND, Key::Slash)],
+            Self::ToggleBlockComment => vec![
+                KeyboardShortcut::new(COMMAND_SHIFT, Key::Slash),
+                // Shift+/ is a question mark on many keyboards.
+                KeyboardShortcut::new(COMMAND_SHIFT, Key::Questionmark),
+            ],
+            Self::ShowCommandPalette => vec![KeyboardShortcut::new(COMMAND_SHIFT, Key::P)],
+        }
+    }
+
+    /// The line operation the command makes, if it's one of the commands editing whole lines.
+    pub fn line_operation(self) -> Option<LineOperation> {
+        let operation = match self {
+            Self::DuplicateLines => LineOperation::Duplicate,
+            Self::MoveLinesUp => LineOperation::MoveUp,
+            Self::MoveLinesDown => LineOperation::MoveDown,
+            Self::DeleteLines => LineOperation::Delete,
+            Self::JoinLines => LineOperation::Join,
+            Self::InsertLineAbove => LineOperation::InsertAbove,
+            Self::InsertLineBelow => LineOperation::InsertBelow,
+            Self::SortLines => LineOperation::Sort(SortOrder::CaseSensitive),
+            Self::SortLinesCaseInsensitive => LineOperation::Sort(SortOrder::CaseInsensitive),
+            Self::SortLinesNumerically => LineOperation::Sort(SortOrder::Numeric),
+            Self::ReverseLines => LineOperation::Reverse,
+            Self::UniqueLines => LineOperation::Unique,
+            Self::TrimTrailingWhitespace => LineOperation::TrimTrailingWhitespace,
+            _ => return None,
+        };
+        Some(operation)
+    }
+
+    /// The command with the given [`Command::id`].
+    pub fn from_id(id: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|command| command.id() == id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_have_distinct_ids() {
+        for (i, command) in Command::ALL.iter().enumerate() {
+            for other in &Command::ALL[i + 1..] {
+                assert_ne!(command.id(), other.id());
+            }
+            assert_eq!(Command::from_id(command.id()), Some(*command));
+        }
+    }
+}
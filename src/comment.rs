@@ -0,0 +1,393 @@
+use std::ops::Range;
+
+use egui::text::{CCursor, CCursorRange};
+
+use crate::buffer::Buffer;
+use crate::language::Language;
+use crate::lines::{selected_lines, LinesEdit};
+use crate::selection::Selections;
+
+/// Text replaced to add or remove comment markers.
+struct Change {
+    range: Range<usize>,
+    text: String,
+
+    /// How many characters at the start of `range` are removed before the text that's kept.
+    skip: usize,
+
+    /// How many characters of `range` are kept, after `skip`.
+    keep: usize,
+
+    /// Where the kept text starts in `text`.
+    cursor: usize,
+}
+
+impl Change {
+    fn insert(index: usize, text: String, cursor: usize) -> Self {
+        Self {
+            range: index..index,
+            text,
+            skip: 0,
+            keep: 0,
+            cursor,
+        }
+    }
+
+    fn remove(range: Range<usize>) -> Self {
+        Self {
+            skip: range.len(),
+            range,
+            text: String::new(),
+            keep: 0,
+            cursor: 0,
+        }
+    }
+}
+
+/// The edits commenting out the lines the selections are on, or uncommenting them if they're all
+/// commented out already, and the selections after them.
+///
+/// The comment markers go at the indentation of the least indented line, so they line up and the
+/// lines keep their indentation relative to each other. Empty lines are left alone. Languages
+/// without line comments get a block comment on each line instead.
+pub fn toggle_line_comments(
+    buffer: &Buffer,
+    selections: &Selections,
+    language: Language,
+) -> Option<(Vec<LinesEdit>, Selections)> {
+    let (open, close) = match (language.line_comment(), language.block_comment()) {
+        (Some(open), _) => (open, None),
+        (None, Some((open, close))) => (open, Some(close)),
+        (None, None) => return None,
+    };
+
+    let mut lines = selections
+        .ranges()
+        .iter()
+        .flat_map(|&selection| selected_lines(buffer, selection))
+        .filter(|&line| !buffer.line_text(line).trim().is_empty())
+        .collect::<Vec<_>>();
+    lines.sort_unstable();
+    lines.dedup();
+    if lines.is_empty() {
+        return None;
+    }
+
+    let commented = lines.iter().all(|&line| {
+        let text = buffer.line_text(line);
+        let text = text.trim();
+        starts_with_marker(text, open) && close.map_or(true, |close| text.ends_with(close))
+    });
+
+    let mut changes = Vec::new();
+    if commented {
+        for line in lines {
+            let text = buffer.line_text(line);
+            let start = buffer.line_to_char(line) + buffer.indent_len(line);
+            let content = text.trim();
+
+            let open_len = marker_len(content, open, true);
+            changes.push(Change::remove(start..start + open_len));
+
+            if let Some(close) = close {
+                let end = start + content.chars().count();
+                let close_len = marker_len(content, close, false).min(end - start - open_len);
+                changes.push(Change::remove(end - close_len..end));
+            }
+        }
+    } else {
+        let indent = lines
+            .iter()
+            .map(|&line| buffer.indent_len(line))
+            .min()
+            .unwrap_or_default();
+
+        for line in lines {
+            let start = buffer.line_to_char(line) + indent;
+            let marker = format!("{open} ");
+            let len = marker.chars().count();
+            changes.push(Change::insert(start, marker, len));
+
+            if let Some(close) = close {
+                let end = buffer.line_char_range(line).end;
+                changes.push(Change::insert(end, format!(" {close}"), 0));
+            }
+        }
+    }
+
+    Some(finish(changes, selections))
+}
+
+/// The edits wrapping each selection in a block comment, or unwrapping it if it's a block comment
+/// already or is right inside one, and the selections after them. An empty selection stands for
+/// the text on its line.
+///
+/// Languages without block comments get line comments instead.
+pub fn toggle_block_comments(
+    buffer: &Buffer,
+    selections: &Selections,
+    language: Language,
+) -> Option<(Vec<LinesEdit>, Selections)> {
+    let Some((open, close)) = language.block_comment() else {
+        return toggle_line_comments(buffer, selections, language);
+    };
+
+    let mut changes: Vec<Change> = Vec::new();
+    for selection in selections.ranges() {
+        let [min, max] = selection.sorted();
+        let range = if min == max {
+            let line = buffer.char_to_line(min.index);
+            let start = buffer.line_to_char(line) + buffer.indent_len(line);
+            let content = buffer.line_text(line);
+            start..start + content.trim().chars().count()
+        } else {
+            min.index..max.index
+        };
+
+        if range.is_empty() {
+            continue;
+        }
+
+        let text = buffer.slice(range.clone());
+        let leading = text.chars().take_while(|c| c.is_whitespace()).count();
+        let content = text.trim();
+        let len = content.chars().count();
+        let (open_len, close_len) = (open.chars().count(), close.chars().count());
+
+        // The markers right around the selection, which is where wrapping it leaves them.
+        let before = buffer.slice(range.start.saturating_sub(open_len + 1)..range.start);
+        let after = buffer.slice(range.end..(range.end + close_len + 1).min(buffer.len_chars()));
+        let before_len = if before.ends_with(&format!("{open} ")) {
+            open_len + 1
+        } else if before.ends_with(open) {
+            open_len
+        } else {
+            0
+        };
+        let after_len = if after.starts_with(&format!(" {close}")) {
+            close_len + 1
+        } else if after.starts_with(close) {
+            close_len
+        } else {
+            0
+        };
+
+        let change = if before_len > 0 && after_len > 0 {
+            Change {
+                range: range.start - before_len..range.end + after_len,
+                keep: range.len(),
+                text,
+                skip: before_len,
+                cursor: 0,
+            }
+        } else if content.starts_with(open)
+            && content.ends_with(close)
+            && len >= open_len + close_len
+        {
+            let start = range.start + leading;
+            let skip = marker_len(content, open, true);
+            let keep = len - skip - marker_len(content, close, false).min(len - skip);
+            let inner = content.chars().skip(skip).take(keep).collect();
+            Change {
+                range: start..start + len,
+                text: inner,
+                skip,
+                keep,
+                cursor: 0,
+            }
+        } else {
+            Change {
+                keep: range.len(),
+                range,
+                text: format!("{open} {text} {close}"),
+                skip: 0,
+                cursor: open_len + 1,
+            }
+        };
+
+        // Cursors on the same line toggle it once, and so do selections in the same comment.
+        let overlaps = changes
+            .last()
+            .is_some_and(|last| change.range.start < last.range.end || change.range == last.range);
+        if !overlaps {
+            changes.push(change);
+        }
+    }
+
+    (!changes.is_empty()).then(|| finish(changes, selections))
+}
+
+/// Whether `text` starts with the comment marker `open`, rather than a longer one starting the
+/// same way, like `///` for a doc comment, which is commented out like any other line.
+fn starts_with_marker(text: &str, open: &str) -> bool {
+    text.strip_prefix(open).is_some_and(|rest| {
+        open.chars()
+            .last()
+            .map_or(true, |last| !rest.starts_with(last))
+    })
+}
+
+/// How many characters `marker` takes at the start of `text`, or at the end if not `at_start`,
+/// along with the space between it and the rest.
+fn marker_len(text: &str, marker: &str, at_start: bool) -> usize {
+    let spaced = if at_start {
+        text.starts_with(&format!("{marker} "))
+    } else {
+        text.ends_with(&format!(" {marker}"))
+    };
+    marker.chars().count() + usize::from(spaced)
+}
+
+/// The edits making `changes`, which are sorted and don't overlap, and `selections` moved to
+/// where the text they were on ends up.
+fn finish(changes: Vec<Change>, selections: &Selections) -> (Vec<LinesEdit>, Selections) {
+    let shift = |ccursor: CCursor| {
+        let mut offset = 0isize;
+        for change in &changes {
+            if ccursor.index < change.range.start {
+                break;
+            }
+            if ccursor.index <= change.range.end {
+                let start = change.range.start.saturating_add_signed(offset);
+                let kept = (ccursor.index - change.range.start).saturating_sub(change.skip);
+                return CCursor::new(start + change.cursor + kept.min(change.keep));
+            }
+            offset += change.text.chars().count() as isize - change.range.len() as isize;
+        }
+        CCursor::new(ccursor.index.saturating_add_signed(offset))
+    };
+    let selections = selections.map(|selection| CCursorRange {
+        primary: shift(selection.primary),
+        secondary: shift(selection.secondary),
+    });
+
+    let edits = changes
+        .into_iter()
+        .map(|change| LinesEdit {
+            range: change.range,
+            text: change.text,
+        })
+        .collect();
+    (edits, selections)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `text` after toggling comments with selections from each `[` to the `]` after it, and
+    /// cursors at each `|`, with the selections after marked the same way.
+    fn toggle(text: &str, language: Language, block: bool) -> String {
+        let mut plain = String::new();
+        let mut ranges = Vec::new();
+        let mut start = None;
+        for c in text.chars() {
+            let index = CCursor::new(plain.chars().count());
+            match c {
+                '[' => start = Some(index),
+                ']' => ranges.push(CCursorRange::two(start.take().unwrap(), index)),
+                '|' => ranges.push(CCursorRange::one(index)),
+                c => plain.push(c),
+            }
+        }
+        let buffer = Buffer::new(&plain);
+        let selections = Selections::new(ranges, 0);
+
+        let toggled = if block {
+            toggle_block_comments(&buffer, &selections, language)
+        } else {
+            toggle_line_comments(&buffer, &selections, language)
+        };
+        let Some((edits, selections)) = toggled else {
+            return text.to_owned();
+        };
+
+        let mut chars = plain.chars().collect::<Vec<_>>();
+        for edit in edits.iter().rev() {
+            chars.splice(edit.range.clone(), edit.text.chars());
+        }
+        let marks = selections.ranges().iter().flat_map(|range| {
+            let [min, max] = range.sorted();
+            if min == max {
+                vec![(min.index, '|')]
+            } else {
+                vec![(min.index, '['), (max.index, ']')]
+            }
+        });
+        for (index, mark) in marks.collect::<Vec<_>>().into_iter().rev() {
+            chars.insert(index, mark);
+        }
+        chars.into_iter().collect()
+    }
+
+    #[test]
+    fn line_comments_line_up_and_toggle_back() {
+        let code = "fn main() {\n    [if x {\n        y();\n\n    }\n]}";
+        let commented = "fn main() {\n    // [if x {\n    //     y();\n\n    // }\n]}";
+        assert_eq!(toggle(code, Language::Rust, false), commented);
+        assert_eq!(toggle(commented, Language::Rust, false), code);
+
+        assert_eq!(toggle("  x = 1|", Language::Python, false), "  # x = 1|");
+        assert_eq!(toggle("#x|", Language::Python, false), "x|");
+        assert_eq!(
+            toggle("<p>|</p>", Language::Html, false),
+            "<!-- <p>|</p> -->"
+        );
+        assert_eq!(
+            toggle("<!-- <p>|</p> -->", Language::Html, false),
+            "<p>|</p>"
+        );
+        assert_eq!(toggle("text|", Language::PlainText, false), "text|");
+
+        // Doc comments aren't comment markers, so they're commented out like anything else.
+        assert_eq!(
+            toggle("/// doc|\nfn f() {}", Language::Rust, false),
+            "// /// doc|\nfn f() {}"
+        );
+        assert_eq!(
+            toggle("// /// doc|\nfn f() {}", Language::Rust, false),
+            "/// doc|\nfn f() {}"
+        );
+
+        // Every line with a cursor on it, once.
+        assert_eq!(
+            toggle("a|\n|b|\nc\nd|", Language::Python, false),
+            "# a|\n# |b|\nc\n# d|"
+        );
+    }
+
+    #[test]
+    fn block_comments_wrap_the_selection() {
+        assert_eq!(
+            toggle("call([a, b]);", Language::Rust, true),
+            "call(/* [a, b] */);"
+        );
+        assert_eq!(
+            toggle("call(/* [a, b] */);", Language::Rust, true),
+            "call([a, b]);"
+        );
+        assert_eq!(
+            toggle("call([/* a, b */]);", Language::Rust, true),
+            "call([a, b]);"
+        );
+        assert_eq!(toggle("  x|;", Language::Css, true), "  /* x|; */");
+        assert_eq!(toggle("  /* x|; */", Language::Css, true), "  x|;");
+        assert_eq!(toggle("x|", Language::Python, true), "# x|");
+
+        assert_eq!(
+            toggle("f([a], [b]);", Language::Rust, true),
+            "f(/* [a] */, /* [b] */);"
+        );
+        assert_eq!(
+            toggle("f(/* [a] */, /* [b] */);", Language::Rust, true),
+            "f([a], [b]);"
+        );
+
+        // Selections in the same comment, once it takes in the markers around them.
+        assert_eq!(
+            toggle("/* [a] */* [b] */", Language::Rust, true),
+            "[a]* [b] */"
+        );
+        assert_eq!(toggle("  /* x|; y|; */", Language::Css, true), "  x|; y|;");
+    }
+}
@@ -0,0 +1,238 @@
+use egui::{Key, Modifiers};
+
+use crate::keymap::Keymap;
+use crate::motion::Motion;
+
+/// Most texts kept in the kill ring.
+const KILL_RING_SIZE: usize = 60;
+
+/// A command bound to a key with Emacs keys, other than moving the cursor.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    /// C-k: kills the rest of the line, or the newline at the end of it.
+    KillLine,
+
+    /// C-w: kills the selection.
+    KillRegion,
+
+    /// M-w: copies the selection into the kill ring.
+    CopyRegion,
+
+    /// C-y: inserts the last kill.
+    Yank,
+
+    /// M-y: replaces what was just yanked with the kill before it.
+    YankPop,
+
+    /// C-space: sets the mark at the cursor, so moving selects from there.
+    SetMark,
+
+    /// C-g: deactivates the mark.
+    Quit,
+}
+
+/// Whether `modifiers` are just Ctrl, and maybe Shift. On a Mac, Cmd shortcuts are left alone.
+fn is_ctrl(modifiers: Modifiers) -> bool {
+    modifiers.ctrl && !modifiers.mac_cmd && !modifiers.alt
+}
+
+/// Whether `modifiers` are just Meta, which is Alt, or Option on a Mac.
+fn is_meta(modifiers: Modifiers) -> bool {
+    modifiers.alt && !modifiers.ctrl && !modifiers.command
+}
+
+impl Action {
+    pub fn from_key(key: Key, modifiers: Modifiers) -> Option<Self> {
+        // Only motions take Shift, which extends the selection.
+        if modifiers.shift {
+            return None;
+        }
+
+        let action = match key {
+            Key::K if is_ctrl(modifiers) => Self::KillLine,
+            Key::W if is_ctrl(modifiers) => Self::KillRegion,
+            Key::W if is_meta(modifiers) => Self::CopyRegion,
+            Key::Y if is_ctrl(modifiers) => Self::Yank,
+            Key::Y if is_meta(modifiers) => Self::YankPop,
+            Key::Space if is_ctrl(modifiers) => Self::SetMark,
+            Key::G if is_ctrl(modifiers) => Self::Quit,
+            _ => return None,
+        };
+        Some(action)
+    }
+}
+
+/// The motion `key` is bound to with `modifiers`, ignoring Shift.
+pub fn motion(key: Key, modifiers: Modifiers) -> Option<Motion> {
+    if !is_ctrl(modifiers) {
+        return None;
+    }
+
+    let motion = match key {
+        Key::A => Motion::LineStart,
+        Key::E => Motion::LineEnd,
+        Key::F => Motion::Right,
+        Key::B => Motion::Left,
+        Key::N => Motion::Down,
+        Key::P => Motion::Up,
+        _ => return None,
+    };
+    Some(motion)
+}
+
+/// Whether `key` does something with Emacs keys, and shouldn't be taken as a shortcut.
+///
+/// Emacs keys only take Shift to extend the selection, so a shortcut `keymap` binds with Shift,
+/// like Ctrl+Shift+P, is taken as the shortcut.
+pub fn handles(key: Key, modifiers: Modifiers, keymap: &Keymap) -> bool {
+    if modifiers.shift && keymap.binds(key, modifiers) {
+        return false;
+    }
+    Action::from_key(key, modifiers).is_some() || motion(key, modifiers).is_some()
+}
+
+/// The state of Emacs keys: the kill ring, and the mark.
+#[derive(Default)]
+pub struct Emacs {
+    /// Killed and copied text, the most recent last.
+    kill_ring: Vec<String>,
+
+    /// Which text in the kill ring was yanked last, and how many characters it is.
+    yanked: Option<(usize, usize)>,
+
+    /// Whether the mark is set, making motions extend the selection from it.
+    pub mark_active: bool,
+
+    /// The command handled last, since kills right after each other are one kill, and M-y only
+    /// works right after a yank.
+    last: Option<Action>,
+}
+
+impl Emacs {
+    /// Adds killed text to the kill ring, or to the last kill if it came right before.
+    pub fn kill(&mut self, text: &str) {
+        match (self.last, self.kill_ring.last_mut()) {
+            (Some(Action::KillLine | Action::KillRegion), Some(last)) => last.push_str(text),
+            _ => self.save(text),
+        }
+    }
+
+    /// Adds copied text to the kill ring.
+    pub fn save(&mut self, text: &str) {
+        self.kill_ring.push(text.to_owned());
+        if self.kill_ring.len() > KILL_RING_SIZE {
+            self.kill_ring.remove(0);
+        }
+    }
+
+    /// The last kill, if there is one.
+    pub fn latest(&self) -> Option<&str> {
+        self.kill_ring.last().map(String::as_str)
+    }
+
+    /// The text to yank.
+    pub fn yank(&mut self) -> Option<String> {
+        let text = self.kill_ring.last()?.clone();
+        self.yanked = Some((self.kill_ring.len() - 1, text.chars().count()));
+        Some(text)
+    }
+
+    /// How many characters before the cursor were just yanked, and the kill before them to
+    /// replace them with, if the last command was a yank.
+    pub fn yank_pop(&mut self) -> Option<(usize, String)> {
+        if !matches!(self.last, Some(Action::Yank | Action::YankPop)) {
+            return None;
+        }
+
+        let (index, len) = self.yanked?;
+        let index = index.checked_sub(1).unwrap_or(self.kill_ring.len() - 1);
+        let text = self.kill_ring[index].clone();
+        self.yanked = Some((index, text.chars().count()));
+        Some((len, text))
+    }
+
+    /// Call after every key or text event, with the command it triggered if any, and whether it
+    /// moved the cursor. Anything but moving deactivates the mark.
+    pub fn finish(&mut self, action: Option<Action>, moved: bool) {
+        self.last = action;
+        if !moved && action != Some(Action::SetMark) {
+            self.mark_active = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kills_in_a_row_are_one_kill() {
+        let mut emacs = Emacs::default();
+
+        emacs.kill("one");
+        emacs.finish(Some(Action::KillLine), false);
+        emacs.kill("\n");
+        emacs.finish(Some(Action::KillLine), false);
+        assert_eq!(emacs.latest(), Some("one\n"));
+
+        emacs.finish(None, true);
+        emacs.kill("two");
+        assert_eq!(emacs.latest(), Some("two"));
+    }
+
+    #[test]
+    fn yank_pop_cycles_through_the_kill_ring() {
+        let mut emacs = Emacs::default();
+        emacs.save("one");
+        emacs.save("two");
+        emacs.save("three");
+
+        // Only right after a yank.
+        assert_eq!(emacs.yank_pop(), None);
+
+        assert_eq!(emacs.yank().as_deref(), Some("three"));
+        emacs.finish(Some(Action::Yank), false);
+        assert_eq!(emacs.yank_pop(), Some((5, "two".to_owned())));
+        emacs.finish(Some(Action::YankPop), false);
+        assert_eq!(emacs.yank_pop(), Some((3, "one".to_owned())));
+        emacs.finish(Some(Action::YankPop), false);
+        assert_eq!(emacs.yank_pop(), Some((3, "three".to_owned())));
+    }
+
+    #[test]
+    fn motions_keep_the_mark_active() {
+        let mut emacs = Emacs {
+            mark_active: true,
+            ..Default::default()
+        };
+        emacs.finish(Some(Action::SetMark), false);
+        emacs.finish(None, true);
+        assert!(emacs.mark_active);
+        emacs.finish(Some(Action::CopyRegion), false);
+        assert!(!emacs.mark_active);
+
+        assert_eq!(
+            motion(Key::F, Modifiers::CTRL | Modifiers::SHIFT),
+            Some(Motion::Right)
+        );
+        assert_eq!(motion(Key::F, Modifiers::MAC_CMD | Modifiers::CTRL), None);
+    }
+
+    #[test]
+    fn shortcuts_with_shift_are_left_to_the_keymap() {
+        let keymap = Keymap::default();
+
+        // Ctrl+Shift as it's pressed on Linux and Windows, where Ctrl is the command key.
+        let ctrl = Modifiers::CTRL | Modifiers::COMMAND;
+        let ctrl_shift = ctrl | Modifiers::SHIFT;
+
+        // The command palette and deleting lines, rather than selecting up and killing the line.
+        assert!(!handles(Key::P, ctrl_shift, &keymap));
+        assert!(!handles(Key::K, ctrl_shift, &keymap));
+        assert_eq!(Action::from_key(Key::K, ctrl_shift), None);
+
+        assert!(handles(Key::P, ctrl, &keymap));
+        assert!(handles(Key::K, ctrl, &keymap));
+        assert!(handles(Key::F, ctrl_shift, &keymap));
+    }
+}
@@ -0,0 +1,83 @@
+use egui::text::LayoutJob;
+use egui::{Color32, FontId, TextFormat};
+
+/// The state of the highlighter at a line boundary.
+///
+/// Lines are highlighted one at a time, so anything a line needs to know about the lines before it
+/// has to be carried over in here. Two lines with the same text and the same start state always
+/// highlight the same way.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HighlightState {
+    /// Words alternate between two colors, and newlines don't break words, so we need to know
+    /// which color the word in progress at the start of the line has.
+    odd_word: bool,
+}
+
+/// Highlights a single line of text, without its trailing newline.
+///
+/// Returns the layout job for the line and the state at the end of it.
+pub fn highlight_line(
+    text: &str,
+    state: HighlightState,
+    in_dark_mode: bool,
+) -> (LayoutJob, HighlightState) {
+    let mut job = LayoutJob::default();
+
+    let words = text.split(' ');
+    let word_count = words.clone().count();
+
+    for (i, word) in words.enumerate() {
+        let color = if (i % 2 == 1) == state.odd_word {
+            if in_dark_mode {
+                Color32::LIGHT_RED
+            } else {
+                Color32::RED
+            }
+        } else if in_dark_mode {
+            Color32::LIGHT_BLUE
+        } else {
+            Color32::BLUE
+        };
+
+        job.append(
+            word,
+            0.0,
+            TextFormat {
+                font_id: FontId::monospace(14.0),
+                color,
+                ..Default::default()
+            },
+        );
+
+        if i != word_count - 1 {
+            job.append(
+                " ",
+                0.0,
+                TextFormat {
+                    font_id: FontId::monospace(14.0),
+                    ..Default::default()
+                },
+            );
+        }
+    }
+
+    job.wrap.max_width = f32::INFINITY;
+
+    (job, end_state(text, state))
+
+    // LayoutJob::simple(
+    //     s.into(),
+    //     FontId::monospace(14.0),
+    //     Color32::LIGHT_GRAY,
+    //     f32::INFINITY,
+    // )
+}
+
+/// The state at the end of a line, without highlighting it.
+pub fn end_state(text: &str, state: HighlightState) -> HighlightState {
+    let spaces = text.chars().filter(|c| *c == ' ').count();
+
+    HighlightState {
+        odd_word: state.odd_word != (spaces % 2 == 1),
+    }
+}
@@ -0,0 +1,1670 @@
+use std::ops::Range;
+
+use egui::text::{CCursor, CCursorRange};
+use egui::text_selection::text_cursor_state::is_word_char;
+use egui::Modifiers;
+
+use crate::buffer::Buffer;
+use crate::indent::Indentation;
+use crate::selection::Selections;
+
+/// Typed characters and backspaces that follow each other within this many seconds can be undone
+/// in one go.
+const GROUP_TIMEOUT: f64 = 1.0;
+
+/// A single undoable edit.
+///
+/// All ranges are in characters, not bytes, so they can be used with `CCursor`s directly.
+#[derive(Clone, serde::Deserialize, serde::Serialize)]
+pub enum EditAction {
+    Delete {
+        text: String,
+
+        /// Where `text` was before it was deleted.
+        range: Range<usize>,
+
+        selection_before: CCursorRange,
+    },
+    InsertAndDelete {
+        inserted_text: String,
+        deleted_text: String,
+
+        /// Where `inserted_text` ended up. `deleted_text` started at the same place.
+        inserted_range: Range<usize>,
+
+        selection_before: CCursorRange,
+    },
+
+    /// The same kind of edit made at several selections at once, which is undone and redone in
+    /// one go.
+    Multi {
+        /// The edits in the order they were made, from the first selection to the last. Each one
+        /// is positioned as of after the ones before it.
+        actions: Vec<EditAction>,
+
+        selections_before: Selections,
+        selections_after: Selections,
+    },
+
+    /// Edits made one after the other as part of one change, like a Vim command and the text
+    /// typed after it, which are undone and redone in one go.
+    Sequence {
+        /// The edits in the order they were made, each one as of after the ones before it.
+        actions: Vec<EditAction>,
+
+        selections_before: Selections,
+        selections_after: Selections,
+    },
+}
+
+impl EditAction {
+    /// An action deleting `range` from `text`.
+    pub fn delete(text: &Buffer, selection_before: CCursorRange, range: Range<usize>) -> Self {
+        EditAction::Delete {
+            text: text.slice(range.clone()),
+            range,
+            selection_before,
+        }
+    }
+
+    /// An action replacing the selected text with `inserted_text`.
+    pub fn replace_selection(
+        text: &Buffer,
+        selection_before: CCursorRange,
+        inserted_text: &str,
+    ) -> Self {
+        let [min, _] = selection_before.sorted();
+        let start = min.index;
+
+        EditAction::InsertAndDelete {
+            inserted_text: inserted_text.to_owned(),
+            deleted_text: text.selected_text(&selection_before),
+            inserted_range: start..start + inserted_text.chars().count(),
+            selection_before,
+        }
+    }
+
+    /// The action typing `inserted_text` over `selection`, if it types anything. New lines aren't
+    /// typed as text, but with Enter, which indents them.
+    pub fn typing(text: &Buffer, selection: CCursorRange, inserted_text: &str) -> Option<Self> {
+        (!inserted_text.is_empty() && inserted_text != "\n" && inserted_text != "\r")
+            .then(|| EditAction::replace_selection(text, selection, inserted_text))
+    }
+
+    /// The action deleting the selected text, if any is selected.
+    pub fn delete_selection(text: &Buffer, selection: CCursorRange) -> Option<Self> {
+        let [min, max] = selection.sorted();
+        (min.index != max.index).then(|| EditAction::delete(text, selection, min.index..max.index))
+    }
+
+    /// The action pressing backspace with `modifiers` makes at `selection`: deleting the
+    /// selection, or else back to the start of the line, the previous word, or a level of
+    /// `indentation`, or the previous character.
+    pub fn backspace(
+        text: &Buffer,
+        selection: CCursorRange,
+        modifiers: Modifiers,
+        indentation: Indentation,
+    ) -> Option<Self> {
+        let [min, max] = selection.sorted();
+        let min = if modifiers.mac_cmd || (modifiers.command && modifiers.shift) {
+            let line_start = text.line_start(min);
+            if line_start.index == max.index {
+                max - 1
+            } else {
+                line_start
+            }
+        } else if min.index != max.index {
+            min
+        } else if modifiers.alt || modifiers.ctrl {
+            text.previous_word(max)
+        } else {
+            // A whole level of indentation at once if it's spaces.
+            indentation.backspace_from(text, max).unwrap_or(max - 1)
+        };
+
+        (min.index != max.index).then(|| EditAction::delete(text, selection, min.index..max.index))
+    }
+
+    /// The action pressing delete with `modifiers` makes at `selection`: deleting the selection,
+    /// or else up to the end of the line, the next word, or the next character.
+    pub fn delete_forward(
+        text: &Buffer,
+        selection: CCursorRange,
+        modifiers: Modifiers,
+    ) -> Option<Self> {
+        let [min, max] = selection.sorted();
+        let max = if modifiers.mac_cmd || (modifiers.command && modifiers.shift) {
+            let line_end = text.line_end(max);
+            if line_end.index == min.index {
+                text.clamp(min + 1)
+            } else {
+                line_end
+            }
+        } else if min.index != max.index {
+            max
+        } else if modifiers.alt || modifiers.ctrl {
+            text.next_word(min)
+        } else {
+            text.clamp(min + 1)
+        };
+
+        (min.index != max.index).then(|| EditAction::delete(text, selection, min.index..max.index))
+    }
+
+    /// Makes an edit at every selection, from first to last, and returns it as a single action
+    /// along with the selections after it.
+    ///
+    /// `edit` gets each selection as of after the edits made before it, and returns the action to
+    /// make there, or `None` to leave that selection as it is. Returns `None` if no edit was made
+    /// at all.
+    pub fn at_each_selection(
+        text: &mut Buffer,
+        selections: &Selections,
+        mut edit: impl FnMut(&Buffer, CCursorRange) -> Option<EditAction>,
+    ) -> Option<(EditAction, Selections)> {
+        let mut actions = Vec::new();
+        let mut ranges = Vec::with_capacity(selections.len());
+
+        // How many characters the edits so far have added, which moves every selection after them.
+        let mut offset = 0;
+
+        for range in selections.ranges() {
+            let range = CCursorRange {
+                primary: text.clamp(shift(range.primary, offset)),
+                secondary: text.clamp(shift(range.secondary, offset)),
+            };
+
+            match edit(text, range) {
+                Some(action) => {
+                    ranges.push(action.apply(text).main());
+                    offset += action.len_change();
+                    actions.push(action);
+                }
+                None => ranges.push(range),
+            }
+        }
+
+        if actions.is_empty() {
+            return None;
+        }
+
+        let selections_after = Selections::new(ranges, selections.main_index());
+        let action = if selections.len() == 1 {
+            actions.remove(0)
+        } else {
+            EditAction::Multi {
+                actions,
+                selections_before: selections.clone(),
+                selections_after: selections_after.clone(),
+            }
+        };
+
+        Some((action, selections_after))
+    }
+
+    /// The edit, but leaving `selections_after` instead of cursors at the end of what it inserted.
+    pub fn leaving(self, selections_before: Selections, selections_after: Selections) -> Self {
+        let actions = match self {
+            EditAction::Multi { actions, .. } => actions,
+            action => vec![action],
+        };
+
+        EditAction::Multi {
+            actions,
+            selections_before,
+            selections_after,
+        }
+    }
+
+    /// Makes the edit, returning the selections after it.
+    pub fn apply(&self, text: &mut Buffer) -> Selections {
+        match self {
+            EditAction::Delete { range, .. } => {
+                text.delete_char_range(range.clone());
+
+                Selections::one(CCursorRange::one(CCursor::new(range.start)))
+            }
+
+            EditAction::InsertAndDelete {
+                inserted_text,
+                deleted_text,
+                inserted_range,
+                ..
+            } => {
+                let start = inserted_range.start;
+                text.delete_char_range(start..start + deleted_text.chars().count());
+
+                let mut ccursor = CCursor::new(start);
+                text.insert_text_at(&mut ccursor, inserted_text);
+
+                Selections::one(CCursorRange::one(ccursor))
+            }
+
+            EditAction::Multi {
+                actions,
+                selections_after,
+                ..
+            }
+            | EditAction::Sequence {
+                actions,
+                selections_after,
+                ..
+            } => {
+                for action in actions {
+                    action.apply(text);
+                }
+
+                selections_after.clone()
+            }
+        }
+    }
+
+    /// Reverts the edit, returning the selections from before it.
+    pub fn revert(&self, text: &mut Buffer) -> Selections {
+        match self {
+            EditAction::Delete {
+                text: deleted_text,
+                range,
+                selection_before,
+            } => {
+                text.insert_text_at(&mut CCursor::new(range.start), deleted_text);
+
+                Selections::one(*selection_before)
+            }
+
+            EditAction::InsertAndDelete {
+                deleted_text,
+                inserted_range,
+                selection_before,
+                ..
+            } => {
+                // Delete the inserted text.
+                text.delete_char_range(inserted_range.clone());
+
+                // Insert deleted text back.
+                text.insert_text_at(&mut CCursor::new(inserted_range.start), deleted_text);
+
+                Selections::one(*selection_before)
+            }
+
+            EditAction::Multi {
+                actions,
+                selections_before,
+                ..
+            }
+            | EditAction::Sequence {
+                actions,
+                selections_before,
+                ..
+            } => {
+                for action in actions.iter().rev() {
+                    action.revert(text);
+                }
+
+                selections_before.clone()
+            }
+        }
+    }
+
+    /// How many characters the edit adds to the text. Negative if it removes more than it adds.
+    fn len_change(&self) -> isize {
+        match self {
+            EditAction::Delete { text, .. } => -(text.chars().count() as isize),
+
+            EditAction::InsertAndDelete {
+                inserted_text,
+                deleted_text,
+                ..
+            } => inserted_text.chars().count() as isize - deleted_text.chars().count() as isize,
+
+            EditAction::Multi { actions, .. } | EditAction::Sequence { actions, .. } => {
+                actions.iter().map(EditAction::len_change).sum()
+            }
+        }
+    }
+
+    /// Moves the edit `offset` characters, to account for an edit made before it.
+    fn shift(&mut self, offset: isize) {
+        match self {
+            EditAction::Delete { range, .. } => {
+                *range = range.start.saturating_add_signed(offset)
+                    ..range.end.saturating_add_signed(offset);
+            }
+
+            EditAction::InsertAndDelete { inserted_range, .. } => {
+                *inserted_range = inserted_range.start.saturating_add_signed(offset)
+                    ..inserted_range.end.saturating_add_signed(offset);
+            }
+
+            EditAction::Multi { actions, .. } | EditAction::Sequence { actions, .. } => {
+                for action in actions {
+                    action.shift(offset);
+                }
+            }
+        }
+    }
+
+    /// A short description of the edit, for the history panel.
+    pub fn describe(&self) -> String {
+        match self {
+            EditAction::Delete { text, .. } => format!("Delete {}", preview(text)),
+
+            EditAction::InsertAndDelete {
+                inserted_text,
+                deleted_text,
+                ..
+            } => {
+                if deleted_text.is_empty() {
+                    format!("Insert {}", preview(inserted_text))
+                } else {
+                    format!(
+                        "Replace {} with {}",
+                        preview(deleted_text),
+                        preview(inserted_text)
+                    )
+                }
+            }
+
+            EditAction::Multi { actions, .. } if actions.len() == 1 => actions[0].describe(),
+            EditAction::Multi { actions, .. } => {
+                format!("{} ×{}", actions[0].describe(), actions.len())
+            }
+
+            EditAction::Sequence { actions, .. } => match actions.as_slice() {
+                [] => String::new(),
+                [action] => action.describe(),
+                [first, last] => format!("{}, {}", first.describe(), last.describe()),
+                [first, .., last] => format!("{}, …, {}", first.describe(), last.describe()),
+            },
+        }
+    }
+
+    /// The selections from before the edit was made.
+    fn selections_before(&self) -> Selections {
+        match self {
+            EditAction::Delete {
+                selection_before, ..
+            }
+            | EditAction::InsertAndDelete {
+                selection_before, ..
+            } => Selections::one(*selection_before),
+
+            EditAction::Multi {
+                selections_before, ..
+            }
+            | EditAction::Sequence {
+                selections_before, ..
+            } => selections_before.clone(),
+        }
+    }
+
+    /// The selections from after the edit was made, the same as `apply` returns.
+    fn selections_after(&self) -> Selections {
+        match self {
+            EditAction::Delete { range, .. } => {
+                Selections::one(CCursorRange::one(CCursor::new(range.start)))
+            }
+
+            EditAction::InsertAndDelete { inserted_range, .. } => {
+                Selections::one(CCursorRange::one(CCursor::new(inserted_range.end)))
+            }
+
+            EditAction::Multi {
+                selections_after, ..
+            }
+            | EditAction::Sequence {
+                selections_after, ..
+            } => selections_after.clone(),
+        }
+    }
+
+    /// Adds `next`, which was made right after this edit, to it, so they're undone in one go.
+    fn then(self, next: EditAction) -> EditAction {
+        let selections_before = self.selections_before();
+        let selections_after = next.selections_after();
+
+        let mut actions = match self {
+            EditAction::Sequence { actions, .. } => actions,
+            action => vec![action],
+        };
+
+        // Keep typing in one piece, so the change is described the way it was made.
+        if !actions.last_mut().is_some_and(|last| last.merge(&next)) {
+            actions.push(next);
+        }
+
+        EditAction::Sequence {
+            actions,
+            selections_before,
+            selections_after,
+        }
+    }
+
+    /// Merges `next` into this action if it continues it, i.e. types right after the text this
+    /// action inserted, or deletes right before the text this action deleted, without crossing
+    /// into a new word.
+    fn merge(&mut self, next: &EditAction) -> bool {
+        match (self, next) {
+            (
+                EditAction::InsertAndDelete {
+                    inserted_text,
+                    inserted_range,
+                    ..
+                },
+                EditAction::InsertAndDelete {
+                    inserted_text: next_inserted_text,
+                    deleted_text: next_deleted_text,
+                    inserted_range: next_inserted_range,
+                    ..
+                },
+            ) if next_deleted_text.is_empty()
+                && next_inserted_range.start == inserted_range.end
+                && !starts_word(inserted_text, next_inserted_text) =>
+            {
+                inserted_text.push_str(next_inserted_text);
+                inserted_range.end = next_inserted_range.end;
+                true
+            }
+
+            (
+                EditAction::Delete { text, range, .. },
+                EditAction::Delete {
+                    text: next_text,
+                    range: next_range,
+                    ..
+                },
+            ) if next_range.end == range.start && !starts_word(next_text, text) => {
+                text.insert_str(0, next_text);
+                range.start = next_range.start;
+                true
+            }
+
+            // Deleting forward leaves the cursor where it is.
+            (
+                EditAction::Delete { text, range, .. },
+                EditAction::Delete {
+                    text: next_text,
+                    range: next_range,
+                    ..
+                },
+            ) if next_range.start == range.start && !starts_word(text, next_text) => {
+                text.push_str(next_text);
+                range.end += next_range.len();
+                true
+            }
+
+            // Every selection has to continue its own edit. The edits at the selections before
+            // each one move it, so take that into account before comparing positions.
+            (
+                EditAction::Multi {
+                    actions,
+                    selections_after,
+                    ..
+                },
+                EditAction::Multi {
+                    actions: next_actions,
+                    selections_after: next_selections_after,
+                    ..
+                },
+            ) if actions.len() == next_actions.len() => {
+                let mut merged = Vec::with_capacity(actions.len());
+                let mut offset = 0;
+
+                for (action, next) in actions.iter().zip(next_actions) {
+                    let mut action = action.clone();
+                    action.shift(offset);
+                    if !action.merge(next) {
+                        return false;
+                    }
+
+                    offset += next.len_change();
+                    merged.push(action);
+                }
+
+                *actions = merged;
+                *selections_after = next_selections_after.clone();
+                true
+            }
+
+            _ => false,
+        }
+    }
+}
+
+/// `ccursor` moved `offset` characters.
+fn shift(ccursor: CCursor, offset: isize) -> CCursor {
+    CCursor {
+        index: ccursor.index.saturating_add_signed(offset),
+        prefer_next_row: ccursor.prefer_next_row,
+    }
+}
+
+/// Longest preview of edited text shown in the history panel, in characters.
+const MAX_PREVIEW_LEN: usize = 24;
+
+/// `text` quoted on a single line, shortened if it's long.
+fn preview(text: &str) -> String {
+    let mut preview = String::from("\"");
+    for (i, c) in text.chars().enumerate() {
+        if i == MAX_PREVIEW_LEN {
+            preview.push('…');
+            break;
+        }
+        match c {
+            '\n' => preview.push_str("\\n"),
+            '\t' => preview.push_str("\\t"),
+            c => preview.push(c),
+        }
+    }
+    preview.push('"');
+    preview
+}
+
+/// Whether `right` starts a new word when it directly follows `left`.
+fn starts_word(left: &str, right: &str) -> bool {
+    match (left.chars().last(), right.chars().next()) {
+        (Some(left), Some(right)) => !is_word_char(left) && is_word_char(right),
+        _ => false,
+    }
+}
+
+/// Kinds of edits that are grouped together into a single undo step.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EditKind {
+    Typing,
+    Backspace,
+    Delete,
+}
+
+impl EditKind {
+    /// The kind, if a key pressed with `modifiers` at `selections` is grouped with the edits
+    /// before it: only plain keys at cursors are.
+    pub fn when_plain(self, modifiers: Modifiers, selections: &Selections) -> Option<Self> {
+        let at_cursors = selections
+            .ranges()
+            .iter()
+            .all(|range| range.primary == range.secondary);
+        (modifiers.is_none() && at_cursors).then_some(self)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Group {
+    kind: EditKind,
+
+    /// When the last edit in the group was made.
+    time: f64,
+}
+
+/// A state of the buffer in the undo tree.
+#[derive(serde::Deserialize, serde::Serialize)]
+struct Node {
+    /// The edit that leads to this node from its parent. `None` for the root, which is the text as
+    /// it was before any edits were made.
+    action: Option<EditAction>,
+
+    parent: usize,
+
+    /// The child that redo goes to, i.e. the one that was most recently made or undone.
+    redo_child: Option<usize>,
+
+    /// When the edit was made, in seconds since the Unix epoch, so it still makes sense after the
+    /// history has been saved and restored.
+    time: f64,
+}
+
+/// The index of the root node, which every history starts out with.
+const ROOT: usize = 0;
+
+/// A node of the undo tree, as listed in the history panel.
+pub struct NodeInfo {
+    pub id: usize,
+
+    /// How many branches deep the node is, for indenting it.
+    pub depth: usize,
+
+    pub label: String,
+
+    /// When the edit was made. `None` for the original text.
+    pub time: Option<f64>,
+    pub is_current: bool,
+}
+
+/// The undo tree of a buffer.
+///
+/// Undoing some edits and then making a new one starts a new branch instead of throwing the
+/// undone edits away, so every state the buffer has ever been in can be returned to.
+///
+/// The history is saved along with the hash of the file contents, so it can be restored when the
+/// same file is opened again later.
+#[derive(serde::Deserialize, serde::Serialize)]
+pub struct History {
+    /// Every node ever created. Children always come after their parents.
+    nodes: Vec<Node>,
+
+    /// The node the buffer is currently at.
+    current: usize,
+
+    /// The node the file on disk is at, as of when it was last opened or saved.
+    saved: usize,
+
+    /// Hash of the text at the `saved` node.
+    saved_hash: u64,
+
+    /// The group the current node belongs to, if it can still be extended.
+    #[serde(skip)]
+    group: Option<Group>,
+
+    /// Whether a change is being made, see `start_change`.
+    #[serde(skip)]
+    changing: bool,
+
+    /// The node the edits of the change being made go into, once it's made one.
+    #[serde(skip)]
+    change_node: Option<usize>,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self {
+            nodes: vec![Node {
+                action: None,
+                parent: ROOT,
+                redo_child: None,
+                time: 0.0,
+            }],
+            current: ROOT,
+            saved: ROOT,
+            saved_hash: 0,
+            group: None,
+            changing: false,
+            change_node: None,
+        }
+    }
+}
+
+impl History {
+    /// An empty history for `text`, which was just read from disk.
+    pub fn new(text: &Buffer) -> Self {
+        let mut history = Self::default();
+        history.mark_saved(text);
+        history
+    }
+
+    /// Records that the file on disk now has the same contents as `text`.
+    pub fn mark_saved(&mut self, text: &Buffer) {
+        self.saved = self.current;
+        self.saved_hash = text.content_hash();
+    }
+
+    /// Picks up a history from an earlier session, if the file it was saved for still has the
+    /// same contents as `text`, which was just read from disk.
+    ///
+    /// The buffer is put at the node it was last saved at, so any edits that were made after
+    /// that and never saved can still be redone.
+    pub fn restore(mut self, text: &Buffer) -> Option<Self> {
+        if self.saved >= self.nodes.len() || self.saved_hash != text.content_hash() {
+            return None;
+        }
+
+        self.current = self.saved;
+        self.group = None;
+        Some(self)
+    }
+
+    /// Records an edit that was just made, as a new child of the current node, or as part of the
+    /// current node if it's the one the change being made went into.
+    pub fn push(&mut self, action: EditAction, time: f64) {
+        self.group = None;
+
+        if self.changing && self.change_node == Some(self.current) {
+            let current = &mut self.nodes[self.current];
+            current.action = current.action.take().map(|last| last.then(action));
+            current.time = time;
+            return;
+        }
+        if self.changing {
+            self.change_node = Some(self.nodes.len());
+        }
+
+        let id = self.nodes.len();
+        self.nodes.push(Node {
+            action: Some(action),
+            parent: self.current,
+            redo_child: None,
+            time,
+        });
+        self.nodes[self.current].redo_child = Some(id);
+        self.current = id;
+    }
+
+    /// Records an edit that was just made, merging it into the current node if it continues the
+    /// same kind of edit without a pause.
+    pub fn push_grouped(&mut self, action: EditAction, kind: EditKind, time: f64) {
+        // A change is one undo step, whatever it's made of.
+        if self.changing {
+            self.push(action, time);
+            return;
+        }
+
+        let continues_group = self
+            .group
+            .is_some_and(|group| group.kind == kind && time - group.time < GROUP_TIMEOUT);
+
+        // The group is sealed whenever we move around the tree, so the current node is the one
+        // the group was started in, and doesn't have any children yet.
+        let current = &mut self.nodes[self.current];
+        let merged = continues_group
+            && current
+                .action
+                .as_mut()
+                .is_some_and(|last| last.merge(&action));
+
+        if merged {
+            current.time = time;
+        } else {
+            self.push(action, time);
+        }
+
+        self.group = Some(Group { kind, time });
+    }
+
+    /// Makes an edit at each of `selections` in `text` and records it, grouped with the edit
+    /// before it if `kind` is given. Returns the selections after the edit.
+    ///
+    /// See [`EditAction::at_each_selection`] for what `edit` gets and returns.
+    pub fn edit(
+        &mut self,
+        text: &mut Buffer,
+        selections: &Selections,
+        kind: Option<EditKind>,
+        time: f64,
+        edit: impl FnMut(&Buffer, CCursorRange) -> Option<EditAction>,
+    ) -> Option<Selections> {
+        let (action, selections) = EditAction::at_each_selection(text, selections, edit)?;
+
+        match kind {
+            Some(kind) => self.push_grouped(action, kind, time),
+            None => self.push(action, time),
+        }
+
+        Some(selections)
+    }
+
+    /// Ends the current undo group, e.g. because the cursor was moved, so the next edit gets an
+    /// undo step of its own.
+    pub fn seal(&mut self) {
+        self.group = None;
+    }
+
+    /// Starts a change, like a Vim command, whose edits are all undone in one go until
+    /// `end_change`, however they're made and however long it takes.
+    pub fn start_change(&mut self) {
+        self.group = None;
+        self.changing = true;
+        self.change_node = None;
+    }
+
+    /// Ends the change started with `start_change`, so the next edit gets an undo step of its own.
+    pub fn end_change(&mut self) {
+        self.group = None;
+        self.changing = false;
+        self.change_node = None;
+    }
+
+    pub fn can_undo(&self) -> bool {
+        self.current != ROOT
+    }
+
+    pub fn can_redo(&self) -> bool {
+        self.nodes[self.current].redo_child.is_some()
+    }
+
+    /// Reverts the most recent edit, returning the selections from before it was made.
+    pub fn undo(&mut self, text: &mut Buffer) -> Option<Selections> {
+        if !self.can_undo() {
+            return None;
+        }
+        self.jump_to(self.nodes[self.current].parent, text)
+    }
+
+    /// Makes the most recently undone edit again, returning the selections from after it was made.
+    pub fn redo(&mut self, text: &mut Buffer) -> Option<Selections> {
+        let child = self.nodes[self.current].redo_child?;
+        self.jump_to(child, text)
+    }
+
+    /// Moves the buffer to the state at node `id`, reverting edits up to the closest common
+    /// ancestor and then making the edits down to `id`.
+    ///
+    /// Returns the selections from before the last reverted edit if `id` is an ancestor of the
+    /// current node, or from after the last edit made otherwise. Returns `None` if the buffer is
+    /// already at `id`.
+    pub fn jump_to(&mut self, id: usize, text: &mut Buffer) -> Option<Selections> {
+        if id == self.current || id >= self.nodes.len() {
+            return None;
+        }
+        self.seal();
+
+        let target_path = self.path_from_root(id);
+        let mut selection = None;
+
+        while !target_path.contains(&self.current) {
+            let node = &self.nodes[self.current];
+            let parent = node.parent;
+            selection = node.action.as_ref().map(|action| action.revert(text));
+
+            // Redo should come back the way we came.
+            self.nodes[parent].redo_child = Some(self.current);
+            self.current = parent;
+        }
+
+        let ancestor = target_path
+            .iter()
+            .position(|&node| node == self.current)
+            .expect("the root is on every path");
+        for &child in &target_path[ancestor + 1..] {
+            self.nodes[self.current].redo_child = Some(child);
+            selection = self.nodes[child]
+                .action
+                .as_ref()
+                .map(|action| action.apply(text));
+            self.current = child;
+        }
+
+        selection
+    }
+
+    /// The nodes from the root down to `id`, both included.
+    fn path_from_root(&self, mut id: usize) -> Vec<usize> {
+        let mut path = vec![id];
+        while id != ROOT {
+            id = self.nodes[id].parent;
+            path.push(id);
+        }
+        path.reverse();
+        path
+    }
+
+    /// Every node of the tree, depth first, with the children of each node in the order they
+    /// were made.
+    ///
+    /// Nodes are only indented when their parent has more than one child, so a history without
+    /// any branches is a flat list.
+    pub fn tree(&self) -> Vec<NodeInfo> {
+        let mut children = vec![Vec::new(); self.nodes.len()];
+        for (id, node) in self.nodes.iter().enumerate().skip(1) {
+            children[node.parent].push(id);
+        }
+
+        let mut tree = Vec::with_capacity(self.nodes.len());
+        let mut stack = vec![(ROOT, 0)];
+        while let Some((id, depth)) = stack.pop() {
+            let node = &self.nodes[id];
+            tree.push(NodeInfo {
+                id,
+                depth,
+                label: node
+                    .action
+                    .as_ref()
+                    .map_or_else(|| "Original".to_owned(), EditAction::describe),
+                time: node.action.as_ref().map(|_| node.time),
+                is_current: id == self.current,
+            });
+
+            let child_depth = depth + usize::from(children[id].len() > 1);
+            stack.extend(children[id].iter().rev().map(|&child| (child, child_depth)));
+        }
+
+        tree
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Makes an edit at `selection` the same way the editor does, if `edit` makes one.
+    fn edit(
+        buffer: &mut Buffer,
+        history: &mut History,
+        selection: &mut CCursorRange,
+        kind: Option<EditKind>,
+        time: f64,
+        edit: impl FnMut(&Buffer, CCursorRange) -> Option<EditAction>,
+    ) {
+        let selections = Selections::one(*selection);
+        if let Some(after) = history.edit(buffer, &selections, kind, time, edit) {
+            *selection = after.main();
+        }
+    }
+
+    /// Types `text` the same way the editor handles `Event::Text`.
+    fn type_text(
+        buffer: &mut Buffer,
+        history: &mut History,
+        selection: &mut CCursorRange,
+        text: &str,
+        time: f64,
+    ) {
+        edit(
+            buffer,
+            history,
+            selection,
+            Some(EditKind::Typing),
+            time,
+            |buffer, range| EditAction::typing(buffer, range, text),
+        );
+    }
+
+    /// Types each character of `text` as a separate event.
+    fn type_chars(
+        buffer: &mut Buffer,
+        history: &mut History,
+        selection: &mut CCursorRange,
+        text: &str,
+        time: f64,
+    ) {
+        for c in text.chars() {
+            type_text(buffer, history, selection, &c.to_string(), time);
+        }
+    }
+
+    /// Presses backspace with `modifiers` the same way the editor handles `Key::Backspace`.
+    fn backspace_with(
+        buffer: &mut Buffer,
+        history: &mut History,
+        selection: &mut CCursorRange,
+        modifiers: Modifiers,
+        time: f64,
+    ) {
+        let kind = EditKind::Backspace.when_plain(modifiers, &Selections::one(*selection));
+        edit(buffer, history, selection, kind, time, |buffer, range| {
+            EditAction::backspace(buffer, range, modifiers, Indentation::default())
+        });
+    }
+
+    /// Presses backspace without modifiers.
+    fn backspace(
+        buffer: &mut Buffer,
+        history: &mut History,
+        selection: &mut CCursorRange,
+        time: f64,
+    ) {
+        backspace_with(buffer, history, selection, Modifiers::NONE, time);
+    }
+
+    /// Presses delete with `modifiers` the same way the editor handles `Key::Delete`.
+    fn delete_with(
+        buffer: &mut Buffer,
+        history: &mut History,
+        selection: &mut CCursorRange,
+        modifiers: Modifiers,
+    ) {
+        let kind = EditKind::Delete.when_plain(modifiers, &Selections::one(*selection));
+        edit(buffer, history, selection, kind, 0.0, |buffer, range| {
+            EditAction::delete_forward(buffer, range, modifiers)
+        });
+    }
+
+    /// Presses delete without modifiers.
+    fn delete(buffer: &mut Buffer, history: &mut History, selection: &mut CCursorRange) {
+        delete_with(buffer, history, selection, Modifiers::NONE);
+    }
+
+    /// How many times undo can be used before reaching the original text.
+    fn undo_steps(history: &History) -> usize {
+        history.path_from_root(history.current).len() - 1
+    }
+
+    fn undo(buffer: &mut Buffer, history: &mut History, selection: &mut CCursorRange) {
+        *selection = history.undo(buffer).expect("nothing to undo").main();
+    }
+
+    #[test]
+    fn typing_a_word_is_one_undo_step() {
+        let mut buffer = Buffer::default();
+        let mut history = History::default();
+        let mut selection = CCursorRange::default();
+
+        type_chars(&mut buffer, &mut history, &mut selection, "hello", 0.0);
+        assert_eq!(undo_steps(&history), 1);
+
+        undo(&mut buffer, &mut history, &mut selection);
+        assert_eq!(buffer.to_string(), "");
+        assert!(!history.can_undo());
+    }
+
+    #[test]
+    fn typing_splits_at_word_boundaries() {
+        let mut buffer = Buffer::default();
+        let mut history = History::default();
+        let mut selection = CCursorRange::default();
+
+        type_chars(
+            &mut buffer,
+            &mut history,
+            &mut selection,
+            "hello world",
+            0.0,
+        );
+        assert_eq!(undo_steps(&history), 2);
+
+        undo(&mut buffer, &mut history, &mut selection);
+        assert_eq!(buffer.to_string(), "hello ");
+        undo(&mut buffer, &mut history, &mut selection);
+        assert_eq!(buffer.to_string(), "");
+    }
+
+    #[test]
+    fn typing_splits_after_a_pause() {
+        let mut buffer = Buffer::default();
+        let mut history = History::default();
+        let mut selection = CCursorRange::default();
+
+        type_chars(&mut buffer, &mut history, &mut selection, "hel", 0.0);
+        type_chars(&mut buffer, &mut history, &mut selection, "lo", 5.0);
+        assert_eq!(undo_steps(&history), 2);
+
+        undo(&mut buffer, &mut history, &mut selection);
+        assert_eq!(buffer.to_string(), "hel");
+    }
+
+    #[test]
+    fn typing_splits_when_the_cursor_moves() {
+        let mut buffer = Buffer::default();
+        let mut history = History::default();
+        let mut selection = CCursorRange::default();
+
+        type_chars(&mut buffer, &mut history, &mut selection, "helo", 0.0);
+
+        // Move the cursor back and insert the missing letter.
+        history.seal();
+        selection = CCursorRange::one(CCursor::new(3));
+        type_chars(&mut buffer, &mut history, &mut selection, "l", 0.0);
+        assert_eq!(buffer.to_string(), "hello");
+        assert_eq!(undo_steps(&history), 2);
+
+        // Typing somewhere that doesn't continue the group doesn't merge either.
+        selection = CCursorRange::one(CCursor::new(0));
+        type_chars(&mut buffer, &mut history, &mut selection, "o", 0.0);
+        assert_eq!(undo_steps(&history), 3);
+
+        undo(&mut buffer, &mut history, &mut selection);
+        assert_eq!(buffer.to_string(), "hello");
+        undo(&mut buffer, &mut history, &mut selection);
+        assert_eq!(buffer.to_string(), "helo");
+        assert_eq!(selection.primary.index, 3);
+    }
+
+    #[test]
+    fn typing_over_a_selection_starts_a_new_step() {
+        let mut buffer = Buffer::default();
+        let mut history = History::default();
+        let mut selection = CCursorRange::default();
+
+        type_chars(&mut buffer, &mut history, &mut selection, "hello", 0.0);
+        selection = CCursorRange::two(CCursor::new(0), CCursor::new(5));
+        type_chars(&mut buffer, &mut history, &mut selection, "bye", 0.0);
+        assert_eq!(undo_steps(&history), 2);
+
+        undo(&mut buffer, &mut history, &mut selection);
+        assert_eq!(buffer.to_string(), "hello");
+        assert_eq!(selection.sorted().map(|c| c.index), [0, 5]);
+    }
+
+    #[test]
+    fn backspaces_are_one_undo_step() {
+        let mut buffer = Buffer::new("hello world");
+        let mut history = History::default();
+        let mut selection = CCursorRange::one(CCursor::new(11));
+
+        for _ in 0..5 {
+            backspace(&mut buffer, &mut history, &mut selection, 0.0);
+        }
+        assert_eq!(buffer.to_string(), "hello ");
+        assert_eq!(undo_steps(&history), 1);
+
+        // Deleting the space crosses into the previous word.
+        backspace(&mut buffer, &mut history, &mut selection, 0.0);
+        backspace(&mut buffer, &mut history, &mut selection, 0.0);
+        assert_eq!(buffer.to_string(), "hell");
+        assert_eq!(undo_steps(&history), 2);
+
+        undo(&mut buffer, &mut history, &mut selection);
+        assert_eq!(buffer.to_string(), "hello ");
+        undo(&mut buffer, &mut history, &mut selection);
+        assert_eq!(buffer.to_string(), "hello world");
+        assert_eq!(selection.primary.index, 11);
+    }
+
+    #[test]
+    fn deleting_nothing_isnt_an_undo_step() {
+        let mut buffer = Buffer::new("hello");
+        let mut history = History::default();
+
+        // The previous word from the start is the start, but on the next row.
+        let mut selection = CCursorRange::one(CCursor::new(0));
+        backspace_with(
+            &mut buffer,
+            &mut history,
+            &mut selection,
+            Modifiers::CTRL,
+            0.0,
+        );
+        backspace(&mut buffer, &mut history, &mut selection, 0.0);
+
+        // The same at the end, from a cursor a word motion left preferring the next row.
+        let mut selection = CCursorRange::one(CCursor {
+            index: 5,
+            prefer_next_row: true,
+        });
+        delete_with(&mut buffer, &mut history, &mut selection, Modifiers::CTRL);
+        delete(&mut buffer, &mut history, &mut selection);
+        assert_eq!(buffer.to_string(), "hello");
+        assert!(!history.can_undo());
+    }
+
+    #[test]
+    fn forward_deletes_are_one_undo_step() {
+        let mut buffer = Buffer::new("hello world");
+        let mut history = History::default();
+        let mut selection = CCursorRange::one(CCursor::new(0));
+
+        for _ in 0..6 {
+            delete(&mut buffer, &mut history, &mut selection);
+        }
+        assert_eq!(buffer.to_string(), "world");
+        assert_eq!(undo_steps(&history), 1);
+
+        // Deleting the next word is a new step, and backspacing is too.
+        delete(&mut buffer, &mut history, &mut selection);
+        assert_eq!(undo_steps(&history), 2);
+        selection = CCursorRange::one(CCursor::new(4));
+        backspace(&mut buffer, &mut history, &mut selection, 0.0);
+        assert_eq!(buffer.to_string(), "orl");
+        assert_eq!(undo_steps(&history), 3);
+
+        undo(&mut buffer, &mut history, &mut selection);
+        undo(&mut buffer, &mut history, &mut selection);
+        assert_eq!(buffer.to_string(), "world");
+        undo(&mut buffer, &mut history, &mut selection);
+        assert_eq!(buffer.to_string(), "hello world");
+        assert_eq!(selection.primary.index, 0);
+    }
+
+    #[test]
+    fn typing_and_backspace_are_separate_steps() {
+        let mut buffer = Buffer::default();
+        let mut history = History::default();
+        let mut selection = CCursorRange::default();
+
+        type_chars(&mut buffer, &mut history, &mut selection, "hello", 0.0);
+        backspace(&mut buffer, &mut history, &mut selection, 0.0);
+        type_chars(&mut buffer, &mut history, &mut selection, "p", 0.0);
+        assert_eq!(buffer.to_string(), "hellp");
+        assert_eq!(undo_steps(&history), 3);
+    }
+
+    #[test]
+    fn a_change_is_one_undo_step() {
+        let mut buffer = Buffer::new("one two");
+        let mut history = History::default();
+        let mut selection = CCursorRange::one(CCursor::new(0));
+
+        // Like Vim's `cw`, then typing a couple of words, correcting one, and after a pause, Enter.
+        history.start_change();
+        edit(
+            &mut buffer,
+            &mut history,
+            &mut selection,
+            None,
+            0.0,
+            |buffer, range| Some(EditAction::delete(buffer, range, 0..3)),
+        );
+        type_chars(&mut buffer, &mut history, &mut selection, "un dos", 0.0);
+        backspace(&mut buffer, &mut history, &mut selection, 0.5);
+        type_chars(&mut buffer, &mut history, &mut selection, "s", 0.5);
+        edit(
+            &mut buffer,
+            &mut history,
+            &mut selection,
+            None,
+            5.0,
+            |buffer, range| Some(EditAction::replace_selection(buffer, range, "\n")),
+        );
+        history.end_change();
+        assert_eq!(buffer.to_string(), "un dos\n two");
+        assert_eq!(undo_steps(&history), 1);
+
+        // Another change right after it gets a step of its own, even though it types too.
+        history.start_change();
+        type_chars(&mut buffer, &mut history, &mut selection, "tres", 5.5);
+        history.end_change();
+        assert_eq!(buffer.to_string(), "un dos\ntres two");
+        assert_eq!(undo_steps(&history), 2);
+
+        undo(&mut buffer, &mut history, &mut selection);
+        assert_eq!(buffer.to_string(), "un dos\n two");
+        undo(&mut buffer, &mut history, &mut selection);
+        assert_eq!(buffer.to_string(), "one two");
+        assert_eq!(selection, CCursorRange::one(CCursor::new(0)));
+
+        history.redo(&mut buffer);
+        assert_eq!(buffer.to_string(), "un dos\n two");
+    }
+
+    #[test]
+    fn redo_replays_the_whole_group() {
+        let mut buffer = Buffer::default();
+        let mut history = History::default();
+        let mut selection = CCursorRange::default();
+
+        type_chars(&mut buffer, &mut history, &mut selection, "hello", 0.0);
+        undo(&mut buffer, &mut history, &mut selection);
+
+        selection = history.redo(&mut buffer).expect("nothing to redo").main();
+        assert_eq!(buffer.to_string(), "hello");
+        assert_eq!(selection.primary.index, 5);
+
+        // Typing after an undo doesn't merge into the redone step, and clears the redo stack.
+        undo(&mut buffer, &mut history, &mut selection);
+        type_chars(&mut buffer, &mut history, &mut selection, "bye", 0.0);
+        assert!(!history.can_redo());
+        assert_eq!(undo_steps(&history), 1);
+    }
+
+    #[test]
+    fn undo_handles_multi_byte_characters() {
+        let mut buffer = Buffer::new("héllo wörld");
+        let mut history = History::default();
+        let mut selection = CCursorRange::one(CCursor::new(11));
+
+        type_chars(&mut buffer, &mut history, &mut selection, "!é🦀", 0.0);
+        backspace(&mut buffer, &mut history, &mut selection, 1.0);
+        selection = CCursorRange::two(CCursor::new(0), CCursor::new(2));
+        type_chars(&mut buffer, &mut history, &mut selection, "日本", 2.0);
+        assert_eq!(buffer.to_string(), "日本llo wörld!é");
+
+        undo(&mut buffer, &mut history, &mut selection);
+        assert_eq!(buffer.to_string(), "héllo wörld!é");
+        assert_eq!(selection.sorted().map(|c| c.index), [0, 2]);
+
+        undo(&mut buffer, &mut history, &mut selection);
+        assert_eq!(buffer.to_string(), "héllo wörld!é🦀");
+        assert_eq!(selection.primary.index, 14);
+
+        undo(&mut buffer, &mut history, &mut selection);
+        assert_eq!(buffer.to_string(), "héllo wörld");
+        assert_eq!(selection.primary.index, 11);
+    }
+
+    /// The id of the node whose label is `label`.
+    fn node(history: &History, label: &str) -> usize {
+        history
+            .tree()
+            .into_iter()
+            .find(|node| node.label == label)
+            .expect("no such node")
+            .id
+    }
+
+    #[test]
+    fn typing_after_undo_starts_a_new_branch() {
+        let mut buffer = Buffer::default();
+        let mut history = History::default();
+        let mut selection = CCursorRange::default();
+
+        type_chars(&mut buffer, &mut history, &mut selection, "hello", 0.0);
+        undo(&mut buffer, &mut history, &mut selection);
+        type_chars(&mut buffer, &mut history, &mut selection, "bye", 0.0);
+
+        let tree = history.tree();
+        let labels: Vec<_> = tree.iter().map(|node| node.label.as_str()).collect();
+        assert_eq!(labels, ["Original", "Insert \"hello\"", "Insert \"bye\""]);
+        assert_eq!(
+            tree.iter().map(|node| node.depth).collect::<Vec<_>>(),
+            [0, 1, 1]
+        );
+        assert!(tree[2].is_current);
+
+        // The undone edit is still there.
+        selection = history
+            .jump_to(node(&history, "Insert \"hello\""), &mut buffer)
+            .unwrap()
+            .main();
+        assert_eq!(buffer.to_string(), "hello");
+        assert_eq!(selection.primary.index, 5);
+    }
+
+    #[test]
+    fn redo_follows_the_most_recent_branch() {
+        let mut buffer = Buffer::default();
+        let mut history = History::default();
+        let mut selection = CCursorRange::default();
+
+        type_chars(&mut buffer, &mut history, &mut selection, "a", 0.0);
+        undo(&mut buffer, &mut history, &mut selection);
+        type_chars(&mut buffer, &mut history, &mut selection, "b", 0.0);
+        undo(&mut buffer, &mut history, &mut selection);
+
+        history.redo(&mut buffer);
+        assert_eq!(buffer.to_string(), "b");
+
+        // Undoing from the other branch makes redo go back there.
+        history.jump_to(node(&history, "Insert \"a\""), &mut buffer);
+        undo(&mut buffer, &mut history, &mut selection);
+        history.redo(&mut buffer);
+        assert_eq!(buffer.to_string(), "a");
+    }
+
+    #[test]
+    fn jumping_between_branches_restores_the_selection() {
+        let mut buffer = Buffer::new("hello world");
+        let mut history = History::default();
+        let mut selection = CCursorRange::two(CCursor::new(0), CCursor::new(5));
+
+        type_chars(&mut buffer, &mut history, &mut selection, "bye", 0.0);
+        type_chars(&mut buffer, &mut history, &mut selection, "!", 5.0);
+        undo(&mut buffer, &mut history, &mut selection);
+        undo(&mut buffer, &mut history, &mut selection);
+
+        selection = CCursorRange::one(CCursor::new(11));
+        backspace(&mut buffer, &mut history, &mut selection, 0.0);
+        assert_eq!(buffer.to_string(), "hello worl");
+
+        // Going to an ancestor restores the selection from before the edit after it.
+        selection = history.jump_to(ROOT, &mut buffer).unwrap().main();
+        assert_eq!(buffer.to_string(), "hello world");
+        assert_eq!(selection.primary.index, 11);
+
+        // Going down a branch gives the selection after its last edit.
+        selection = history
+            .jump_to(node(&history, "Insert \"!\""), &mut buffer)
+            .unwrap()
+            .main();
+        assert_eq!(buffer.to_string(), "bye! world");
+        assert_eq!(selection.primary.index, 4);
+
+        // Going across branches works the same way.
+        selection = history
+            .jump_to(node(&history, "Delete \"d\""), &mut buffer)
+            .unwrap()
+            .main();
+        assert_eq!(buffer.to_string(), "hello worl");
+        assert_eq!(selection.primary.index, 10);
+
+        assert!(history.jump_to(history.current, &mut buffer).is_none());
+    }
+
+    #[test]
+    fn restoring_goes_back_to_the_saved_node() {
+        let mut buffer = Buffer::new("hello");
+        let mut history = History::new(&buffer);
+        let mut selection = CCursorRange::one(CCursor::new(5));
+
+        type_chars(&mut buffer, &mut history, &mut selection, " world", 0.0);
+        history.mark_saved(&buffer);
+        type_chars(&mut buffer, &mut history, &mut selection, "!", 5.0);
+
+        // The file on disk changed since, so the history no longer applies.
+        assert!(History::new(&buffer).restore(&Buffer::new("bye")).is_none());
+
+        // Closing without saving leaves the file at the saved node, but the unsaved edit can
+        // still be redone.
+        let mut buffer = Buffer::new("hello world");
+        let mut history = history.restore(&buffer).expect("file didn't change");
+        assert_eq!(undo_steps(&history), 2);
+
+        history.redo(&mut buffer);
+        assert_eq!(buffer.to_string(), "hello world!");
+        history.jump_to(ROOT, &mut buffer);
+        assert_eq!(buffer.to_string(), "hello");
+    }
+
+    /// Types `text` at every selection.
+    fn type_at_each(
+        buffer: &mut Buffer,
+        history: &mut History,
+        selections: &mut Selections,
+        text: &str,
+        time: f64,
+    ) {
+        let (action, after) = EditAction::at_each_selection(buffer, selections, |buffer, range| {
+            Some(EditAction::replace_selection(buffer, range, text))
+        })
+        .unwrap();
+        *selections = after;
+        history.push_grouped(action, EditKind::Typing, time);
+    }
+
+    #[test]
+    fn editing_at_several_selections_is_one_undo_step() {
+        let mut buffer = Buffer::new("one\ntwo\nthree");
+        let mut history = History::default();
+        let mut selections = Selections::new(
+            vec![
+                CCursorRange::one(CCursor::new(0)),
+                CCursorRange::two(CCursor::new(4), CCursor::new(7)),
+                CCursorRange::one(CCursor::new(8)),
+            ],
+            2,
+        );
+
+        for c in ["a", "b", "c"] {
+            type_at_each(&mut buffer, &mut history, &mut selections, c, 0.0);
+        }
+        assert_eq!(buffer.to_string(), "abcone\nabc\nabcthree");
+        assert_eq!(undo_steps(&history), 1);
+
+        let cursors = |selections: &Selections| {
+            selections
+                .ranges()
+                .iter()
+                .map(|range| range.primary.index)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(cursors(&selections), [3, 10, 14]);
+
+        let before = history.undo(&mut buffer).unwrap();
+        assert_eq!(buffer.to_string(), "one\ntwo\nthree");
+        assert_eq!(cursors(&before), [0, 7, 8]);
+        assert_eq!(before.main_index(), 2);
+
+        let after = history.redo(&mut buffer).unwrap();
+        assert_eq!(buffer.to_string(), "abcone\nabc\nabcthree");
+        assert_eq!(cursors(&after), [3, 10, 14]);
+    }
+
+    #[test]
+    fn backspacing_at_several_cursors_is_one_undo_step() {
+        let mut buffer = Buffer::new("hello\nworld");
+        let mut history = History::default();
+        let mut selections = Selections::new(
+            vec![
+                CCursorRange::one(CCursor::new(5)),
+                CCursorRange::one(CCursor::new(11)),
+            ],
+            0,
+        );
+
+        for _ in 0..3 {
+            let (action, after) =
+                EditAction::at_each_selection(&mut buffer, &selections, |buffer, range| {
+                    let index = range.primary.index;
+                    Some(EditAction::delete(buffer, range, index - 1..index))
+                })
+                .unwrap();
+            selections = after;
+            history.push_grouped(action, EditKind::Backspace, 0.0);
+        }
+        assert_eq!(buffer.to_string(), "he\nwo");
+        assert_eq!(undo_steps(&history), 1);
+
+        history.undo(&mut buffer);
+        assert_eq!(buffer.to_string(), "hello\nworld");
+    }
+
+    #[derive(Clone, Debug)]
+    enum Op {
+        Type(char),
+        Paste(String),
+        Backspace,
+        DeleteWord,
+        Cut,
+        Select(usize, usize),
+        Undo,
+        Redo,
+    }
+
+    /// Edits, and moving the cursor around.
+    fn edit_op() -> impl proptest::strategy::Strategy<Value = Op> {
+        use proptest::prelude::*;
+
+        prop_oneof![
+            4 => any::<char>().prop_map(Op::Type),
+            1 => "(?s).{0,8}".prop_map(Op::Paste),
+            2 => Just(Op::Backspace),
+            1 => Just(Op::DeleteWord),
+            1 => Just(Op::Cut),
+            1 => (any::<usize>(), any::<usize>()).prop_map(|(a, b)| Op::Select(a, b)),
+        ]
+    }
+
+    /// Edits mixed with undos and redos, which make the history branch.
+    fn op() -> impl proptest::strategy::Strategy<Value = Op> {
+        use proptest::prelude::*;
+
+        prop_oneof![
+            8 => edit_op(),
+            1 => Just(Op::Undo),
+            1 => Just(Op::Redo),
+        ]
+    }
+
+    /// Applies `op` the same way the editor does.
+    fn apply_op(
+        buffer: &mut Buffer,
+        history: &mut History,
+        selection: &mut CCursorRange,
+        op: &Op,
+        time: f64,
+    ) {
+        match op {
+            Op::Type(c) => type_text(buffer, history, selection, &c.to_string(), time),
+            Op::Paste(text) => {
+                if !text.is_empty() {
+                    edit(buffer, history, selection, None, time, |buffer, range| {
+                        Some(EditAction::replace_selection(buffer, range, text))
+                    });
+                }
+            }
+            Op::Backspace => backspace(buffer, history, selection, time),
+            Op::DeleteWord => backspace_with(buffer, history, selection, Modifiers::ALT, time),
+            Op::Cut => edit(
+                buffer,
+                history,
+                selection,
+                None,
+                time,
+                EditAction::delete_selection,
+            ),
+            Op::Select(a, b) => {
+                let len = buffer.len_chars() + 1;
+                history.seal();
+                *selection = CCursorRange::two(CCursor::new(a % len), CCursor::new(b % len));
+            }
+            Op::Undo => {
+                if let Some(undone) = history.undo(buffer) {
+                    *selection = undone.main();
+                }
+            }
+            Op::Redo => {
+                if let Some(redone) = history.redo(buffer) {
+                    *selection = redone.main();
+                }
+            }
+        }
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn undoing_everything_restores_the_original(
+            original in "(?s).{0,32}",
+            start in proptest::prelude::any::<(usize, usize)>(),
+            ops in proptest::collection::vec((edit_op(), 0.0..2.0), 0..48),
+        ) {
+            let mut buffer = Buffer::new(&original);
+            let mut history = History::default();
+
+            let len = buffer.len_chars() + 1;
+            let mut selection =
+                CCursorRange::two(CCursor::new(start.0 % len), CCursor::new(start.1 % len));
+
+            // Selecting text isn't undoable, so compare against the selection at the first edit.
+            let mut selection_before_first_edit = None;
+            let mut time = 0.0;
+
+            for (op, delay) in &ops {
+                time += delay;
+
+                let selection_before = selection;
+                apply_op(&mut buffer, &mut history, &mut selection, op, time);
+
+                if selection_before_first_edit.is_none() && history.can_undo() {
+                    selection_before_first_edit = Some(selection_before);
+                }
+            }
+            let edited = buffer.to_string();
+
+            while history.can_undo() {
+                selection = history.undo(&mut buffer).unwrap().main();
+            }
+
+            proptest::prop_assert_eq!(buffer.to_string(), original);
+            if let Some(expected) = selection_before_first_edit {
+                proptest::prop_assert_eq!(selection.primary.index, expected.primary.index);
+                proptest::prop_assert_eq!(selection.secondary.index, expected.secondary.index);
+            }
+
+            while history.can_redo() {
+                history.redo(&mut buffer);
+            }
+
+            proptest::prop_assert_eq!(buffer.to_string(), edited);
+        }
+
+        #[test]
+        fn jumping_to_any_node_restores_its_text(
+            original in "(?s).{0,32}",
+            ops in proptest::collection::vec((op(), 0.0..2.0), 0..48),
+            jumps in proptest::collection::vec(proptest::prelude::any::<usize>(), 0..16),
+        ) {
+            let mut buffer = Buffer::new(&original);
+            let mut history = History::default();
+            let mut selection = CCursorRange::default();
+
+            // The text at every node, as of the last time we were there. Grouped edits change the
+            // text of the node they're merged into.
+            let mut texts = vec![original];
+            let mut time = 0.0;
+
+            for (op, delay) in &ops {
+                time += delay;
+                apply_op(&mut buffer, &mut history, &mut selection, op, time);
+
+                texts.resize(history.nodes.len(), String::new());
+                texts[history.current] = buffer.to_string();
+            }
+
+            for jump in jumps {
+                let id = jump % texts.len();
+                history.jump_to(id, &mut buffer);
+
+                proptest::prop_assert_eq!(history.current, id);
+                proptest::prop_assert_eq!(buffer.to_string(), texts[id].as_str());
+            }
+        }
+    }
+}
@@ -0,0 +1,416 @@
+use egui::text::{CCursor, CCursorRange};
+
+use crate::buffer::Buffer;
+use crate::language::{closing_bracket, Language};
+use crate::lines::selected_lines;
+use crate::selection::Selections;
+
+/// How a buffer is indented.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Indentation {
+    /// Whether to indent with tabs rather than spaces.
+    pub tabs: bool,
+
+    /// Columns per level of indentation, when indenting with spaces.
+    pub width: usize,
+
+    /// Columns a tab is shown as.
+    pub tab_width: usize,
+}
+
+impl Default for Indentation {
+    fn default() -> Self {
+        Self {
+            tabs: false,
+            width: 4,
+            tab_width: 4,
+        }
+    }
+}
+
+impl Indentation {
+    /// Guesses how `text` is indented: with tabs if more lines start with a tab than with a space,
+    /// and otherwise by the most common step from the indentation of a line to the next.
+    ///
+    /// The tab width is left at the default, since tabs look the same however wide they're shown.
+    pub fn detect(text: &str) -> Self {
+        let mut tab_lines = 0;
+        let mut space_lines = 0;
+
+        // How often the indentation grows by each number of spaces, from the spaces indenting the
+        // line before. Not from a line indented with tabs, which could be any number of columns.
+        let mut steps = [0usize; 9];
+        let mut previous = Some(0);
+
+        for line in text.lines().filter(|line| !line.trim().is_empty()) {
+            if line.starts_with('\t') {
+                tab_lines += 1;
+                previous = None;
+                continue;
+            }
+
+            let spaces = line.chars().take_while(|&c| c == ' ').count();
+            if spaces > 0 {
+                space_lines += 1;
+            }
+            if let Some(previous) = previous {
+                if spaces > previous && spaces - previous < steps.len() {
+                    steps[spaces - previous] += 1;
+                }
+            }
+            previous = Some(spaces);
+        }
+
+        let default = Self::default();
+        if tab_lines > space_lines {
+            return Self {
+                tabs: true,
+                ..default
+            };
+        }
+
+        // Steps of a single space are more likely to be alignment, like in block comments.
+        let width = (2..steps.len())
+            .filter(|&width| steps[width] > 0)
+            .max_by_key(|&width| steps[width])
+            .unwrap_or(default.width);
+        Self { width, ..default }
+    }
+
+    /// One level of indentation.
+    pub fn unit(self) -> String {
+        if self.tabs {
+            "\t".to_owned()
+        } else {
+            " ".repeat(self.width)
+        }
+    }
+
+    /// How many columns `text` takes up at the start of a line.
+    pub fn columns(self, text: &str) -> usize {
+        text.chars().fold(0, |column, c| {
+            if c == '\t' {
+                column + self.tab_width - column % self.tab_width
+            } else {
+                column + 1
+            }
+        })
+    }
+
+    /// What Tab inserts at `ccursor`: a tab, or spaces up to the next level of indentation.
+    pub fn tab_at(self, buffer: &Buffer, ccursor: CCursor) -> String {
+        if self.tabs {
+            return "\t".to_owned();
+        }
+
+        let line_start = buffer.line_start(ccursor).index;
+        let column = self.columns(&buffer.slice(line_start..ccursor.index));
+        " ".repeat(self.width - column % self.width)
+    }
+
+    /// Where Backspace at `ccursor` deletes back to if it's in the spaces indenting its line: the
+    /// previous level of indentation.
+    pub fn backspace_from(self, buffer: &Buffer, ccursor: CCursor) -> Option<CCursor> {
+        let line_start = buffer.line_start(ccursor).index;
+        let spaces = ccursor.index - line_start;
+        if self.tabs
+            || spaces == 0
+            || buffer
+                .slice(line_start..ccursor.index)
+                .chars()
+                .any(|c| c != ' ')
+        {
+            return None;
+        }
+
+        let level_start = (spaces - 1) / self.width * self.width;
+        Some(CCursor::new(line_start + level_start))
+    }
+}
+
+/// An edit to the whitespace at the start of a line.
+#[derive(Debug, PartialEq, Eq)]
+pub struct IndentEdit {
+    /// Where the line starts.
+    pub start: usize,
+
+    /// How many characters at the start are removed.
+    pub removed: usize,
+    pub inserted: String,
+}
+
+/// The edits indenting every line a selection is on by a level, or outdenting them if `outdent`.
+///
+/// Empty lines aren't indented, and a selection ending at the very start of a line doesn't count
+/// as being on it.
+pub fn indent_lines(
+    buffer: &Buffer,
+    selections: &Selections,
+    indentation: Indentation,
+    outdent: bool,
+) -> Vec<IndentEdit> {
+    let mut lines = Vec::new();
+    for &selection in selections.ranges() {
+        lines.extend(selected_lines(buffer, selection));
+    }
+    lines.sort_unstable();
+    lines.dedup();
+
+    lines
+        .into_iter()
+        .filter_map(|line| {
+            let range = buffer.line_char_range(line);
+            if outdent {
+                let text = buffer.slice(range.start..range.start + buffer.indent_len(line));
+                let mut removed = 0;
+                for c in text.chars() {
+                    if c == '\t' {
+                        removed += usize::from(removed == 0);
+                        break;
+                    }
+                    if removed == indentation.width {
+                        break;
+                    }
+                    removed += 1;
+                }
+
+                (removed > 0).then(|| IndentEdit {
+                    start: range.start,
+                    removed,
+                    inserted: String::new(),
+                })
+            } else {
+                (!range.is_empty()).then(|| IndentEdit {
+                    start: range.start,
+                    removed: 0,
+                    inserted: indentation.unit(),
+                })
+            }
+        })
+        .collect()
+}
+
+/// The edits indenting every line with tabs, or with spaces, keeping how many levels it's
+/// indented: a tab for every `indentation.width` spaces, or the other way around. Indentation
+/// that doesn't come to a whole number of levels is made up with spaces.
+pub fn convert_indentation(
+    buffer: &Buffer,
+    indentation: Indentation,
+    tabs: bool,
+) -> Vec<IndentEdit> {
+    let width = indentation.width.max(1);
+
+    // A tab is a level of indentation, however wide it's shown.
+    let levels = Indentation {
+        tab_width: width,
+        ..indentation
+    };
+
+    (0..buffer.len_lines())
+        .filter_map(|line| {
+            let start = buffer.line_to_char(line);
+            let removed = buffer.indent_len(line);
+            let current = buffer.slice(start..start + removed);
+
+            let columns = levels.columns(&current);
+            let inserted = if tabs {
+                "\t".repeat(columns / width) + &" ".repeat(columns % width)
+            } else {
+                " ".repeat(columns)
+            };
+
+            (inserted != current).then_some(IndentEdit {
+                start,
+                removed,
+                inserted,
+            })
+        })
+        .collect()
+}
+
+/// Where the character at `index` ends up after `edits`.
+pub fn shift_index(index: usize, edits: &[IndentEdit]) -> usize {
+    edits
+        .iter()
+        .filter(|edit| edit.start <= index)
+        .fold(index, |shifted, edit| {
+            shifted + edit.inserted.chars().count() - (index - edit.start).min(edit.removed)
+        })
+}
+
+/// The text pressing Enter replaces `selection` with, and how many characters before the end of
+/// it the cursor ends up.
+///
+/// The new line keeps the indentation of the line it's split from, one level deeper after
+/// something that opens a block. Between a pair of brackets, the closing one goes on a line of its
+/// own, and the cursor on the indented line in between.
+pub fn newline(
+    buffer: &Buffer,
+    selection: CCursorRange,
+    language: Language,
+    indentation: Indentation,
+) -> (String, usize) {
+    let [min, max] = selection.sorted();
+    let line = buffer.char_to_line(min.index);
+    let line_range = buffer.line_char_range(line);
+
+    let before = buffer.slice(line_range.start..min.index);
+    let after = buffer.slice(max.index.min(line_range.end).max(min.index)..line_range.end);
+
+    // Only as much of the indentation as is before the cursor.
+    let indent = before
+        .chars()
+        .take_while(|&c| c == ' ' || c == '\t')
+        .collect::<String>();
+
+    if !language.opens_block(&before) {
+        return (format!("\n{indent}"), 0);
+    }
+
+    let unit = indentation.unit();
+    let closes = before
+        .trim_end()
+        .chars()
+        .last()
+        .and_then(closing_bracket)
+        .is_some_and(|close| after.trim_start().starts_with(close));
+
+    if closes {
+        let closing_line = format!("\n{indent}");
+        let back = closing_line.chars().count();
+        (format!("\n{indent}{unit}{closing_line}"), back)
+    } else {
+        (format!("\n{indent}{unit}"), 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// What Enter inserts with the cursor at `|` in `text`, with the cursor marked by `|` too.
+    fn enter(text: &str, language: Language) -> String {
+        let index = text.chars().position(|c| c == '|').unwrap();
+        let buffer = Buffer::new(&text.replace('|', ""));
+
+        let indentation = Indentation {
+            tabs: text.starts_with('\t'),
+            ..Default::default()
+        };
+        let selection = CCursorRange::one(CCursor::new(index));
+        let (inserted, back) = newline(&buffer, selection, language, indentation);
+        let split = inserted.chars().count() - back;
+        let (start, end) = inserted.split_at(
+            inserted
+                .char_indices()
+                .nth(split)
+                .map_or(inserted.len(), |(i, _)| i),
+        );
+        format!("{start}|{end}")
+    }
+
+    #[test]
+    fn enter_keeps_and_adds_indentation() {
+        assert_eq!(enter("    let x = 1;|", Language::Rust), "\n    |");
+        assert_eq!(enter("  |  foo", Language::Rust), "\n  |");
+        assert_eq!(enter("    fn main() {|", Language::Rust), "\n        |");
+        assert_eq!(enter("\tif x:|", Language::Python), "\n\t\t|");
+        assert_eq!(enter("if x:|", Language::Rust), "\n|");
+        assert_eq!(enter("  fn main() {|}", Language::Rust), "\n      |\n  ");
+        assert_eq!(enter("call(|)", Language::PlainText), "\n    |\n");
+    }
+
+    #[test]
+    fn lines_are_indented_and_outdented_by_a_level() {
+        let buffer = Buffer::new("one\n\n  two\n\tthree\nfour");
+        let indentation = Indentation::default();
+        let selection =
+            |min, max| Selections::one(CCursorRange::two(CCursor::new(min), CCursor::new(max)));
+
+        // The empty line isn't indented, and the line the selection ends at the start of isn't
+        // either.
+        let edits = indent_lines(&buffer, &selection(1, 18), indentation, false);
+        assert_eq!(
+            edits.iter().map(|edit| edit.start).collect::<Vec<_>>(),
+            [0, 5, 11]
+        );
+        assert_eq!(shift_index(1, &edits), 5);
+        assert_eq!(shift_index(6, &edits), 14);
+
+        let edits = indent_lines(&buffer, &selection(0, 22), indentation, true);
+        assert_eq!(
+            edits
+                .iter()
+                .map(|edit| (edit.start, edit.removed))
+                .collect::<Vec<_>>(),
+            [(5, 2), (11, 1)]
+        );
+        assert_eq!(shift_index(6, &edits), 5);
+    }
+
+    #[test]
+    fn soft_tabs_move_by_a_level() {
+        let buffer = Buffer::new("      x\n\tx");
+        let indentation = Indentation::default();
+
+        assert_eq!(indentation.tab_at(&buffer, CCursor::new(6)), "  ");
+        assert_eq!(
+            indentation.backspace_from(&buffer, CCursor::new(6)),
+            Some(CCursor::new(4))
+        );
+        assert_eq!(
+            indentation.backspace_from(&buffer, CCursor::new(4)),
+            Some(CCursor::new(0))
+        );
+        assert_eq!(indentation.backspace_from(&buffer, CCursor::new(7)), None);
+        assert_eq!(indentation.backspace_from(&buffer, CCursor::new(9)), None);
+        assert_eq!(indentation.columns("\t  \t"), 8);
+    }
+
+    #[test]
+    fn indentation_is_detected_and_converted() {
+        let spaces = "fn main() {\n  if x {\n    y();\n  }\n}\n";
+        assert_eq!(
+            Indentation::detect(spaces),
+            Indentation {
+                width: 2,
+                ..Default::default()
+            }
+        );
+        assert!(Indentation::detect("a {\n\tb\n\tc {\n\t\td\n").tabs);
+        assert_eq!(
+            Indentation::detect("no indentation"),
+            Indentation::default()
+        );
+
+        // The step from the line indented with tabs doesn't count.
+        assert_eq!(Indentation::detect("a\n  b\n\t\tc\n      d\n").width, 2);
+
+        let inserted = |edits: Vec<IndentEdit>| {
+            edits
+                .into_iter()
+                .map(|edit| edit.inserted)
+                .collect::<Vec<_>>()
+        };
+
+        let buffer = Buffer::new("a\n        b\n      c\n\t d");
+        let edits = convert_indentation(&buffer, Indentation::default(), true);
+        assert_eq!(inserted(edits), ["\t\t", "\t  "]);
+        let edits = convert_indentation(&buffer, Indentation::default(), false);
+        assert_eq!(inserted(edits), ["     "]);
+
+        // By level, however wide tabs are shown.
+        let two_spaces = Indentation {
+            width: 2,
+            tab_width: 8,
+            ..Default::default()
+        };
+        let buffer = Buffer::new("a\n  b\n    c\n     d\n  \te");
+        let edits = convert_indentation(&buffer, two_spaces, true);
+        assert_eq!(inserted(edits), ["\t", "\t\t", "\t\t ", "\t\t"]);
+
+        let buffer = Buffer::new("a\n\tb\n\t\tc\n\t\t d");
+        let edits = convert_indentation(&buffer, two_spaces, false);
+        assert_eq!(inserted(edits), ["  ", "    ", "     "]);
+    }
+}
@@ -0,0 +1,405 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use egui::{Key, KeyboardShortcut, ModifierNames, Modifiers};
+
+use crate::command::Command;
+
+/// Name of the keymap file in the config directory.
+const FILE_NAME: &str = "keymap.toml";
+
+/// The keys pressed one after the other to run a command, like Ctrl+K Ctrl+C.
+pub type KeySequence = Vec<KeyboardShortcut>;
+
+/// The contents of the keymap file, which binds command ids to key sequences:
+///
+/// ```toml
+/// [bindings]
+/// "file.save" = "Cmd+S"
+/// "edit.redo" = ["Cmd+Shift+Z", "Ctrl+K Ctrl+R"]
+/// "selection.select_all" = []
+/// ```
+///
+/// "Cmd" is Cmd on a Mac and Ctrl elsewhere. Commands that are in the file lose their default
+/// bindings, so an empty list unbinds a command.
+#[derive(serde::Deserialize)]
+struct KeymapFile {
+    #[serde(default)]
+    bindings: BTreeMap<String, Bindings>,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(untagged)]
+enum Bindings {
+    One(String),
+    Many(Vec<String>),
+}
+
+/// What a key press did.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Matched {
+    /// The key isn't bound, so it's left for whatever has focus.
+    Unbound,
+
+    /// The key started or continued a sequence, or broke one off.
+    Sequence,
+
+    /// The key completed the sequence of the command.
+    Command(Command),
+}
+
+/// The key bindings of the commands.
+pub struct Keymap {
+    bindings: Vec<(KeySequence, Command)>,
+
+    /// Errors in the keymap file and bindings that conflict, to show to the user.
+    problems: Vec<String>,
+
+    /// The keys pressed so far of a sequence that hasn't been completed yet.
+    pending: Vec<(Key, Modifiers)>,
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        Self {
+            bindings: Command::ALL
+                .iter()
+                .flat_map(|&command| {
+                    command
+                        .default_shortcuts()
+                        .into_iter()
+                        .map(move |shortcut| (vec![shortcut], command))
+                })
+                .collect(),
+            problems: Vec::new(),
+            pending: Vec::new(),
+        }
+    }
+}
+
+impl Keymap {
+    /// Where the keymap file is, if there's a config directory.
+    pub fn path() -> Option<PathBuf> {
+        directories_next::ProjectDirs::from("", "", crate::APP_ID)
+            .map(|dirs| dirs.config_dir().join(FILE_NAME))
+    }
+
+    /// The default keymap with the bindings in the keymap file, if there is one.
+    pub fn load(is_mac: bool) -> Self {
+        let Some(path) = Self::path() else {
+            return Self::default();
+        };
+
+        match std::fs::read_to_string(&path) {
+            Ok(text) => Self::parse(&text, is_mac),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Self::default(),
+            Err(err) => Self {
+                problems: vec![format!("Could not read {}: {err}", path.display())],
+                ..Self::default()
+            },
+        }
+    }
+
+    /// The default keymap with the bindings in `text`, in the format of [`KeymapFile`].
+    ///
+    /// Whatever can't be parsed is skipped and reported in [`Keymap::problems`].
+    pub fn parse(text: &str, is_mac: bool) -> Self {
+        let mut keymap = Self::default();
+
+        let file = match toml::from_str::<KeymapFile>(text) {
+            Ok(file) => file,
+            Err(err) => {
+                keymap.problems.push(format!("{FILE_NAME}: {err}"));
+                return keymap;
+            }
+        };
+
+        for (id, bindings) in file.bindings {
+            let Some(command) = Command::from_id(&id) else {
+                keymap
+                    .problems
+                    .push(format!("{FILE_NAME}: there's no command \"{id}\""));
+                continue;
+            };
+
+            let sequences = match bindings {
+                Bindings::One(sequence) => vec![sequence],
+                Bindings::Many(sequences) => sequences,
+            };
+
+            keymap.bindings.retain(|&(_, bound)| bound != command);
+            for sequence in sequences {
+                match parse_sequence(&sequence) {
+                    Ok(sequence) => keymap.bindings.push((sequence, command)),
+                    Err(err) => keymap
+                        .problems
+                        .push(format!("{FILE_NAME}: \"{sequence}\" for \"{id}\": {err}")),
+                }
+            }
+        }
+
+        keymap.problems.extend(conflicts(&keymap.bindings, is_mac));
+        keymap
+    }
+
+    /// Errors in the keymap file and bindings that conflict.
+    pub fn problems(&self) -> &[String] {
+        &self.problems
+    }
+
+    /// The sequences `command` is bound to.
+    pub fn sequences(&self, command: Command) -> impl Iterator<Item = &KeySequence> {
+        self.bindings
+            .iter()
+            .filter(move |&&(_, bound)| bound == command)
+            .map(|(sequence, _)| sequence)
+    }
+
+    /// The first sequence `command` is bound to, for showing next to it in menus.
+    pub fn shortcut_text(&self, ctx: &egui::Context, command: Command) -> Option<String> {
+        self.sequences(command)
+            .next()
+            .map(|sequence| format_sequence(sequence, |chord| ctx.format_shortcut(chord)))
+    }
+
+    /// The keys pressed so far of a sequence that hasn't been completed.
+    pub fn pending_text(&self, ctx: &egui::Context) -> Option<String> {
+        let pending = self
+            .pending
+            .iter()
+            .map(|&(key, modifiers)| ctx.format_shortcut(&KeyboardShortcut::new(modifiers, key)))
+            .collect::<Vec<_>>();
+        (!pending.is_empty()).then(|| pending.join(" "))
+    }
+
+    /// Whether pressing `key` with `modifiers` next would run a command, or continue a sequence.
+    pub fn binds(&self, key: Key, modifiers: Modifiers) -> bool {
+        self.bindings.iter().any(|(sequence, _)| {
+            sequence.len() > self.pending.len()
+                && sequence
+                    .iter()
+                    .zip(self.pending.iter().chain([&(key, modifiers)]))
+                    .all(|(chord, &(key, modifiers))| chord_matches(chord, key, modifiers))
+        })
+    }
+
+    /// Handles a key press.
+    pub fn press(&mut self, key: Key, modifiers: Modifiers) -> Matched {
+        self.pending.push((key, modifiers));
+
+        let mut is_prefix = false;
+        for (sequence, command) in &self.bindings {
+            if sequence.len() < self.pending.len() {
+                continue;
+            }
+
+            let matches = sequence
+                .iter()
+                .zip(&self.pending)
+                .all(|(chord, &(key, modifiers))| chord_matches(chord, key, modifiers));
+            if matches && sequence.len() == self.pending.len() {
+                self.pending.clear();
+                return Matched::Command(*command);
+            }
+            is_prefix |= matches;
+        }
+
+        if is_prefix {
+            Matched::Sequence
+        } else if self.pending.len() > 1 {
+            self.pending.clear();
+            Matched::Sequence
+        } else {
+            self.pending.clear();
+            Matched::Unbound
+        }
+    }
+}
+
+/// Whether pressing `key` with `modifiers` counts as pressing `chord`.
+fn chord_matches(chord: &KeyboardShortcut, key: Key, modifiers: Modifiers) -> bool {
+    chord.logical_key == key && modifiers.matches_exact(chord.modifiers)
+}
+
+/// The modifiers actually held down when pressing `chord`.
+fn pressed_modifiers(chord: &KeyboardShortcut, is_mac: bool) -> Modifiers {
+    let Modifiers {
+        alt,
+        ctrl,
+        shift,
+        mac_cmd,
+        command,
+    } = chord.modifiers;
+
+    Modifiers {
+        alt,
+        ctrl: ctrl || (command && !is_mac),
+        shift,
+        mac_cmd: mac_cmd || (command && is_mac),
+        command: command || mac_cmd || (ctrl && !is_mac),
+    }
+}
+
+/// Bindings where pressing the keys of one also runs, or starts, another.
+fn conflicts(bindings: &[(KeySequence, Command)], is_mac: bool) -> Vec<String> {
+    // Whether pressing `a` is the same as pressing the start of `b`.
+    let is_prefix = |a: &KeySequence, b: &KeySequence| {
+        a.len() <= b.len()
+            && a.iter().zip(b).all(|(a, b)| {
+                chord_matches(b, a.logical_key, pressed_modifiers(a, is_mac))
+                    || chord_matches(a, b.logical_key, pressed_modifiers(b, is_mac))
+            })
+    };
+    let text = |sequence: &KeySequence| {
+        format_sequence(sequence, |chord| {
+            chord.format(&ModifierNames::NAMES, is_mac)
+        })
+    };
+
+    let mut conflicts = Vec::new();
+    for (i, (a, a_command)) in bindings.iter().enumerate() {
+        for (b, b_command) in &bindings[i + 1..] {
+            if a_command == b_command {
+                continue;
+            }
+
+            if is_prefix(a, b) || is_prefix(b, a) {
+                conflicts.push(format!(
+                    "{} for \"{}\" conflicts with {} for \"{}\"",
+                    text(a),
+                    a_command.id(),
+                    text(b),
+                    b_command.id(),
+                ));
+            }
+        }
+    }
+    conflicts
+}
+
+fn format_sequence(
+    sequence: &KeySequence,
+    format_chord: impl Fn(&KeyboardShortcut) -> String,
+) -> String {
+    sequence
+        .iter()
+        .map(format_chord)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Parses chords separated by spaces, like "Ctrl+K Ctrl+C".
+fn parse_sequence(text: &str) -> Result<KeySequence, String> {
+    let sequence = text
+        .split_whitespace()
+        .map(parse_chord)
+        .collect::<Result<KeySequence, _>>()?;
+
+    if sequence.is_empty() {
+        return Err("no keys given".to_owned());
+    }
+    Ok(sequence)
+}
+
+/// Parses modifiers and a key separated by `+`, like "Ctrl+Shift+P".
+fn parse_chord(text: &str) -> Result<KeyboardShortcut, String> {
+    let mut parts = text.split('+').collect::<Vec<_>>();
+    let key = parts.pop().unwrap_or_default();
+
+    let mut modifiers = Modifiers::NONE;
+    for modifier in parts {
+        modifiers = modifiers.plus(match modifier.to_lowercase().as_str() {
+            "alt" | "option" => Modifiers::ALT,
+            "ctrl" | "control" => Modifiers::CTRL,
+            "shift" => Modifiers::SHIFT,
+            "cmd" | "command" => Modifiers::COMMAND,
+            _ => return Err(format!("unknown modifier \"{modifier}\"")),
+        });
+    }
+
+    let key = Key::from_name(key)
+        .or_else(|| Key::from_name(&key.to_uppercase()))
+        .ok_or_else(|| format!("unknown key \"{key}\""))?;
+
+    Ok(KeyboardShortcut::new(modifiers, key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_bindings_dont_conflict() {
+        assert_eq!(Keymap::default().problems(), [] as [String; 0]);
+        assert!(conflicts(&Keymap::default().bindings, true).is_empty());
+        assert!(conflicts(&Keymap::default().bindings, false).is_empty());
+    }
+
+    #[test]
+    fn bindings_replace_the_defaults() {
+        let keymap = Keymap::parse(
+            r#"
+            [bindings]
+            "edit.undo" = "Ctrl+K Ctrl+U"
+            "edit.redo" = []
+            "#,
+            false,
+        );
+        assert_eq!(keymap.problems(), [] as [String; 0]);
+
+        assert_eq!(
+            keymap.sequences(Command::Undo).collect::<Vec<_>>(),
+            [&vec![
+                KeyboardShortcut::new(Modifiers::CTRL, Key::K),
+                KeyboardShortcut::new(Modifiers::CTRL, Key::U),
+            ]]
+        );
+        assert_eq!(keymap.sequences(Command::Redo).count(), 0);
+        assert_eq!(keymap.sequences(Command::Save).count(), 1);
+    }
+
+    #[test]
+    fn sequences_are_matched_key_by_key() {
+        let mut keymap = Keymap::parse(
+            r#"
+            [bindings]
+            "edit.undo" = "Ctrl+K Ctrl+U"
+            "#,
+            false,
+        );
+        let ctrl = Modifiers::CTRL | Modifiers::COMMAND;
+
+        assert_eq!(keymap.press(Key::K, ctrl), Matched::Sequence);
+        assert_eq!(keymap.press(Key::U, ctrl), Matched::Command(Command::Undo));
+
+        // Anything else breaks off the sequence.
+        assert_eq!(keymap.press(Key::K, ctrl), Matched::Sequence);
+        assert_eq!(keymap.press(Key::U, Modifiers::NONE), Matched::Sequence);
+        assert_eq!(keymap.press(Key::U, Modifiers::NONE), Matched::Unbound);
+
+        assert_eq!(keymap.press(Key::S, ctrl), Matched::Command(Command::Save));
+    }
+
+    #[test]
+    fn problems_are_reported() {
+        let keymap = Keymap::parse("[bindings", false);
+        assert_eq!(keymap.problems().len(), 1);
+
+        let keymap = Keymap::parse(
+            r#"
+            [bindings]
+            "edit.undo" = "Hyper+Z"
+            "edit.redo" = "Cmd+Banana"
+            "edit.unknown" = "Cmd+U"
+            "file.save" = "Ctrl+O"
+            "#,
+            false,
+        );
+        let problems = keymap.problems();
+        assert_eq!(problems.len(), 4, "{problems:#?}");
+        assert!(problems[0].contains("Banana"));
+        assert!(problems[1].contains("Hyper"));
+        assert!(problems[2].contains("edit.unknown"));
+        assert!(problems[3].contains("file.open"));
+    }
+}
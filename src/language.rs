@@ -0,0 +1,177 @@
+/// What a file is written in, as far as editing it goes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Language {
+    #[default]
+    PlainText,
+    Rust,
+
+    /// C, and the languages that borrowed its braces, like C++, Java, JavaScript and Go.
+    CLike,
+    Python,
+    Ruby,
+    Lua,
+    Shell,
+    Toml,
+    Yaml,
+    Html,
+    Css,
+}
+
+impl Language {
+    /// The language of the file at `path`, from its extension.
+    pub fn from_path(path: &str) -> Self {
+        let extension = std::path::Path::new(path)
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(str::to_ascii_lowercase);
+
+        match extension.as_deref() {
+            Some("rs") => Self::Rust,
+            Some(
+                "c" | "h" | "cc" | "cpp" | "cxx" | "hpp" | "java" | "js" | "jsx" | "ts" | "tsx"
+                | "go" | "cs" | "swift" | "kt" | "scala" | "dart",
+            ) => Self::CLike,
+            Some("py" | "pyw") => Self::Python,
+            Some("rb") => Self::Ruby,
+            Some("lua") => Self::Lua,
+            Some("sh" | "bash" | "zsh" | "fish") => Self::Shell,
+            Some("toml") => Self::Toml,
+            Some("yaml" | "yml") => Self::Yaml,
+            Some("html" | "htm" | "xml" | "svg") => Self::Html,
+            Some("css" | "scss" | "less") => Self::Css,
+            _ => Self::PlainText,
+        }
+    }
+
+    /// Whether a line ending in `text` opens a block, so the line after it is indented further.
+    pub fn opens_block(self, text: &str) -> bool {
+        let text = text.trim_end();
+        if text.ends_with(['{', '[', '(']) {
+            return true;
+        }
+
+        // A Ruby block opens before its parameters, as in `do |item|`.
+        let text = match text.strip_suffix('|') {
+            Some(rest) if self == Self::Ruby => {
+                rest.rfind('|').map_or(text, |i| rest[..i].trim_end())
+            }
+            _ => text,
+        };
+
+        let last_word = text
+            .rsplit(|c: char| !c.is_alphanumeric() && c != '_')
+            .next()
+            .unwrap_or_default();
+        let first_word = text.split_whitespace().next().unwrap_or_default();
+
+        match self {
+            Self::Python | Self::Yaml => text.ends_with(':'),
+            Self::Ruby => {
+                matches!(last_word, "do" | "then" | "else" | "begin")
+                    || matches!(
+                        first_word,
+                        "def" | "class" | "module" | "if" | "unless" | "while" | "until" | "elsif"
+                    )
+            }
+            Self::Lua => {
+                matches!(last_word, "do" | "then" | "else" | "repeat")
+                    || (text.contains("function") && text.ends_with(')'))
+            }
+            Self::Shell => matches!(last_word, "do" | "then" | "else"),
+            Self::Html => {
+                // An opening tag that isn't closed on the same line. Comments, doctypes and
+                // elements that can't have content aren't closed at all.
+                let Some(i) = text.rfind('<') else {
+                    return false;
+                };
+                let tag = &text[i + 1..];
+                let name = tag
+                    .split(|c: char| c.is_whitespace() || c == '>' || c == '/')
+                    .next()
+                    .unwrap_or_default();
+
+                text.ends_with('>')
+                    && !text.ends_with("/>")
+                    && !text.ends_with("-->")
+                    && !tag.starts_with(['/', '!', '?'])
+                    && !text[..i].contains("</")
+                    && !is_void_element(name)
+            }
+            _ => false,
+        }
+    }
+
+    /// What starts a comment running to the end of the line.
+    pub fn line_comment(self) -> Option<&'static str> {
+        match self {
+            Self::Rust | Self::CLike => Some("//"),
+            Self::Python | Self::Ruby | Self::Shell | Self::Toml | Self::Yaml => Some("#"),
+            Self::Lua => Some("--"),
+            Self::PlainText | Self::Html | Self::Css => None,
+        }
+    }
+
+    /// What starts and ends a comment that can span lines.
+    pub fn block_comment(self) -> Option<(&'static str, &'static str)> {
+        match self {
+            Self::Rust | Self::CLike | Self::Css => Some(("/*", "*/")),
+            Self::Lua => Some(("--[[", "]]")),
+            Self::Html => Some(("<!--", "-->")),
+            Self::PlainText | Self::Python | Self::Ruby | Self::Shell | Self::Toml | Self::Yaml => {
+                None
+            }
+        }
+    }
+}
+
+/// Whether the HTML element `name` is one that has no content or closing tag, like `<br>`.
+fn is_void_element(name: &str) -> bool {
+    const VOID_ELEMENTS: [&str; 14] = [
+        "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "param",
+        "source", "track", "wbr",
+    ];
+    VOID_ELEMENTS
+        .iter()
+        .any(|void| void.eq_ignore_ascii_case(name))
+}
+
+/// The bracket closing `open`, if it's an opening bracket.
+pub fn closing_bracket(open: char) -> Option<char> {
+    match open {
+        '{' => Some('}'),
+        '[' => Some(']'),
+        '(' => Some(')'),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocks_are_opened_per_language() {
+        assert_eq!(Language::from_path("src/main.rs"), Language::Rust);
+        assert_eq!(Language::from_path("script.PY"), Language::Python);
+        assert_eq!(Language::from_path("README"), Language::PlainText);
+
+        assert!(Language::PlainText.opens_block("fn main() {"));
+        assert!(Language::PlainText.opens_block("let v = vec![  "));
+        assert!(!Language::PlainText.opens_block("if x:"));
+        assert!(Language::Python.opens_block("if x:"));
+        assert!(Language::Ruby.opens_block("items.each do |item|"));
+        assert!(Language::Lua.opens_block("if x then"));
+        assert!(Language::Shell.opens_block("for f in *; do"));
+        assert!(Language::Html.opens_block("<div class=\"a\">"));
+        assert!(!Language::Html.opens_block("<p>text</p>"));
+        assert!(!Language::Html.opens_block("<br/>"));
+        assert!(!Language::Html.opens_block("<input type=\"text\" />"));
+        assert!(!Language::Html.opens_block("<br>"));
+        assert!(!Language::Html.opens_block("  <IMG src=\"a.png\">"));
+        assert!(!Language::Html.opens_block("<!-- <div> -->"));
+        assert!(!Language::Html.opens_block("<!DOCTYPE html>"));
+        assert!(!Language::Html.opens_block("<?xml version=\"1.0\"?>"));
+        assert!(Language::Html.opens_block("<body>"));
+        assert!(Language::Html.opens_block("<section id=\"main\">"));
+    }
+}
@@ -1,4 +1,23 @@
 #![warn(clippy::all, rust_2018_idioms)]
 
 mod app;
+mod buffer;
+mod command;
+mod comment;
+mod emacs;
+mod highlight;
+mod history;
+mod indent;
+mod keymap;
+mod language;
+mod line_ending;
+mod lines;
+mod motion;
+mod palette;
+mod selection;
+mod view;
+mod vim;
 pub use app::TemplateApp;
+
+/// Identifies the app to the OS, and names its config directory.
+pub const APP_ID: &str = "com.thorlaksson.egui_edit";
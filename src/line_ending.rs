@@ -0,0 +1,93 @@
+use std::borrow::Cow;
+
+/// What ends the lines of a file. The buffer always uses `\n`, and the file's own line ending is
+/// put back when it's saved.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LineEnding {
+    #[default]
+    Lf,
+    Crlf,
+}
+
+impl LineEnding {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Lf => "\n",
+            Self::Crlf => "\r\n",
+        }
+    }
+
+    /// The name of the line ending, for the status bar.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Lf => "LF",
+            Self::Crlf => "CRLF",
+        }
+    }
+}
+
+/// How many lines of a text end in each way.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LineEndings {
+    pub lf: usize,
+    pub crlf: usize,
+}
+
+impl LineEndings {
+    pub fn count(text: &str) -> Self {
+        let newlines = text.matches('\n').count();
+        let crlf = text.matches("\r\n").count();
+
+        Self {
+            lf: newlines - crlf,
+            crlf,
+        }
+    }
+
+    /// Whether lines end in both ways.
+    pub fn is_mixed(self) -> bool {
+        self.lf > 0 && self.crlf > 0
+    }
+
+    /// The line ending most lines use, or LF if there's only one line.
+    pub fn most_common(self) -> LineEnding {
+        if self.crlf > self.lf {
+            LineEnding::Crlf
+        } else {
+            LineEnding::Lf
+        }
+    }
+}
+
+/// `text` with every line ending in `\n`.
+pub fn normalize(text: &str) -> Cow<'_, str> {
+    if text.contains("\r\n") {
+        Cow::Owned(text.replace("\r\n", "\n"))
+    } else {
+        Cow::Borrowed(text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn line_endings_are_detected() {
+        let lf = LineEndings::count("one\ntwo\n");
+        assert_eq!(lf.most_common(), LineEnding::Lf);
+        assert!(!lf.is_mixed());
+
+        let crlf = LineEndings::count("one\r\ntwo\r\nthree");
+        assert_eq!(crlf.most_common(), LineEnding::Crlf);
+        assert!(!crlf.is_mixed());
+
+        let mixed = LineEndings::count("one\r\ntwo\nthree\r\n");
+        assert_eq!(mixed, LineEndings { lf: 1, crlf: 2 });
+        assert!(mixed.is_mixed());
+        assert_eq!(mixed.most_common(), LineEnding::Crlf);
+
+        assert_eq!(LineEndings::count("").most_common(), LineEnding::Lf);
+        assert_eq!(normalize("one\r\ntwo\n"), "one\ntwo\n");
+    }
+}
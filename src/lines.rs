@@ -0,0 +1,491 @@
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::ops::Range;
+
+use egui::text::{CCursor, CCursorRange};
+
+use crate::buffer::Buffer;
+use crate::selection::Selections;
+
+/// An edit to the lines the selections are on, rather than to the selected text.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LineOperation {
+    /// Copies the lines below themselves, moving the selections onto the copy.
+    Duplicate,
+    MoveUp,
+    MoveDown,
+    Delete,
+
+    /// Joins the lines into one with a space between each, or a single line with the one after
+    /// it.
+    Join,
+
+    /// Inserts an empty line above, indented like the line below it.
+    InsertAbove,
+
+    /// Inserts an empty line below, indented like the line above it.
+    InsertBelow,
+    Sort(SortOrder),
+    Reverse,
+
+    /// Removes lines that are the same as a line before them.
+    Unique,
+    TrimTrailingWhitespace,
+}
+
+/// How lines are sorted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SortOrder {
+    CaseSensitive,
+    CaseInsensitive,
+
+    /// By the number each line starts with, with the lines that don't start with one last.
+    Numeric,
+}
+
+impl LineOperation {
+    /// Whether the operation is on every line when nothing is selected, rather than just the
+    /// lines with a cursor on them.
+    fn whole_buffer_by_default(self) -> bool {
+        matches!(
+            self,
+            Self::Sort(_) | Self::Reverse | Self::Unique | Self::TrimTrailingWhitespace
+        )
+    }
+
+    /// Whether the operation also edits the line before or after the lines it's on, so lines
+    /// next to each other have to be edited together.
+    fn takes_neighbours(self) -> bool {
+        matches!(
+            self,
+            Self::MoveUp | Self::MoveDown | Self::Delete | Self::Join
+        )
+    }
+}
+
+/// Lines of the buffer replaced with other text.
+#[derive(Debug, PartialEq, Eq)]
+pub struct LinesEdit {
+    /// From the start of the first line to the end of the last one, without its newline.
+    pub range: Range<usize>,
+    pub text: String,
+}
+
+/// A position in some lines: the line, counted from the first one, and the column in characters.
+type Position = (usize, usize);
+
+/// What a group of lines is replaced with, and the selections on the new lines, as their
+/// secondary and primary ends.
+struct Replacement {
+    lines: Vec<String>,
+    selections: Vec<[Position; 2]>,
+}
+
+/// The edits making `operation` on the lines the selections are on, and the selections after
+/// them. Returns `None` if the operation doesn't change anything.
+///
+/// Selections on the same lines are edited as one group of lines, and so are those on neighbouring
+/// lines when the operation takes in the line above or below, so no line is edited twice.
+pub fn edit_lines(
+    buffer: &Buffer,
+    selections: &Selections,
+    operation: LineOperation,
+) -> Option<(Vec<LinesEdit>, Selections)> {
+    let mut edits = Vec::new();
+    let mut ranges = Vec::with_capacity(selections.len());
+    let mut main = 0;
+
+    // How many characters the edits so far have added.
+    let mut offset = 0isize;
+
+    for (lines, indices) in groups(buffer, selections, operation) {
+        let group = indices
+            .iter()
+            .map(|&i| selections.ranges()[i])
+            .collect::<Vec<_>>();
+        let has_main = indices.contains(&selections.main_index());
+
+        let Some((first, last, replacement)) = replace_lines(buffer, lines, &group, operation)
+        else {
+            for (range, i) in group.into_iter().zip(indices) {
+                if i == selections.main_index() {
+                    main = ranges.len();
+                }
+                ranges.push(CCursorRange {
+                    primary: CCursor::new(range.primary.index.saturating_add_signed(offset)),
+                    secondary: CCursor::new(range.secondary.index.saturating_add_signed(offset)),
+                });
+            }
+            continue;
+        };
+
+        let range = buffer.line_to_char(first)..buffer.line_char_range(last).end;
+        let start = range.start.saturating_add_signed(offset);
+        let index = |(line, column): Position| {
+            let before = replacement.lines[..line]
+                .iter()
+                .map(|line| line.chars().count() + 1)
+                .sum::<usize>();
+            CCursor::new(start + before + column)
+        };
+
+        // Either every selection is kept, or they're all replaced by one.
+        let kept = replacement.selections.len() == indices.len();
+        for (i, [secondary, primary]) in replacement.selections.iter().copied().enumerate() {
+            if (kept && indices[i] == selections.main_index()) || (!kept && has_main) {
+                main = ranges.len();
+            }
+            ranges.push(CCursorRange {
+                primary: index(primary),
+                secondary: index(secondary),
+            });
+        }
+
+        let text = replacement.lines.join("\n");
+        offset += text.chars().count() as isize - range.len() as isize;
+        edits.push(LinesEdit { range, text });
+    }
+
+    (!edits.is_empty()).then(|| (edits, Selections::new(ranges, main)))
+}
+
+/// The lines the selections are on, with those on the same lines grouped together, along with the
+/// indices of the selections in each group.
+fn groups(
+    buffer: &Buffer,
+    selections: &Selections,
+    operation: LineOperation,
+) -> Vec<(Range<usize>, Vec<usize>)> {
+    let nothing_selected = selections
+        .ranges()
+        .iter()
+        .all(|range| range.primary == range.secondary);
+    if operation.whole_buffer_by_default() && nothing_selected {
+        // Leave out the empty line after a trailing newline, so sorting doesn't move it up.
+        let mut last = buffer.len_lines() - 1;
+        if last > 0 && buffer.line_char_range(last).is_empty() {
+            last -= 1;
+        }
+        return vec![(0..last + 1, (0..selections.len()).collect())];
+    }
+
+    let mut groups: Vec<(Range<usize>, Vec<usize>)> = Vec::new();
+    for (i, &range) in selections.ranges().iter().enumerate() {
+        let Range { start, end } = selected_lines(buffer, range);
+
+        match groups.last_mut() {
+            Some((lines, indices))
+                if start < lines.end || (start == lines.end && operation.takes_neighbours()) =>
+            {
+                lines.end = lines.end.max(end);
+                indices.push(i);
+            }
+            _ => groups.push((start..end, vec![i])),
+        }
+    }
+    groups
+}
+
+/// The lines `selection` is on. A selection ending at the very start of a line isn't on it.
+pub fn selected_lines(buffer: &Buffer, selection: CCursorRange) -> Range<usize> {
+    let [min, max] = selection.sorted();
+    let first = buffer.char_to_line(min.index);
+    let mut last = buffer.char_to_line(max.index);
+    if last > first && buffer.line_to_char(last) == max.index {
+        last -= 1;
+    }
+    first..last + 1
+}
+
+/// The first and last line `operation` replaces to edit `lines`, with `selections` on them, and
+/// what it replaces them with. Returns `None` if it doesn't change them.
+fn replace_lines(
+    buffer: &Buffer,
+    lines: Range<usize>,
+    selections: &[CCursorRange],
+    operation: LineOperation,
+) -> Option<(usize, usize, Replacement)> {
+    let end = buffer.len_lines() - 1;
+    let (first, last) = (lines.start, lines.end - 1);
+
+    // Moving and joining lines takes in a neighbouring line, and so does deleting them, to take
+    // the newline between them.
+    let (first, last) = match operation {
+        LineOperation::MoveUp => (first.checked_sub(1)?, last),
+        LineOperation::MoveDown => (first, (last < end).then_some(last + 1)?),
+        LineOperation::Join if first == last => (first, (last < end).then_some(last + 1)?),
+        LineOperation::Delete if last < end => (first, last + 1),
+        LineOperation::Delete => (first.saturating_sub(1), last),
+        _ => (first, last),
+    };
+
+    let old = (first..=last)
+        .map(|line| buffer.line_text(line))
+        .collect::<Vec<_>>();
+    let mut new = old.clone();
+
+    let positions = selections
+        .iter()
+        .map(|range| {
+            [range.secondary, range.primary].map(|ccursor| {
+                let line = buffer.char_to_line(ccursor.index);
+                (line - first, ccursor.index - buffer.line_to_char(line))
+            })
+        })
+        .collect::<Vec<_>>();
+    let moved = |lines: isize| {
+        positions
+            .iter()
+            .map(|ends| ends.map(|(line, column)| (line.saturating_add_signed(lines), column)))
+            .collect()
+    };
+
+    let len = |line: &str| line.chars().count();
+    let indent = |line: &str| {
+        line.chars()
+            .take_while(|&c| c == ' ' || c == '\t')
+            .collect::<String>()
+    };
+    // Where the lines that were asked for are, among those replaced.
+    let group = lines.start - first..lines.end - first;
+
+    let selections = match operation {
+        LineOperation::Duplicate => {
+            new.extend_from_slice(&old);
+            moved(group.len() as isize)
+        }
+        LineOperation::MoveUp => {
+            new.rotate_left(1);
+            moved(-1)
+        }
+        LineOperation::MoveDown => {
+            new.rotate_right(1);
+            moved(1)
+        }
+        LineOperation::Delete => {
+            new = if lines.end - 1 < end {
+                vec![old[old.len() - 1].clone()]
+            } else if lines.start > 0 {
+                vec![old[0].clone()]
+            } else {
+                vec![String::new()]
+            };
+
+            // The cursors stay in the same column, on the line that takes the place of the
+            // deleted ones.
+            positions
+                .iter()
+                .map(|&[_, (_, column)]| [(0, column.min(len(&new[0]))); 2])
+                .collect()
+        }
+        LineOperation::Join => {
+            let mut joined = old[0].clone();
+            let mut column = 0;
+            for line in &old[1..] {
+                joined.truncate(joined.trim_end().len());
+                column = len(&joined);
+
+                let line = line.trim_start();
+                if !joined.is_empty() && !line.is_empty() {
+                    joined.push(' ');
+                }
+                joined.push_str(line);
+            }
+
+            // The cursor goes where the last line was joined on.
+            new = vec![joined];
+            vec![[(0, column); 2]]
+        }
+        LineOperation::InsertAbove | LineOperation::InsertBelow => {
+            let above = operation == LineOperation::InsertAbove;
+
+            // A line above or below each selection, which goes before the line at `target`.
+            let targets = selections
+                .iter()
+                .map(|&range| {
+                    let lines = selected_lines(buffer, range);
+                    if above {
+                        lines.start - first
+                    } else {
+                        lines.end - first
+                    }
+                })
+                .collect::<Vec<_>>();
+            let mut inserted = targets.clone();
+            inserted.sort_unstable();
+            inserted.dedup();
+
+            for &target in inserted.iter().rev() {
+                let indent = indent(&old[if above { target } else { target - 1 }]);
+                new.insert(target, indent);
+            }
+
+            // Each cursor goes on its new line, after the lines inserted above it.
+            targets
+                .iter()
+                .map(|&target| {
+                    let line = target + inserted.iter().filter(|&&other| other < target).count();
+                    [(line, len(&new[line])); 2]
+                })
+                .collect()
+        }
+        LineOperation::Sort(order) => {
+            sort(&mut new, order);
+            vec![select_all(&new)]
+        }
+        LineOperation::Reverse => {
+            new.reverse();
+            vec![select_all(&new)]
+        }
+        LineOperation::Unique => {
+            let mut seen = HashSet::new();
+            new.retain(|line| seen.insert(line.clone()));
+            vec![select_all(&new)]
+        }
+        LineOperation::TrimTrailingWhitespace => {
+            for line in &mut new {
+                line.truncate(line.trim_end().len());
+            }
+            positions
+                .iter()
+                .map(|ends| ends.map(|(line, column)| (line, column.min(len(&new[line])))))
+                .collect()
+        }
+    };
+
+    (new != old).then_some((
+        first,
+        last,
+        Replacement {
+            lines: new,
+            selections,
+        },
+    ))
+}
+
+/// A selection of every one of `lines`.
+fn select_all(lines: &[String]) -> [Position; 2] {
+    let last = lines.len() - 1;
+    [(0, 0), (last, lines[last].chars().count())]
+}
+
+fn sort(lines: &mut [String], order: SortOrder) {
+    match order {
+        SortOrder::CaseSensitive => lines.sort(),
+        SortOrder::CaseInsensitive => lines.sort_by_cached_key(|line| line.to_lowercase()),
+        SortOrder::Numeric => lines.sort_by(|a, b| match (leading_number(a), leading_number(b)) {
+            (Some(a), Some(b)) => a.total_cmp(&b),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => Ordering::Equal,
+        }),
+    }
+}
+
+/// The number at the start of `line`, after any indentation.
+fn leading_number(line: &str) -> Option<f64> {
+    let line = line.trim_start();
+    let end = line
+        .char_indices()
+        .find(|&(i, c)| !(c.is_ascii_digit() || c == '.' || (i == 0 && matches!(c, '-' | '+'))))
+        .map_or(line.len(), |(i, _)| i);
+
+    // The longest part that's a number, so `1.2.3` sorts as 1.2.
+    (1..=end).rev().find_map(|end| line[..end].parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The text after making `operation` with cursors at each `|` in `text`, with the cursors and
+    /// the ends of selections after it marked by `|` too.
+    fn edit(text: &str, operation: LineOperation) -> String {
+        let mut ranges = Vec::new();
+        for (i, _) in text.match_indices('|') {
+            let index = text[..i].chars().count() - ranges.len();
+            ranges.push(CCursorRange::one(CCursor::new(index)));
+        }
+        let buffer = Buffer::new(&text.replace('|', ""));
+        let selections = Selections::new(ranges, 0);
+
+        let Some((edits, selections)) = edit_lines(&buffer, &selections, operation) else {
+            return text.to_owned();
+        };
+
+        let mut chars = buffer
+            .slice(0..buffer.len_chars())
+            .chars()
+            .collect::<Vec<_>>();
+        for edit in edits.iter().rev() {
+            chars.splice(edit.range.clone(), edit.text.chars());
+        }
+
+        let mut marks = selections
+            .ranges()
+            .iter()
+            .flat_map(|range| {
+                let [min, max] = range.sorted();
+                if min == max {
+                    vec![min.index]
+                } else {
+                    vec![min.index, max.index]
+                }
+            })
+            .collect::<Vec<_>>();
+        marks.sort_unstable();
+        for index in marks.into_iter().rev() {
+            chars.insert(index, '|');
+        }
+        chars.into_iter().collect()
+    }
+
+    #[test]
+    fn lines_are_duplicated_moved_and_deleted() {
+        use LineOperation::*;
+
+        assert_eq!(edit("a|b\nc", Duplicate), "ab\na|b\nc");
+        assert_eq!(edit("a\nb|\nc|", MoveUp), "b|\nc|\na");
+        assert_eq!(edit("a|\nb\nc|", MoveUp), "a|\nc|\nb");
+        assert_eq!(edit("a|\nb\nc|", MoveDown), "b\na|\nc|");
+        assert_eq!(edit("one\ntw|o\nthree", Delete), "one\nth|ree");
+        assert_eq!(edit("one\ntwo|", Delete), "one|");
+        assert_eq!(edit("|one", Delete), "|");
+        assert_eq!(edit("o|ne\ntw|o\nthree", Delete), "t|h|ree");
+    }
+
+    #[test]
+    fn lines_are_joined_and_inserted() {
+        use LineOperation::*;
+
+        assert_eq!(edit("fn main() {|\n    x\n}", Join), "fn main() {| x\n}");
+        assert_eq!(edit("a  |\n\nb", Join), "a|\nb");
+        assert_eq!(edit("last|", Join), "last|");
+        assert_eq!(edit("    x|\ny", InsertAbove), "    |\n    x\ny");
+        assert_eq!(edit("    x|\ny|", InsertBelow), "    x\n    |\ny\n|");
+        assert_eq!(edit("a|\n  b|", InsertAbove), "|\na\n  |\n  b");
+        assert_eq!(edit("x|y|\nz", InsertBelow), "xy\n|\nz");
+    }
+
+    #[test]
+    fn lines_are_sorted_and_cleaned_up() {
+        use LineOperation::*;
+
+        let lines = "|b\nC\n10\n9 lives\na\n";
+        assert_eq!(
+            edit(lines, Sort(SortOrder::CaseSensitive)),
+            "|10\n9 lives\nC\na\nb|\n"
+        );
+        assert_eq!(
+            edit(lines, Sort(SortOrder::CaseInsensitive)),
+            "|10\n9 lives\na\nb\nC|\n"
+        );
+        assert_eq!(
+            edit(lines, Sort(SortOrder::Numeric)),
+            "|9 lives\n10\nb\nC\na|\n"
+        );
+        assert_eq!(edit("a\nb\na\nc|", Unique), "|a\nb\nc|");
+        assert_eq!(edit("a|\nb\nc", Reverse), "|c\nb\na|");
+        assert_eq!(edit("a  \nb\t|\n", TrimTrailingWhitespace), "a\nb|\n");
+    }
+}
@@ -11,7 +11,7 @@ fn main() -> eframe::Result<()> {
 
     let native_options = eframe::NativeOptions {
         viewport: egui::ViewportBuilder::default()
-            .with_app_id("com.thorlaksson.egui_edit")
+            .with_app_id(egui_edit::APP_ID)
             .with_inner_size([1200.0, 800.0])
             .with_min_inner_size([300.0, 220.0])
             .with_icon(
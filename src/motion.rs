@@ -0,0 +1,167 @@
+use egui::text::CCursor;
+use egui::{vec2, Key, Modifiers};
+
+use crate::buffer::Buffer;
+use crate::view::LineLayout;
+
+/// A way of moving the cursor, independent of the keys it's bound to.
+///
+/// Every motion can also extend the selection, which is what Shift does with any of them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Motion {
+    Left,
+    Right,
+    WordLeft,
+    WordRight,
+
+    /// To the first non-whitespace character of the line, or to the start of the line if the
+    /// cursor is already there.
+    LineStart,
+    LineEnd,
+    Up,
+    Down,
+
+    /// Up or down by the height of the view.
+    PageUp,
+    PageDown,
+    DocumentStart,
+    DocumentEnd,
+}
+
+impl Motion {
+    /// The motion `key` is bound to with `modifiers`, ignoring Shift.
+    ///
+    /// Follows the conventions of the platform: on a Mac, Cmd+arrows move to the ends of the line
+    /// or document and Alt+arrows jump words, while elsewhere Ctrl+arrows jump words and
+    /// Ctrl+Home/End move to the ends of the document.
+    pub fn from_key(key: Key, modifiers: Modifiers) -> Option<Self> {
+        let word = modifiers.alt || (modifiers.ctrl && !modifiers.mac_cmd);
+
+        let motion = match key {
+            Key::ArrowLeft if modifiers.mac_cmd => Self::LineStart,
+            Key::ArrowLeft if word => Self::WordLeft,
+            Key::ArrowLeft if !modifiers.command => Self::Left,
+            Key::ArrowRight if modifiers.mac_cmd => Self::LineEnd,
+            Key::ArrowRight if word => Self::WordRight,
+            Key::ArrowRight if !modifiers.command => Self::Right,
+            Key::ArrowUp if modifiers.mac_cmd && !modifiers.alt => Self::DocumentStart,
+            Key::ArrowUp if !modifiers.alt && !modifiers.command => Self::Up,
+            Key::ArrowDown if modifiers.mac_cmd && !modifiers.alt => Self::DocumentEnd,
+            Key::ArrowDown if !modifiers.alt && !modifiers.command => Self::Down,
+            Key::Home if modifiers.command => Self::DocumentStart,
+            Key::Home if !modifiers.alt => Self::LineStart,
+            Key::End if modifiers.command => Self::DocumentEnd,
+            Key::End if !modifiers.alt => Self::LineEnd,
+            Key::PageUp if !modifiers.alt && !modifiers.command => Self::PageUp,
+            Key::PageDown if !modifiers.alt && !modifiers.command => Self::PageDown,
+            _ => return None,
+        };
+        Some(motion)
+    }
+
+    /// Whether the motion moves between lines, and should keep the cursor's horizontal position.
+    pub fn is_vertical(self) -> bool {
+        matches!(self, Self::Up | Self::Down | Self::PageUp | Self::PageDown)
+    }
+
+    /// Where the cursor ends up after the motion, if the view is `page_height` points tall.
+    ///
+    /// Vertical motions move to the position closest to `x`, relative to the left of the text, or
+    /// to the cursor's own horizontal position if there's no `x`.
+    pub fn apply(
+        self,
+        ui: &egui::Ui,
+        buffer: &Buffer,
+        line_layout: &mut LineLayout,
+        ccursor: CCursor,
+        x: Option<f32>,
+        page_height: f32,
+    ) -> CCursor {
+        let line = buffer.char_to_line(ccursor.index);
+        let target_x = |line_layout: &mut LineLayout| {
+            x.unwrap_or_else(|| line_layout.cursor_rect(ui, buffer, ccursor).center().x)
+        };
+
+        match self {
+            Self::Left => buffer.clamp(ccursor - 1),
+            Self::Right => buffer.clamp(ccursor + 1),
+            Self::WordLeft => buffer.previous_word(ccursor),
+            Self::WordRight => buffer.next_word(ccursor),
+            Self::LineStart => buffer.smart_line_start(ccursor),
+            Self::LineEnd => buffer.line_end(ccursor),
+            Self::Up if line == 0 => CCursor::default(),
+            Self::Up => {
+                let x = target_x(line_layout);
+                line_layout.cursor_in_line_at_x(ui, buffer, line - 1, x)
+            }
+            Self::Down if line + 1 >= buffer.len_lines() => CCursor::new(buffer.len_chars()),
+            Self::Down => {
+                let x = target_x(line_layout);
+                line_layout.cursor_in_line_at_x(ui, buffer, line + 1, x)
+            }
+            Self::PageUp | Self::PageDown => {
+                let x = target_x(line_layout);
+                let center_y = line_layout.cursor_rect(ui, buffer, ccursor).center().y;
+                let y = if self == Self::PageUp {
+                    center_y - page_height
+                } else {
+                    center_y + page_height
+                };
+
+                if y < 0.0 {
+                    CCursor::default()
+                } else if y >= line_layout.total_height() {
+                    CCursor::new(buffer.len_chars())
+                } else {
+                    line_layout.cursor_from_pos(ui, buffer, vec2(x, y))
+                }
+            }
+            Self::DocumentStart => CCursor::default(),
+            Self::DocumentEnd => CCursor::new(buffer.len_chars()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn navigation_follows_the_platform() {
+        let ctrl = Modifiers::CTRL;
+        let mac_cmd = Modifiers::MAC_CMD | Modifiers::COMMAND;
+
+        assert_eq!(
+            Motion::from_key(Key::ArrowLeft, ctrl),
+            Some(Motion::WordLeft)
+        );
+        assert_eq!(
+            Motion::from_key(Key::ArrowLeft, Modifiers::ALT),
+            Some(Motion::WordLeft)
+        );
+        assert_eq!(
+            Motion::from_key(Key::ArrowLeft, mac_cmd),
+            Some(Motion::LineStart)
+        );
+        assert_eq!(
+            Motion::from_key(Key::Home, Modifiers::COMMAND | ctrl),
+            Some(Motion::DocumentStart)
+        );
+        assert_eq!(
+            Motion::from_key(Key::ArrowDown, mac_cmd),
+            Some(Motion::DocumentEnd)
+        );
+
+        // Shift extends the selection with the same motion.
+        assert_eq!(
+            Motion::from_key(Key::End, Modifiers::SHIFT),
+            Some(Motion::LineEnd)
+        );
+
+        // Adding cursors above and below.
+        assert_eq!(
+            Motion::from_key(Key::ArrowUp, Modifiers::COMMAND | Modifiers::ALT),
+            None
+        );
+    }
+}
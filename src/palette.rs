@@ -0,0 +1,163 @@
+use egui::{Key, Modifiers};
+
+use crate::command::Command;
+use crate::keymap::Keymap;
+
+/// Most rows shown at once before the list scrolls.
+const MAX_VISIBLE_ROWS: f32 = 12.0;
+
+/// An overlay listing every command, filtered by what's typed into it.
+#[derive(Default)]
+pub struct Palette {
+    query: String,
+
+    /// Index of the highlighted command among the ones matching `query`.
+    selected: usize,
+}
+
+/// How the palette was closed.
+pub enum Outcome {
+    Run(Command),
+    Cancel,
+}
+
+impl Palette {
+    /// The commands matching the query, best match first.
+    pub fn matches(&self) -> Vec<Command> {
+        let mut matches = Command::ALL
+            .iter()
+            .copied()
+            .filter(|&command| command != Command::ShowCommandPalette)
+            .filter_map(|command| {
+                fuzzy_score(&self.query, command.description()).map(|score| (command, score))
+            })
+            .collect::<Vec<_>>();
+
+        // Stable, so equally good matches stay in the order of `Command::ALL`.
+        matches.sort_by_key(|&(_, score)| std::cmp::Reverse(score));
+        matches.into_iter().map(|(command, _)| command).collect()
+    }
+
+    /// Shows the palette, returning how it was closed if it was.
+    pub fn show(&mut self, ctx: &egui::Context, keymap: &Keymap) -> Option<Outcome> {
+        let mut outcome = None;
+
+        egui::Window::new("Command palette")
+            .title_bar(false)
+            .resizable(false)
+            .anchor(egui::Align2::CENTER_TOP, egui::vec2(0.0, 32.0))
+            .show(ctx, |ui| {
+                // Take the keys for moving through the list before the text field sees them.
+                let (up, down, enter, escape) = ui.input_mut(|i| {
+                    (
+                        i.consume_key(Modifiers::NONE, Key::ArrowUp),
+                        i.consume_key(Modifiers::NONE, Key::ArrowDown),
+                        i.consume_key(Modifiers::NONE, Key::Enter),
+                        i.consume_key(Modifiers::NONE, Key::Escape),
+                    )
+                });
+
+                let response = ui.add(
+                    egui::TextEdit::singleline(&mut self.query)
+                        .hint_text("Type a command")
+                        .desired_width(400.0),
+                );
+                response.request_focus();
+                if response.changed() {
+                    self.selected = 0;
+                }
+
+                let matches = self.matches();
+                if down {
+                    self.selected = (self.selected + 1).min(matches.len().saturating_sub(1));
+                }
+                if up {
+                    self.selected = self.selected.saturating_sub(1);
+                }
+
+                let row_height = ui.spacing().interact_size.y;
+                egui::ScrollArea::vertical()
+                    .max_height(row_height * MAX_VISIBLE_ROWS)
+                    .show(ui, |ui| {
+                        for (i, &command) in matches.iter().enumerate() {
+                            let is_selected = i == self.selected;
+
+                            let row = ui.horizontal(|ui| {
+                                let label = ui.selectable_label(is_selected, command.description());
+                                if let Some(shortcut) = keymap.shortcut_text(ctx, command) {
+                                    ui.with_layout(
+                                        egui::Layout::right_to_left(egui::Align::Center),
+                                        |ui| ui.weak(shortcut),
+                                    );
+                                }
+                                label
+                            });
+
+                            if row.inner.clicked() {
+                                outcome = Some(Outcome::Run(command));
+                            }
+                            if is_selected && (up || down) {
+                                row.response.scroll_to_me(None);
+                            }
+                        }
+                    });
+
+                if enter {
+                    outcome = Some(match matches.get(self.selected) {
+                        Some(&command) => Outcome::Run(command),
+                        None => Outcome::Cancel,
+                    });
+                }
+                if escape {
+                    outcome = Some(Outcome::Cancel);
+                }
+            });
+
+        outcome
+    }
+}
+
+/// How well `query` matches `text`, or `None` if it doesn't.
+///
+/// Every character of the query, ignoring whitespace and case, has to appear in the text in the
+/// same order. Matches score higher when they start words or directly follow the previous match,
+/// so "sf" prefers "Save file" over "Selfie", and lower the further they are from it.
+pub fn fuzzy_score(query: &str, text: &str) -> Option<i32> {
+    let text = text.chars().collect::<Vec<_>>();
+
+    let mut score = 0;
+    let mut from = 0;
+    for q in query.chars().filter(|c| !c.is_whitespace()) {
+        let i = (from..text.len()).find(|&i| text[i].to_lowercase().eq(q.to_lowercase()))?;
+
+        // Skipping a few characters costs a little, but skipping many costs no more than that.
+        score += 1 - (i - from).min(3) as i32;
+        if i == 0 || !text[i - 1].is_alphanumeric() {
+            score += 3;
+        }
+        if i > 0 && i == from {
+            score += 5;
+        }
+
+        from = i + 1;
+    }
+
+    Some(score)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fuzzy_matching_prefers_word_starts_and_runs() {
+        assert!(fuzzy_score("", "Undo").is_some());
+        assert!(fuzzy_score("UNDO", "Undo the last edit").is_some());
+        assert!(fuzzy_score("sel all", "Select all").is_some());
+        assert!(fuzzy_score("redo", "Undo the last edit").is_none());
+        assert!(fuzzy_score("ba", "ab").is_none());
+
+        assert!(fuzzy_score("sf", "Save file") > fuzzy_score("sf", "Selfie"));
+        assert!(fuzzy_score("und", "Undo") > fuzzy_score("und", "Redo the last undone edit"));
+    }
+}
@@ -0,0 +1,211 @@
+use egui::text::{CCursor, CCursorRange};
+
+use crate::buffer::Buffer;
+
+/// The cursors in a buffer, each with its own selection.
+///
+/// There is always at least one. The selections are kept sorted by position, and any that overlap
+/// are merged, so an edit can be made at every selection in turn, from first to last, without the
+/// edits running into each other.
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct Selections {
+    ranges: Vec<CCursorRange>,
+
+    /// Index of the main selection in `ranges`, the one that was added last. The view follows it,
+    /// and it's the one extended by dragging the mouse.
+    main: usize,
+}
+
+impl Default for Selections {
+    fn default() -> Self {
+        Self::one(CCursorRange::default())
+    }
+}
+
+impl Selections {
+    pub fn one(range: CCursorRange) -> Self {
+        Self {
+            ranges: vec![range],
+            main: 0,
+        }
+    }
+
+    /// Selections from `ranges`, in any order, where `ranges[main]` is the main one.
+    pub fn new(ranges: Vec<CCursorRange>, main: usize) -> Self {
+        assert!(
+            main < ranges.len(),
+            "the main selection must be in `ranges`"
+        );
+
+        let mut selections = Self { ranges, main };
+        selections.normalize();
+        selections
+    }
+
+    /// The main selection.
+    pub fn main(&self) -> CCursorRange {
+        self.ranges[self.main]
+    }
+
+    pub fn main_index(&self) -> usize {
+        self.main
+    }
+
+    /// Every selection, sorted by position.
+    pub fn ranges(&self) -> &[CCursorRange] {
+        &self.ranges
+    }
+
+    pub fn len(&self) -> usize {
+        self.ranges.len()
+    }
+
+    /// Adds a selection, which becomes the main one.
+    pub fn add(&mut self, range: CCursorRange) {
+        self.ranges.push(range);
+        self.main = self.ranges.len() - 1;
+        self.normalize();
+    }
+
+    /// Replaces the main selection.
+    pub fn set_main(&mut self, range: CCursorRange) {
+        self.ranges[self.main] = range;
+        self.normalize();
+    }
+
+    /// Drops every selection except the main one.
+    pub fn collapse(&mut self) {
+        *self = Self::one(self.main());
+    }
+
+    /// Moves every selection with `f`.
+    pub fn map(&self, f: impl FnMut(CCursorRange) -> CCursorRange) -> Self {
+        Self::new(self.ranges.iter().copied().map(f).collect(), self.main)
+    }
+
+    /// Clamps every selection to the buffer.
+    pub fn clamp(&self, buffer: &Buffer) -> Self {
+        self.map(|range| CCursorRange {
+            primary: buffer.clamp(range.primary),
+            secondary: buffer.clamp(range.secondary),
+        })
+    }
+
+    /// Sorts the selections and merges the ones that overlap.
+    fn normalize(&mut self) {
+        let main = self.main;
+
+        let mut ranges = std::mem::take(&mut self.ranges)
+            .into_iter()
+            .enumerate()
+            .collect::<Vec<_>>();
+        ranges.sort_by_key(|(_, range)| {
+            let [min, max] = range.sorted();
+            (min.index, max.index)
+        });
+
+        for (i, range) in ranges {
+            let is_main = i == main;
+
+            if let Some(last) = self.ranges.last_mut() {
+                let [last_min, last_max] = last.sorted();
+                let [min, max] = range.sorted();
+
+                if min.index < last_max.index || min.index == last_min.index {
+                    // Keep the direction of the main selection if it's one of the two.
+                    let forward = if is_main {
+                        is_forward(&range)
+                    } else {
+                        is_forward(last)
+                    };
+                    let max = if max.index > last_max.index {
+                        max
+                    } else {
+                        last_max
+                    };
+                    *last = directed(last_min, max, forward);
+
+                    if is_main {
+                        self.main = self.ranges.len() - 1;
+                    }
+                    continue;
+                }
+            }
+
+            if is_main {
+                self.main = self.ranges.len();
+            }
+            self.ranges.push(range);
+        }
+    }
+}
+
+/// A rectangular selection: one selection on each line from `anchor` to `head`, all covering the
+/// same horizontal span.
+///
+/// Positions are a line and a horizontal position in points relative to the left of the text, so
+/// the block keeps its shape across short lines.
+#[derive(Clone, Copy, Debug)]
+pub struct Block {
+    /// The corner the block was started from.
+    pub anchor: (usize, f32),
+
+    /// The corner that moves when the block is extended.
+    pub head: (usize, f32),
+}
+
+/// Whether the cursor is at the end of the selection rather than at the start.
+fn is_forward(range: &CCursorRange) -> bool {
+    range.primary.index >= range.secondary.index
+}
+
+/// A selection from `min` to `max`, with the cursor at `max` if `forward`, and at `min` otherwise.
+fn directed(min: CCursor, max: CCursor, forward: bool) -> CCursorRange {
+    if forward {
+        CCursorRange::two(min, max)
+    } else {
+        CCursorRange::two(max, min)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(secondary: usize, primary: usize) -> CCursorRange {
+        CCursorRange::two(CCursor::new(secondary), CCursor::new(primary))
+    }
+
+    fn indices(selections: &Selections) -> Vec<(usize, usize)> {
+        selections
+            .ranges()
+            .iter()
+            .map(|range| (range.secondary.index, range.primary.index))
+            .collect()
+    }
+
+    #[test]
+    fn selections_are_sorted() {
+        let selections = Selections::new(vec![range(8, 9), range(0, 2), range(4, 4)], 0);
+        assert_eq!(indices(&selections), [(0, 2), (4, 4), (8, 9)]);
+        assert_eq!(selections.main_index(), 2);
+    }
+
+    #[test]
+    fn overlapping_selections_are_merged() {
+        let mut selections = Selections::new(vec![range(0, 4), range(6, 8)], 0);
+
+        // Touching isn't overlapping.
+        selections.add(range(4, 4));
+        assert_eq!(selections.len(), 3);
+
+        // The merged selection is the main one if either of them was, and keeps its direction.
+        selections.add(range(7, 2));
+        assert_eq!(indices(&selections), [(8, 0)]);
+        assert_eq!(selections.main_index(), 0);
+
+        let selections = Selections::new(vec![range(3, 3), range(3, 3), range(5, 5)], 1);
+        assert_eq!(indices(&selections), [(3, 3), (5, 5)]);
+        assert_eq!(selections.main_index(), 0);
+    }
+}
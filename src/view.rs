@@ -0,0 +1,547 @@
+use std::sync::Arc;
+
+use egui::text::{CCursor, LayoutJob, LayoutSection};
+use egui::{vec2, FontId, Galley, NumExt, Rect, Vec2};
+
+use crate::buffer::{Buffer, LineEdit};
+use crate::highlight::{self, HighlightState};
+
+/// How many lines above and below the viewport to lay out and paint, so small scrolls don't show
+/// empty space before the next frame catches up.
+pub const OVERSCAN: usize = 8;
+
+/// Heights and vertical offsets of every line in the buffer.
+///
+/// Only the lines inside the viewport are ever laid out, so lines that haven't been measured yet
+/// are assumed to be one row tall. That keeps the size of the scroll area, and with it the
+/// scrollbar, correct for the whole document without laying all of it out every frame.
+#[derive(Default)]
+struct LineHeights {
+    heights: Vec<f32>,
+
+    /// `offsets[i]` is the top of line `i`. Has one more entry than `heights`, so the last one is
+    /// the height of the whole document.
+    offsets: Vec<f32>,
+
+    /// Index of the first entry in `offsets` that needs recomputing.
+    stale_from: usize,
+
+    row_height: f32,
+    widest: f32,
+}
+
+impl LineHeights {
+    /// Starts over if the row height changed, e.g. because the font did.
+    fn set_row_height(&mut self, row_height: f32, len_lines: usize) {
+        if self.row_height != row_height {
+            self.row_height = row_height;
+            self.reset(len_lines);
+        }
+    }
+
+    fn apply(&mut self, edit: &LineEdit) {
+        self.heights.splice(
+            edit.line + 1..edit.line + 1 + edit.removed,
+            std::iter::repeat(self.row_height).take(edit.inserted),
+        );
+        self.stale_from = self.stale_from.min(edit.line + 1);
+    }
+
+    fn len(&self) -> usize {
+        self.heights.len()
+    }
+
+    fn reset(&mut self, len_lines: usize) {
+        self.heights = vec![self.row_height; len_lines];
+        self.offsets.clear();
+        self.stale_from = 0;
+        self.widest = 0.0;
+    }
+
+    /// Records the size of a line that has been laid out.
+    fn measure(&mut self, line: usize, size: Vec2) {
+        let height = size.y.at_least(self.row_height);
+        if self.heights[line] != height {
+            self.heights[line] = height;
+            self.stale_from = self.stale_from.min(line + 1);
+        }
+
+        self.widest = self.widest.max(size.x);
+    }
+
+    fn update_offsets(&mut self) {
+        if self.offsets.is_empty() {
+            self.offsets.push(0.0);
+        }
+
+        let valid = self.stale_from.clamp(1, self.offsets.len());
+        if valid == self.heights.len() + 1 {
+            return;
+        }
+
+        self.offsets.truncate(valid);
+        let mut top = self.offsets[valid - 1];
+        for height in &self.heights[valid - 1..] {
+            top += height;
+            self.offsets.push(top);
+        }
+
+        self.stale_from = usize::MAX;
+    }
+
+    /// The top of `line`, relative to the top of the first line.
+    fn top(&mut self, line: usize) -> f32 {
+        self.update_offsets();
+        self.offsets[line.min(self.heights.len())]
+    }
+
+    /// The height of the whole document.
+    fn total_height(&mut self) -> f32 {
+        self.update_offsets();
+        self.offsets.last().copied().unwrap_or_default()
+    }
+
+    /// The line at `y`, relative to the top of the first line.
+    fn line_at(&mut self, y: f32) -> usize {
+        self.update_offsets();
+        let line = self
+            .offsets
+            .partition_point(|&top| top <= y)
+            .saturating_sub(1);
+        line.min(self.heights.len().saturating_sub(1))
+    }
+
+    /// Width of the widest line laid out so far.
+    fn widest(&self) -> f32 {
+        self.widest
+    }
+}
+
+/// Drop galleys that weren't used in the last frame once there are more than this many.
+const MAX_CACHED_GALLEYS: usize = 1024;
+
+/// Per-line layout of the buffer, kept across frames.
+///
+/// Every line is highlighted and laid out on its own, and the resulting galleys are cached until
+/// the line changes. The highlighter state at the start of each line is tracked as well, so an
+/// edit only re-highlights the lines that changed, plus any following lines whose start state
+/// changed because of it.
+#[derive(Default)]
+pub struct LineLayout {
+    heights: LineHeights,
+    lines: Vec<CachedLine>,
+
+    /// The highlighter state at the start of each line, known for the first `starts.len()` lines.
+    starts: Vec<HighlightState>,
+
+    in_dark_mode: bool,
+    pixels_per_point: f32,
+
+    /// Columns a tab is shown as.
+    tab_width: usize,
+    frame: u64,
+    cached_galleys: usize,
+}
+
+#[derive(Default)]
+struct CachedLine {
+    /// The highlighter state at the start and end of the line, as of when it was last
+    /// highlighted. `None` if the line has changed since.
+    states: Option<(HighlightState, HighlightState)>,
+
+    galley: Option<CachedGalley>,
+}
+
+struct CachedGalley {
+    /// The highlighter state the galley was laid out with.
+    start: HighlightState,
+    galley: Arc<Galley>,
+    last_used: u64,
+}
+
+impl LineLayout {
+    /// Prepares the layout for a new frame, and catches up with any edits made to `buffer`.
+    pub fn begin_frame(
+        &mut self,
+        ui: &egui::Ui,
+        buffer: &mut Buffer,
+        row_height: f32,
+        tab_width: usize,
+    ) {
+        self.frame += 1;
+
+        let in_dark_mode = ui.visuals().dark_mode;
+        let pixels_per_point = ui.ctx().pixels_per_point();
+        if self.in_dark_mode != in_dark_mode
+            || self.pixels_per_point != pixels_per_point
+            || self.tab_width != tab_width
+        {
+            self.in_dark_mode = in_dark_mode;
+            self.pixels_per_point = pixels_per_point;
+            self.tab_width = tab_width;
+            self.clear_galleys(|_| true);
+        } else if self.cached_galleys > MAX_CACHED_GALLEYS {
+            let frame = self.frame;
+            self.clear_galleys(|cached| cached.last_used + 1 < frame);
+        }
+
+        self.heights.set_row_height(row_height, self.lines.len());
+        self.sync(buffer);
+    }
+
+    /// Applies the edits made to `buffer` since the last sync.
+    pub fn sync(&mut self, buffer: &mut Buffer) {
+        for edit in buffer.take_line_edits() {
+            let removed = edit.line + 1..edit.line + 1 + edit.removed;
+            if removed.end > self.lines.len() {
+                // The edit is to lines we don't know about, so we've lost track of the buffer,
+                // and the edits after it can't be applied either.
+                self.reset(buffer.len_lines());
+                return;
+            }
+
+            self.heights.apply(&edit);
+
+            let removed_galleys = self
+                .lines
+                .splice(
+                    removed,
+                    std::iter::repeat_with(CachedLine::default).take(edit.inserted),
+                )
+                .filter(|line| line.galley.is_some())
+                .count();
+            self.cached_galleys -= removed_galleys;
+
+            let changed_line = &mut self.lines[edit.line];
+            changed_line.states = None;
+            if changed_line.galley.take().is_some() {
+                self.cached_galleys -= 1;
+            }
+
+            self.starts.truncate(edit.line + 1);
+        }
+
+        // Start over if we've lost track of the buffer, e.g. because a new file was opened.
+        if self.lines.len() != buffer.len_lines() || self.heights.len() != buffer.len_lines() {
+            self.reset(buffer.len_lines());
+        }
+    }
+
+    /// Forgets everything about the lines, for a buffer with `len_lines` lines.
+    fn reset(&mut self, len_lines: usize) {
+        self.lines = std::iter::repeat_with(CachedLine::default)
+            .take(len_lines)
+            .collect();
+        self.starts.clear();
+        self.cached_galleys = 0;
+        self.heights.reset(len_lines);
+    }
+
+    fn clear_galleys(&mut self, should_clear: impl Fn(&CachedGalley) -> bool) {
+        for line in &mut self.lines {
+            if line.galley.as_ref().is_some_and(&should_clear) {
+                line.galley = None;
+                self.cached_galleys -= 1;
+            }
+        }
+    }
+
+    /// The highlighter state at the start of `line`.
+    fn start_state(&mut self, buffer: &Buffer, line: usize) -> HighlightState {
+        if self.starts.is_empty() {
+            self.starts.push(HighlightState::default());
+        }
+
+        while self.starts.len() <= line {
+            let previous = self.starts.len() - 1;
+            let start = self.starts[previous];
+
+            let end = match self.lines[previous].states {
+                Some((cached_start, end)) if cached_start == start => end,
+                _ => {
+                    let end = highlight::end_state(&buffer.line_text(previous), start);
+                    self.lines[previous].states = Some((start, end));
+                    end
+                }
+            };
+
+            self.starts.push(end);
+        }
+
+        self.starts[line]
+    }
+
+    /// The laid out `line` of the buffer, without its trailing newline.
+    pub fn galley(&mut self, ui: &egui::Ui, buffer: &Buffer, line: usize) -> Arc<Galley> {
+        let start = self.start_state(buffer, line);
+        let frame = self.frame;
+
+        if let Some(cached) = &mut self.lines[line].galley {
+            if cached.start == start {
+                cached.last_used = frame;
+                return cached.galley.clone();
+            }
+        }
+
+        let (mut layout_job, end) =
+            highlight::highlight_line(&buffer.line_text(line), start, self.in_dark_mode);
+        let space_width = ui.fonts(|f| f.glyph_width(&FontId::monospace(14.0), ' '));
+        align_tabs(&mut layout_job, self.tab_width.max(1), space_width);
+        let galley = ui.fonts(|f| f.layout_job(layout_job));
+
+        self.heights.measure(line, galley.size());
+
+        let cached_line = &mut self.lines[line];
+        cached_line.states = Some((start, end));
+        if cached_line.galley.is_none() {
+            self.cached_galleys += 1;
+        }
+        cached_line.galley = Some(CachedGalley {
+            start,
+            galley: galley.clone(),
+            last_used: frame,
+        });
+
+        galley
+    }
+
+    /// The top of `line`, relative to the top of the first line.
+    pub fn top(&mut self, line: usize) -> f32 {
+        self.heights.top(line)
+    }
+
+    /// The height of the whole document.
+    pub fn total_height(&mut self) -> f32 {
+        self.heights.total_height()
+    }
+
+    /// The line at `y`, relative to the top of the first line.
+    pub fn line_at(&mut self, y: f32) -> usize {
+        self.heights.line_at(y)
+    }
+
+    /// Width of the widest line laid out so far.
+    pub fn widest(&self) -> f32 {
+        self.heights.widest()
+    }
+
+    /// The rectangle of the cursor, relative to the top left of the text.
+    pub fn cursor_rect(&mut self, ui: &egui::Ui, buffer: &Buffer, ccursor: CCursor) -> Rect {
+        let ccursor = buffer.clamp(ccursor);
+        let line = buffer.char_to_line(ccursor.index);
+        let column = ccursor.index - buffer.line_to_char(line);
+
+        self.galley(ui, buffer, line)
+            .pos_from_ccursor(CCursor::new(column))
+            .translate(vec2(0.0, self.top(line)))
+    }
+
+    /// The cursor closest to `pos`, relative to the top left of the text.
+    pub fn cursor_from_pos(&mut self, ui: &egui::Ui, buffer: &Buffer, pos: Vec2) -> CCursor {
+        let line = self.line_at(pos.y);
+        self.cursor_in_line_at_x(ui, buffer, line, pos.x)
+    }
+
+    /// Moves the cursor to the line above, keeping its horizontal position.
+    pub fn cursor_up_one_line(
+        &mut self,
+        ui: &egui::Ui,
+        buffer: &Buffer,
+        ccursor: CCursor,
+    ) -> CCursor {
+        let line = buffer.char_to_line(ccursor.index);
+        if line == 0 {
+            CCursor::default()
+        } else {
+            let x = self.cursor_rect(ui, buffer, ccursor).center().x;
+            self.cursor_in_line_at_x(ui, buffer, line - 1, x)
+        }
+    }
+
+    /// Moves the cursor to the line below, keeping its horizontal position.
+    pub fn cursor_down_one_line(
+        &mut self,
+        ui: &egui::Ui,
+        buffer: &Buffer,
+        ccursor: CCursor,
+    ) -> CCursor {
+        let line = buffer.char_to_line(ccursor.index);
+        if line + 1 >= buffer.len_lines() {
+            CCursor::new(buffer.len_chars())
+        } else {
+            let x = self.cursor_rect(ui, buffer, ccursor).center().x;
+            self.cursor_in_line_at_x(ui, buffer, line + 1, x)
+        }
+    }
+
+    /// The cursor in `line` closest to `x`, relative to the left of the text.
+    pub fn cursor_in_line_at_x(
+        &mut self,
+        ui: &egui::Ui,
+        buffer: &Buffer,
+        line: usize,
+        x: f32,
+    ) -> CCursor {
+        let galley = self.galley(ui, buffer, line);
+        let column = galley
+            .cursor_from_pos(vec2(x, galley.size().y / 2.0))
+            .ccursor
+            .index;
+
+        CCursor::new(buffer.line_to_char(line) + column)
+    }
+}
+
+/// Makes every tab in `job` reach the next multiple of `tab_width` columns.
+///
+/// egui draws every tab as wide as `epaint::text::TAB_SIZE` spaces, so the text after each tab is
+/// given a section of its own, moved over by the difference.
+fn align_tabs(job: &mut LayoutJob, tab_width: usize, space_width: f32) {
+    if !job.text.contains('\t') {
+        return;
+    }
+
+    let mut sections = Vec::with_capacity(job.sections.len());
+    let mut column = 0;
+
+    // Space to add before the next section, left over from a tab at the end of the last one.
+    let mut carry = 0.0;
+
+    for section in std::mem::take(&mut job.sections) {
+        let mut start = section.byte_range.start;
+        let mut leading_space = carry + section.leading_space;
+
+        for (i, c) in job.text[section.byte_range.clone()].char_indices() {
+            if c != '\t' {
+                column += 1;
+                continue;
+            }
+
+            let end = section.byte_range.start + i + 1;
+            sections.push(LayoutSection {
+                leading_space,
+                byte_range: start..end,
+                format: section.format.clone(),
+            });
+
+            let width = tab_width - column % tab_width;
+            column += width;
+            leading_space = (width as f32 - epaint::text::TAB_SIZE as f32) * space_width;
+            start = end;
+        }
+
+        if start < section.byte_range.end {
+            sections.push(LayoutSection {
+                leading_space,
+                byte_range: start..section.byte_range.end,
+                format: section.format,
+            });
+            carry = 0.0;
+        } else {
+            carry = leading_space;
+        }
+    }
+
+    job.sections = sections;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn line_heights_follow_inserts_and_deletes() {
+        let mut heights = LineHeights {
+            row_height: 10.0,
+            ..Default::default()
+        };
+        heights.reset(3);
+        heights.measure(1, vec2(0.0, 30.0));
+
+        assert_eq!(heights.top(2), 40.0);
+        assert_eq!(heights.total_height(), 50.0);
+        assert_eq!(heights.line_at(15.0), 1);
+        assert_eq!(heights.line_at(45.0), 2);
+        assert_eq!(heights.line_at(1000.0), 2);
+
+        // Two lines inserted after the first one, which pushes the tall line down.
+        heights.apply(&LineEdit {
+            line: 0,
+            removed: 0,
+            inserted: 2,
+        });
+        assert_eq!(heights.len(), 5);
+        assert_eq!(heights.top(4), 60.0);
+        assert_eq!(heights.line_at(35.0), 3);
+
+        heights.apply(&LineEdit {
+            line: 0,
+            removed: 3,
+            inserted: 0,
+        });
+        assert_eq!(heights.len(), 2);
+        assert_eq!(heights.total_height(), 20.0);
+        assert_eq!(heights.line_at(15.0), 1);
+    }
+
+    #[test]
+    fn sync_only_invalidates_from_the_edited_line() {
+        let mut buffer = Buffer::new("a b\nc\nd e\nf");
+        let mut layout = LineLayout::default();
+        layout.sync(&mut buffer);
+        layout.start_state(&buffer, 3);
+        assert_eq!(layout.starts.len(), 4);
+
+        buffer.insert_text_at(&mut CCursor::new(buffer.line_to_char(2)), "x");
+        layout.sync(&mut buffer);
+
+        let highlighted = layout
+            .lines
+            .iter()
+            .map(|line| line.states.is_some())
+            .collect::<Vec<_>>();
+        assert_eq!(highlighted, [true, true, false, false]);
+        assert_eq!(layout.starts.len(), 3);
+
+        // Lines added or removed after the edited one keep the lines before them.
+        buffer.insert_text_at(&mut CCursor::new(buffer.line_to_char(3)), "g\n");
+        layout.sync(&mut buffer);
+        assert_eq!(layout.lines.len(), 5);
+        assert!(layout.lines[1].states.is_some());
+        assert_eq!(layout.starts.len(), 3);
+    }
+
+    #[test]
+    fn highlight_state_changes_carry_forward() {
+        let mut buffer = Buffer::new("a\nb\nc c\nd");
+        let mut layout = LineLayout::default();
+        layout.sync(&mut buffer);
+
+        let even = HighlightState::default();
+        let odd = highlight::end_state(" ", even);
+        assert_eq!(layout.start_state(&buffer, 1), even);
+        assert_eq!(layout.start_state(&buffer, 3), odd);
+
+        // A space in the first line flips the state at the start of every line after it.
+        buffer.insert_text_at(&mut CCursor::new(1), " ");
+        layout.sync(&mut buffer);
+        assert_eq!(layout.start_state(&buffer, 1), odd);
+        assert_eq!(layout.start_state(&buffer, 2), odd);
+        assert_eq!(layout.start_state(&buffer, 3), even);
+    }
+
+    #[test]
+    fn edits_to_unknown_lines_start_over() {
+        let mut buffer = Buffer::new("a\nb");
+        let mut layout = LineLayout::default();
+        layout.sync(&mut buffer);
+        layout.start_state(&buffer, 1);
+
+        // Lines the layout never saw, as if the buffer had been replaced without it knowing.
+        buffer = Buffer::new("a\nb\nc\nd");
+        buffer.delete_char_range(2..6);
+        layout.sync(&mut buffer);
+        assert_eq!(layout.lines.len(), buffer.len_lines());
+        assert!(layout.starts.is_empty());
+    }
+}
@@ -0,0 +1,1457 @@
+use std::collections::HashMap;
+use std::ops::Range;
+
+use egui::text::{CCursor, CCursorRange};
+use egui::{Event, Key};
+
+use crate::buffer::Buffer;
+use crate::command::Command;
+use crate::indent::{self, Indentation};
+use crate::language::Language;
+
+/// What keys do in Vim mode.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Mode {
+    /// Keys are commands.
+    #[default]
+    Normal,
+
+    /// Keys type text, like they do outside of Vim mode.
+    Insert,
+
+    /// Keys are commands, and motions extend a selection of characters.
+    Visual,
+
+    /// Keys are commands, and motions extend a selection of whole lines.
+    VisualLine,
+}
+
+/// A key as far as Vim mode is concerned.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VimKey {
+    Char(char),
+    Escape,
+    Enter,
+    Backspace,
+
+    /// Ctrl+R, which redoes.
+    Redo,
+}
+
+impl VimKey {
+    /// The key `event` presses, if it's one Vim mode knows. The arrow keys move like h, j, k and l.
+    pub fn from_event(event: &Event) -> Option<Self> {
+        match event {
+            Event::Text(text) => {
+                let mut chars = text.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) => Some(Self::Char(c)),
+                    _ => None,
+                }
+            }
+            Event::Key {
+                key,
+                pressed: true,
+                modifiers,
+                ..
+            } => match key {
+                Key::Escape => Some(Self::Escape),
+                Key::Enter => Some(Self::Enter),
+                Key::Backspace => Some(Self::Backspace),
+                Key::R if modifiers.ctrl => Some(Self::Redo),
+                Key::ArrowLeft => Some(Self::Char('h')),
+                Key::ArrowDown => Some(Self::Char('j')),
+                Key::ArrowUp => Some(Self::Char('k')),
+                Key::ArrowRight => Some(Self::Char('l')),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+/// Something the editor has to do for a key pressed in Vim mode.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Action {
+    /// Replaces the selections with this one.
+    Select(CCursorRange),
+
+    /// Replaces `range` with `text`.
+    Edit {
+        range: Range<usize>,
+        text: String,
+    },
+
+    Undo,
+    Redo,
+
+    /// Starts a change, like a command and the text typed in insert mode after it, whose edits
+    /// are undone in one go.
+    StartChange,
+
+    /// Ends the change that was started, once the command is done and insert mode has been left.
+    EndChange,
+
+    /// Copies the text to the system clipboard.
+    Copy(String),
+
+    Run(Command),
+}
+
+/// Text yanked or deleted into a register.
+#[derive(Clone, Debug, Default)]
+struct Register {
+    text: String,
+
+    /// Whether the text is whole lines, which are put on lines of their own.
+    linewise: bool,
+}
+
+/// How a motion covers text when an operator is applied with it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    /// Up to the target, but not including it.
+    Exclusive,
+
+    /// Up to and including the target.
+    Inclusive,
+
+    /// Every line from the cursor's to the target's.
+    Linewise,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum VimMotion {
+    Left,
+    Right,
+    Up,
+    Down,
+    WordForward,
+    WordBackward,
+    WordEnd,
+    LineStart,
+    FirstNonBlank,
+    LineEnd,
+    FileStart,
+    FileEnd,
+
+    /// To the next `char` on the line, or just before it if `till`.
+    FindForward(char, bool),
+
+    /// To the previous `char` on the line, or just after it if `till`.
+    FindBackward(char, bool),
+}
+
+impl VimMotion {
+    fn is_vertical(self) -> bool {
+        matches!(self, Self::Up | Self::Down)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Operator {
+    Delete,
+    Change,
+    Yank,
+}
+
+/// The text an operator applies to.
+enum Region {
+    Chars(Range<usize>),
+
+    /// The first and last line.
+    Lines(usize, usize),
+}
+
+/// A command typed in normal or visual mode.
+struct Parsed {
+    register: Option<char>,
+    count: Option<usize>,
+    op: Op,
+}
+
+enum Op {
+    Move(VimMotion),
+
+    /// An operator with the count typed after it, and its motion, or `None` if the operator was
+    /// doubled to apply it to whole lines, like `dd`.
+    Operate(Operator, Option<usize>, Option<VimMotion>),
+
+    /// `r` and the character to replace with.
+    Replace(char),
+    Redo,
+
+    /// Any other command, which is a single key.
+    Key(char),
+}
+
+/// Why keys aren't a command.
+enum Stop {
+    /// They could be the start of one.
+    Incomplete,
+    Invalid,
+}
+
+/// Keys typed in normal or visual mode, read one at a time.
+struct Keys<'a> {
+    keys: &'a [VimKey],
+    pos: usize,
+}
+
+impl Keys<'_> {
+    fn next(&mut self) -> Result<VimKey, Stop> {
+        let key = *self.keys.get(self.pos).ok_or(Stop::Incomplete)?;
+        self.pos += 1;
+        Ok(key)
+    }
+
+    fn char(&mut self) -> Result<char, Stop> {
+        match self.next()? {
+            VimKey::Char(c) => Ok(c),
+            _ => Err(Stop::Invalid),
+        }
+    }
+
+    fn peek(&self) -> Option<VimKey> {
+        self.keys.get(self.pos).copied()
+    }
+
+    /// A count, which can't start with 0 since that's a motion.
+    fn count(&mut self) -> Option<usize> {
+        let mut count = None;
+        while let Some(VimKey::Char(c)) = self.peek() {
+            match c.to_digit(10) {
+                Some(0) if count.is_none() => break,
+                Some(digit) => {
+                    count = Some(count.unwrap_or(0usize).saturating_mul(10) + digit as usize);
+                    self.pos += 1;
+                }
+                None => break,
+            }
+        }
+        count
+    }
+
+    /// The motion starting with `c`, or `None` if `c` doesn't start one.
+    fn motion(&mut self, c: char) -> Result<Option<VimMotion>, Stop> {
+        let motion = match c {
+            'h' => VimMotion::Left,
+            'l' | ' ' => VimMotion::Right,
+            'j' => VimMotion::Down,
+            'k' => VimMotion::Up,
+            'w' => VimMotion::WordForward,
+            'b' => VimMotion::WordBackward,
+            'e' => VimMotion::WordEnd,
+            '0' => VimMotion::LineStart,
+            '^' => VimMotion::FirstNonBlank,
+            '$' => VimMotion::LineEnd,
+            'G' => VimMotion::FileEnd,
+            'g' if self.char()? == 'g' => VimMotion::FileStart,
+            'g' => return Err(Stop::Invalid),
+            'f' => VimMotion::FindForward(self.char()?, false),
+            't' => VimMotion::FindForward(self.char()?, true),
+            'F' => VimMotion::FindBackward(self.char()?, false),
+            'T' => VimMotion::FindBackward(self.char()?, true),
+            _ => return Ok(None),
+        };
+        Ok(Some(motion))
+    }
+}
+
+/// Reads `keys` as a command. In visual mode, operators apply to the selection right away
+/// instead of waiting for a motion.
+fn parse(keys: &[VimKey], visual: bool) -> Result<Parsed, Stop> {
+    let mut keys = Keys { keys, pos: 0 };
+
+    let register = if keys.peek() == Some(VimKey::Char('"')) {
+        keys.next()?;
+        Some(keys.char()?)
+    } else {
+        None
+    };
+    let count = keys.count();
+
+    let op = match keys.next()? {
+        VimKey::Redo => Op::Redo,
+        VimKey::Char(c @ ('d' | 'c' | 'y')) if !visual => {
+            let operator = match c {
+                'd' => Operator::Delete,
+                'c' => Operator::Change,
+                _ => Operator::Yank,
+            };
+            let motion_count = keys.count();
+            let next = keys.char()?;
+            if next == c {
+                Op::Operate(operator, motion_count, None)
+            } else {
+                let motion = keys.motion(next)?.ok_or(Stop::Invalid)?;
+                Op::Operate(operator, motion_count, Some(motion))
+            }
+        }
+        VimKey::Char('r') if !visual => Op::Replace(keys.char()?),
+        VimKey::Char(c) => match keys.motion(c)? {
+            Some(motion) => Op::Move(motion),
+            None if "xXDCsSYpPiaIAoOuvV.:Jdcy".contains(c) => Op::Key(c),
+            None => return Err(Stop::Invalid),
+        },
+        _ => return Err(Stop::Invalid),
+    };
+
+    Ok(Parsed {
+        register,
+        count,
+        op,
+    })
+}
+
+/// The state of Vim mode.
+///
+/// Keys are handled against a copy of the buffer, so a command can look at the text as of after
+/// the edits it has made so far, and `.` can replay a change key by key.
+#[derive(Default)]
+pub struct Vim {
+    mode: Mode,
+
+    /// Keys of the command being typed in normal or visual mode.
+    pending: Vec<VimKey>,
+
+    /// What's been typed after `:`, while a command line is being typed.
+    command_line: Option<String>,
+
+    /// Why the last command line failed.
+    message: Option<String>,
+
+    registers: HashMap<char, Register>,
+
+    /// Where the visual selection started, and where its end with the cursor is.
+    visual: (usize, usize),
+
+    /// The column moving up and down keeps the cursor at, if it's been moving up and down.
+    column: Option<usize>,
+
+    /// The keys of the change being made, and of the last one made, which `.` repeats.
+    recording: Vec<VimKey>,
+    last_change: Vec<VimKey>,
+
+    /// Whether `.` is replaying keys.
+    replaying: bool,
+
+    /// What the file is written in and how it's indented, which new lines follow.
+    language: Language,
+    indentation: Indentation,
+}
+
+impl Vim {
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// Goes back to normal mode, e.g. after the selection was changed with the mouse.
+    pub fn reset(&mut self) {
+        if self.mode != Mode::Insert {
+            self.mode = Mode::Normal;
+        }
+        self.pending.clear();
+    }
+
+    /// A line about the mode, or the command line while it's being typed.
+    pub fn status(&self) -> String {
+        if let Some(command_line) = &self.command_line {
+            return format!(":{command_line}");
+        }
+
+        let mut status = match self.mode {
+            Mode::Normal => "-- NORMAL --",
+            Mode::Insert => "-- INSERT --",
+            Mode::Visual => "-- VISUAL --",
+            Mode::VisualLine => "-- VISUAL LINE --",
+        }
+        .to_owned();
+
+        if !self.pending.is_empty() {
+            status.push(' ');
+            status.extend(self.pending.iter().filter_map(|key| match key {
+                VimKey::Char(c) => Some(*c),
+                _ => None,
+            }));
+        }
+        if let Some(message) = &self.message {
+            status.push_str("  ");
+            status.push_str(message);
+        }
+        status
+    }
+
+    /// Sets what the `+` and `*` registers put to `text`, which is on the system clipboard, e.g.
+    /// after being copied or pasted. Text ending in a newline is put as whole lines.
+    pub fn set_clipboard(&mut self, text: &str) {
+        let text = text.replace("\r\n", "\n");
+        let register = Register {
+            linewise: text.ends_with('\n'),
+            text,
+        };
+        self.registers.insert('+', register.clone());
+        self.registers.insert('*', register);
+    }
+
+    /// Records what `event` typed in insert mode, which the editor handles as it would outside of
+    /// Vim mode, so `.` can type it again.
+    pub fn record(&mut self, event: &Event) {
+        if self.mode != Mode::Insert {
+            return;
+        }
+
+        match event {
+            Event::Text(text) | Event::Paste(text) => {
+                self.recording.extend(text.chars().map(VimKey::Char));
+            }
+            Event::Key {
+                key,
+                pressed: true,
+                modifiers,
+                ..
+            } if modifiers.is_none() => match key {
+                Key::Enter => self.recording.push(VimKey::Enter),
+                Key::Backspace => self.recording.push(VimKey::Backspace),
+                Key::Tab => self.recording.push(VimKey::Char('\t')),
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+
+    /// Handles `key` with the cursor at `ccursor`, returning what the editor has to do for it.
+    /// New lines are indented like Enter indents them in `language` with `indentation`.
+    pub fn handle(
+        &mut self,
+        key: VimKey,
+        buffer: &Buffer,
+        ccursor: CCursor,
+        language: Language,
+        indentation: Indentation,
+    ) -> Vec<Action> {
+        self.language = language;
+        self.indentation = indentation;
+
+        let mut buffer = buffer.clone();
+        let mut cursor = ccursor.index.min(buffer.len_chars());
+        let mut actions = Vec::new();
+
+        self.message = None;
+        let was_inserting = self.mode == Mode::Insert;
+        self.handle_key(key, &mut buffer, &mut cursor, &mut actions);
+
+        // Insert mode is part of the change that entered it, so that ends when it's left.
+        let edited = actions
+            .iter()
+            .any(|action| matches!(action, Action::Edit { .. }));
+        let inserting = self.mode == Mode::Insert;
+        if !was_inserting && (edited || inserting) {
+            actions.insert(0, Action::StartChange);
+        }
+        if !inserting && (edited || was_inserting) {
+            actions.push(Action::EndChange);
+        }
+        actions
+    }
+
+    fn handle_key(
+        &mut self,
+        key: VimKey,
+        buffer: &mut Buffer,
+        cursor: &mut usize,
+        actions: &mut Vec<Action>,
+    ) {
+        if self.command_line.is_some() {
+            self.command_line_key(key, buffer, cursor, actions);
+            return;
+        }
+
+        if self.mode == Mode::Insert {
+            self.insert_key(key, buffer, cursor, actions);
+            return;
+        }
+
+        let visual = self.mode != Mode::Normal;
+        if key == VimKey::Escape {
+            self.pending.clear();
+            if visual {
+                self.mode = Mode::Normal;
+                *cursor = self.visual.1;
+            }
+            *cursor = clamp_normal(buffer, *cursor);
+            actions.push(select(*cursor));
+            return;
+        }
+
+        if !visual && !self.replaying {
+            if self.pending.is_empty() {
+                self.recording.clear();
+            }
+            self.recording.push(key);
+        }
+        self.pending.push(key);
+
+        let parsed = match parse(&self.pending, visual) {
+            Ok(parsed) => parsed,
+            Err(Stop::Incomplete) => return,
+            Err(Stop::Invalid) => {
+                self.pending.clear();
+                return;
+            }
+        };
+        self.pending.clear();
+
+        if visual {
+            self.execute_visual(parsed, buffer, cursor, actions);
+        } else if self.execute_normal(parsed, buffer, cursor, actions) && !self.replaying {
+            // Changes that go on in insert mode are done when it's left.
+            if self.mode == Mode::Normal {
+                self.last_change = self.recording.clone();
+            }
+        }
+    }
+
+    fn insert_key(
+        &mut self,
+        key: VimKey,
+        buffer: &mut Buffer,
+        cursor: &mut usize,
+        actions: &mut Vec<Action>,
+    ) {
+        // Keys typed into the editor are recorded by `record`, but replayed ones come through here.
+        if !self.replaying && matches!(key, VimKey::Char(_) | VimKey::Enter | VimKey::Backspace) {
+            self.recording.push(key);
+        }
+
+        match key {
+            VimKey::Char(c) => {
+                edit(buffer, *cursor..*cursor, &c.to_string(), actions);
+                *cursor += 1;
+            }
+            VimKey::Enter => *cursor = self.newline(buffer, *cursor, actions),
+            VimKey::Backspace if *cursor > 0 => {
+                edit(buffer, *cursor - 1..*cursor, "", actions);
+                *cursor -= 1;
+            }
+            VimKey::Escape => {
+                self.mode = Mode::Normal;
+                if !self.replaying {
+                    self.recording.push(VimKey::Escape);
+                    self.last_change = self.recording.clone();
+                }
+
+                // The cursor goes back onto the last character typed.
+                let line = buffer.char_to_line(*cursor);
+                if *cursor > buffer.line_to_char(line) {
+                    *cursor -= 1;
+                }
+                *cursor = clamp_normal(buffer, *cursor);
+            }
+            VimKey::Backspace | VimKey::Redo => return,
+        }
+        actions.push(select(*cursor));
+    }
+
+    fn command_line_key(
+        &mut self,
+        key: VimKey,
+        buffer: &mut Buffer,
+        cursor: &mut usize,
+        actions: &mut Vec<Action>,
+    ) {
+        let Some(command_line) = &mut self.command_line else {
+            return;
+        };
+
+        match key {
+            VimKey::Char(c) => command_line.push(c),
+            VimKey::Backspace => {
+                if command_line.pop().is_none() {
+                    self.command_line = None;
+                }
+            }
+            VimKey::Escape | VimKey::Redo => self.command_line = None,
+            VimKey::Enter => {
+                let command_line = self.command_line.take().unwrap_or_default();
+                match command_line.trim() {
+                    "w" => actions.push(Action::Run(Command::Save)),
+                    "q" | "q!" => actions.push(Action::Run(Command::Quit)),
+                    "wq" | "x" => {
+                        actions.push(Action::Run(Command::Save));
+                        actions.push(Action::Run(Command::Quit));
+                    }
+                    other => match other.parse::<usize>() {
+                        Ok(line) => {
+                            let line = line.saturating_sub(1).min(buffer.len_lines() - 1);
+                            *cursor = first_non_blank(buffer, line);
+                            actions.push(select(*cursor));
+                        }
+                        Err(_) => {
+                            self.message = Some(format!("Not an editor command: {other}"));
+                        }
+                    },
+                }
+            }
+        }
+    }
+
+    /// Runs a command in normal mode, returning whether it changed the text.
+    fn execute_normal(
+        &mut self,
+        parsed: Parsed,
+        buffer: &mut Buffer,
+        cursor: &mut usize,
+        actions: &mut Vec<Action>,
+    ) -> bool {
+        let Parsed {
+            register,
+            count: typed_count,
+            op,
+        } = parsed;
+        let count = typed_count.unwrap_or(1);
+
+        if !matches!(op, Op::Move(motion) if motion.is_vertical()) {
+            self.column = None;
+        }
+
+        let line = buffer.char_to_line(*cursor);
+        let line_range = buffer.line_char_range(line);
+
+        let changed = match op {
+            Op::Move(motion) => {
+                if let Some((target, _)) = self.motion(buffer, *cursor, motion, typed_count) {
+                    *cursor = clamp_normal(buffer, target);
+                }
+                false
+            }
+            Op::Operate(operator, motion_count, motion) => {
+                // Counts before and after the operator multiply.
+                let motion_count = match (typed_count, motion_count) {
+                    (None, None) => None,
+                    _ => Some(count * motion_count.unwrap_or(1)),
+                };
+                let count = motion_count.unwrap_or(1);
+                let region = match motion {
+                    Some(motion) => {
+                        self.motion_region(buffer, *cursor, operator, motion, motion_count)
+                    }
+                    None => Some(Region::Lines(
+                        line,
+                        (line + count - 1).min(buffer.len_lines() - 1),
+                    )),
+                };
+                match region {
+                    Some(region) => {
+                        self.operate(buffer, cursor, operator, region, register, actions);
+                        operator != Operator::Yank
+                    }
+                    None => false,
+                }
+            }
+            Op::Replace(c) => {
+                let end = *cursor + count;
+                if end > line_range.end {
+                    return false;
+                }
+                edit(buffer, *cursor..end, &c.to_string().repeat(count), actions);
+                *cursor = end - 1;
+                true
+            }
+            Op::Redo => {
+                actions.extend(std::iter::repeat(Action::Redo).take(count));
+                return false;
+            }
+            Op::Key(c) => match c {
+                'x' | 'X' | 'D' | 'C' | 's' | 'S' | 'Y' => {
+                    let (operator, motion) = match c {
+                        'x' => (Operator::Delete, Some(VimMotion::Right)),
+                        'X' => (Operator::Delete, Some(VimMotion::Left)),
+                        'D' => (Operator::Delete, Some(VimMotion::LineEnd)),
+                        'C' => (Operator::Change, Some(VimMotion::LineEnd)),
+                        's' => (Operator::Change, Some(VimMotion::Right)),
+                        'S' => (Operator::Change, None),
+                        _ => (Operator::Yank, None),
+                    };
+                    return self.execute_normal(
+                        Parsed {
+                            register,
+                            count: None,
+                            op: Op::Operate(operator, typed_count, motion),
+                        },
+                        buffer,
+                        cursor,
+                        actions,
+                    );
+                }
+                'p' | 'P' => self.put(buffer, cursor, register, c == 'P', count, actions),
+                'i' | 'a' | 'I' | 'A' => {
+                    *cursor = match c {
+                        'i' => *cursor,
+                        'a' => (*cursor + 1).min(line_range.end),
+                        'I' => first_non_blank(buffer, line),
+                        _ => line_range.end,
+                    };
+                    self.mode = Mode::Insert;
+                    true
+                }
+                'o' => {
+                    *cursor = self.newline(buffer, line_range.end, actions);
+                    self.mode = Mode::Insert;
+                    true
+                }
+                'O' => {
+                    *cursor = if line > 0 {
+                        let end = buffer.line_char_range(line - 1).end;
+                        self.newline(buffer, end, actions)
+                    } else {
+                        // There's no line before to go by, so keep this one's indentation.
+                        let start = line_range.start;
+                        let indent = buffer.slice(start..start + buffer.indent_len(line));
+                        edit(buffer, start..start, &format!("{indent}\n"), actions);
+                        start + indent.chars().count()
+                    };
+                    self.mode = Mode::Insert;
+                    true
+                }
+                'J' => {
+                    for _ in 0..count.saturating_sub(1).max(1) {
+                        let line = buffer.char_to_line(*cursor);
+                        if line + 1 >= buffer.len_lines() {
+                            break;
+                        }
+
+                        let end = buffer.line_char_range(line).end;
+                        let next = buffer.line_char_range(line + 1);
+                        let separator = if end == buffer.line_to_char(line)
+                            || next.start + buffer.indent_len(line + 1) == next.end
+                        {
+                            ""
+                        } else {
+                            " "
+                        };
+                        let indent_end = next.start + buffer.indent_len(line + 1);
+                        edit(buffer, end..indent_end, separator, actions);
+                        *cursor = end;
+                    }
+                    true
+                }
+                'u' => {
+                    actions.extend(std::iter::repeat(Action::Undo).take(count));
+                    return false;
+                }
+                'v' | 'V' => {
+                    self.mode = if c == 'v' {
+                        Mode::Visual
+                    } else {
+                        Mode::VisualLine
+                    };
+                    self.visual = (*cursor, *cursor);
+                    actions.push(self.visual_selection(buffer));
+                    return false;
+                }
+                '.' => {
+                    self.repeat(buffer, cursor, typed_count, actions);
+                    return false;
+                }
+                ':' => {
+                    self.command_line = Some(String::new());
+                    return false;
+                }
+                _ => return false,
+            },
+        };
+
+        if self.mode != Mode::Insert {
+            *cursor = clamp_normal(buffer, *cursor);
+        }
+        actions.push(select(*cursor));
+        changed
+    }
+
+    /// Runs a command in visual mode.
+    fn execute_visual(
+        &mut self,
+        parsed: Parsed,
+        buffer: &mut Buffer,
+        cursor: &mut usize,
+        actions: &mut Vec<Action>,
+    ) {
+        let (anchor, head) = self.visual;
+
+        let operator = match parsed.op {
+            Op::Move(motion) => {
+                if let Some((target, _)) = self.motion(buffer, head, motion, parsed.count) {
+                    self.visual.1 = clamp_normal(buffer, target);
+                }
+                actions.push(self.visual_selection(buffer));
+                return;
+            }
+            Op::Key('o') => {
+                self.visual = (head, anchor);
+                actions.push(self.visual_selection(buffer));
+                return;
+            }
+            Op::Key(c @ ('v' | 'V')) => {
+                let mode = if c == 'v' {
+                    Mode::Visual
+                } else {
+                    Mode::VisualLine
+                };
+                if self.mode == mode {
+                    self.mode = Mode::Normal;
+                    *cursor = head;
+                    actions.push(select(*cursor));
+                } else {
+                    self.mode = mode;
+                    actions.push(self.visual_selection(buffer));
+                }
+                return;
+            }
+            Op::Key('d' | 'x' | 'X' | 'D') => Operator::Delete,
+            Op::Key('c' | 's' | 'C' | 'S') => Operator::Change,
+            Op::Key('y' | 'Y') => Operator::Yank,
+            _ => return,
+        };
+
+        let region = if self.mode == Mode::VisualLine {
+            Region::Lines(
+                buffer.char_to_line(anchor.min(head)),
+                buffer.char_to_line(anchor.max(head)),
+            )
+        } else {
+            Region::Chars(anchor.min(head)..(anchor.max(head) + 1).min(buffer.len_chars()))
+        };
+        self.mode = Mode::Normal;
+        self.recording.clear();
+        self.operate(buffer, cursor, operator, region, parsed.register, actions);
+        if self.mode != Mode::Insert {
+            *cursor = clamp_normal(buffer, *cursor);
+        }
+        actions.push(select(*cursor));
+    }
+
+    /// Replays the last change, with `count` instead of the count it was made with if given.
+    fn repeat(
+        &mut self,
+        buffer: &mut Buffer,
+        cursor: &mut usize,
+        count: Option<usize>,
+        actions: &mut Vec<Action>,
+    ) {
+        let mut keys = self.last_change.clone();
+        if let Some(count) = count {
+            let digits = keys
+                .iter()
+                .take_while(|key| matches!(key, VimKey::Char(c) if c.is_ascii_digit()))
+                .count();
+            keys.splice(..digits, count.to_string().chars().map(VimKey::Char));
+        }
+
+        self.replaying = true;
+        for key in keys {
+            self.handle_key(key, buffer, cursor, actions);
+        }
+        self.replaying = false;
+    }
+
+    /// The selection showing the visual selection.
+    fn visual_selection(&self, buffer: &Buffer) -> Action {
+        let (anchor, head) = self.visual;
+        let len = buffer.len_chars();
+
+        let range = if self.mode == Mode::VisualLine {
+            let anchor_line = buffer.char_to_line(anchor);
+            let head_line = buffer.char_to_line(head);
+            if head_line >= anchor_line {
+                CCursorRange::two(
+                    CCursor::new(buffer.line_to_char(anchor_line)),
+                    CCursor::new(buffer.line_char_range_with_newline(head_line).end),
+                )
+            } else {
+                CCursorRange::two(
+                    CCursor::new(buffer.line_char_range_with_newline(anchor_line).end),
+                    CCursor::new(buffer.line_to_char(head_line)),
+                )
+            }
+        } else if head >= anchor {
+            CCursorRange::two(CCursor::new(anchor), CCursor::new((head + 1).min(len)))
+        } else {
+            CCursorRange::two(CCursor::new((anchor + 1).min(len)), CCursor::new(head))
+        };
+        Action::Select(range)
+    }
+
+    /// Where `motion` moves the cursor from `cursor`, and how it covers the text in between, or
+    /// `None` if it can't move, like `f` when the character isn't on the line.
+    fn motion(
+        &mut self,
+        buffer: &Buffer,
+        cursor: usize,
+        motion: VimMotion,
+        count: Option<usize>,
+    ) -> Option<(usize, Kind)> {
+        let n = count.unwrap_or(1);
+        let line = buffer.char_to_line(cursor);
+        let line_range = buffer.line_char_range(line);
+        let last_line = buffer.len_lines() - 1;
+
+        let target = match motion {
+            VimMotion::Left => (
+                line_range.start.max(cursor.saturating_sub(n)),
+                Kind::Exclusive,
+            ),
+            VimMotion::Right => ((cursor + n).min(line_range.end), Kind::Exclusive),
+            VimMotion::Up | VimMotion::Down => {
+                let column = *self
+                    .column
+                    .get_or_insert(cursor.saturating_sub(line_range.start));
+                let target_line = if motion == VimMotion::Up {
+                    line.checked_sub(n)?
+                } else if line + n <= last_line {
+                    line + n
+                } else {
+                    return None;
+                };
+                let target_range = buffer.line_char_range(target_line);
+                let target = (target_range.start + column).min(target_range.end);
+                (target, Kind::Linewise)
+            }
+            VimMotion::WordForward => {
+                let target = (0..n).fold(cursor, |i, _| next_word_start(buffer, i));
+                (target, Kind::Exclusive)
+            }
+            VimMotion::WordBackward => {
+                let target = (0..n).fold(cursor, |i, _| previous_word_start(buffer, i));
+                (target, Kind::Exclusive)
+            }
+            VimMotion::WordEnd => {
+                let target = (0..n).fold(cursor, |i, _| next_word_end(buffer, i));
+                (target, Kind::Inclusive)
+            }
+            VimMotion::LineStart => (line_range.start, Kind::Exclusive),
+            VimMotion::FirstNonBlank => (first_non_blank(buffer, line), Kind::Exclusive),
+            VimMotion::LineEnd => {
+                let range = buffer.line_char_range((line + n - 1).min(last_line));
+                (
+                    range.end.saturating_sub(1).max(range.start),
+                    Kind::Inclusive,
+                )
+            }
+            VimMotion::FileStart | VimMotion::FileEnd => {
+                let target_line = match (count, motion) {
+                    (Some(count), _) => count.saturating_sub(1).min(last_line),
+                    (None, VimMotion::FileStart) => 0,
+                    (None, _) => last_line,
+                };
+                (first_non_blank(buffer, target_line), Kind::Linewise)
+            }
+            VimMotion::FindForward(c, till) => {
+                let mut from = cursor + 1 + usize::from(till);
+                let mut found = None;
+                for _ in 0..n {
+                    let i = (from..line_range.end).find(|&i| buffer.char_at(i) == Some(c))?;
+                    found = Some(i);
+                    from = i + 1;
+                }
+                let found = found?;
+                (if till { found - 1 } else { found }, Kind::Inclusive)
+            }
+            VimMotion::FindBackward(c, till) => {
+                let mut to = cursor.saturating_sub(usize::from(till));
+                let mut found = None;
+                for _ in 0..n {
+                    let i = (line_range.start..to)
+                        .rev()
+                        .find(|&i| buffer.char_at(i) == Some(c))?;
+                    found = Some(i);
+                    to = i;
+                }
+                let found = found?;
+                (if till { found + 1 } else { found }, Kind::Exclusive)
+            }
+        };
+        Some(target)
+    }
+
+    /// The text `operator` applies to when it's given `motion` at `cursor`.
+    fn motion_region(
+        &mut self,
+        buffer: &Buffer,
+        cursor: usize,
+        operator: Operator,
+        motion: VimMotion,
+        count: Option<usize>,
+    ) -> Option<Region> {
+        let is_blank = |i| buffer.char_at(i).map_or(true, char::is_whitespace);
+
+        // `cw` changes to the end of the word, like `ce`, but doesn't skip to the next word when
+        // the cursor is already at the end of one.
+        if operator == Operator::Change && motion == VimMotion::WordForward && !is_blank(cursor) {
+            let end = (1..count.unwrap_or(1))
+                .fold(word_end(buffer, cursor), |i, _| next_word_end(buffer, i));
+            return Some(Region::Chars(cursor..end + 1));
+        }
+
+        let (target, kind) = self.motion(buffer, cursor, motion, count)?;
+        let (start, end) = (cursor.min(target), cursor.max(target));
+
+        let region = match kind {
+            Kind::Linewise => Region::Lines(buffer.char_to_line(start), buffer.char_to_line(end)),
+            Kind::Inclusive => {
+                let line_end = buffer.line_char_range(buffer.char_to_line(end)).end;
+                Region::Chars(start..(end + 1).min(line_end.max(start)))
+            }
+            Kind::Exclusive => {
+                let mut end = end;
+
+                // A word motion with an operator stops at the end of the line the last word is
+                // on, rather than at the start of the next line.
+                if motion == VimMotion::WordForward {
+                    let mut last = end;
+                    while last > start && is_blank(last - 1) {
+                        last -= 1;
+                    }
+                    if buffer.char_to_line(last) != buffer.char_to_line(end) && last > start {
+                        end = last;
+                    }
+                }
+                Region::Chars(start..end)
+            }
+        };
+        Some(region)
+    }
+
+    /// Applies `operator` to `region`, storing the text in `register`.
+    fn operate(
+        &mut self,
+        buffer: &mut Buffer,
+        cursor: &mut usize,
+        operator: Operator,
+        region: Region,
+        register: Option<char>,
+        actions: &mut Vec<Action>,
+    ) {
+        match region {
+            Region::Chars(range) => {
+                let text = buffer.slice(range.clone());
+                self.store(register, text, false, operator == Operator::Yank, actions);
+
+                if operator != Operator::Yank {
+                    edit(buffer, range.clone(), "", actions);
+                }
+                if operator == Operator::Change {
+                    self.mode = Mode::Insert;
+                }
+                *cursor = range.start;
+            }
+            Region::Lines(first, last) => {
+                let start = buffer.line_to_char(first);
+                let end = buffer.line_char_range_with_newline(last).end;
+                let mut text = buffer.slice(start..end);
+                if !text.ends_with('\n') {
+                    text.push('\n');
+                }
+                self.store(register, text, true, operator == Operator::Yank, actions);
+
+                match operator {
+                    Operator::Yank => *cursor = (*cursor).min(start),
+                    Operator::Delete => {
+                        // Deleting the last line takes the newline before it instead.
+                        let start = if end == buffer.len_chars()
+                            && first > 0
+                            && !buffer.slice(start..end).ends_with('\n')
+                        {
+                            start - 1
+                        } else {
+                            start
+                        };
+                        edit(buffer, start..end, "", actions);
+
+                        let line = first.min(buffer.len_lines() - 1);
+                        *cursor = first_non_blank(buffer, line);
+                    }
+                    Operator::Change => {
+                        // Keep the indentation of the first line.
+                        let indent = buffer.slice(start..start + buffer.indent_len(first));
+                        let content_end = buffer.line_char_range(last).end;
+                        edit(buffer, start..content_end, &indent, actions);
+
+                        *cursor = start + indent.chars().count();
+                        self.mode = Mode::Insert;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Stores text that was yanked or deleted in `register`, and in the unnamed register.
+    fn store(
+        &mut self,
+        register: Option<char>,
+        text: String,
+        linewise: bool,
+        yanked: bool,
+        actions: &mut Vec<Action>,
+    ) {
+        let name = register.unwrap_or('"');
+        if name == '_' {
+            return;
+        }
+        if matches!(name, '+' | '*') {
+            actions.push(Action::Copy(text.clone()));
+        }
+
+        let register = Register { text, linewise };
+        if yanked && name == '"' {
+            self.registers.insert('0', register.clone());
+        }
+        self.registers.insert(name, register.clone());
+        self.registers.insert('"', register);
+    }
+
+    /// Starts a new line at `index`, indented the way Enter indents it, and returns where the
+    /// cursor goes on it.
+    fn newline(&self, buffer: &mut Buffer, index: usize, actions: &mut Vec<Action>) -> usize {
+        let selection = CCursorRange::one(CCursor::new(index));
+        let (text, back) = indent::newline(buffer, selection, self.language, self.indentation);
+        edit(buffer, index..index, &text, actions);
+        index + text.chars().count() - back
+    }
+
+    /// Puts the text in `register` `count` times, after the cursor or before it if `before`.
+    /// Returns whether there was anything to put.
+    fn put(
+        &mut self,
+        buffer: &mut Buffer,
+        cursor: &mut usize,
+        register: Option<char>,
+        before: bool,
+        count: usize,
+        actions: &mut Vec<Action>,
+    ) -> bool {
+        let Some(register) = self.registers.get(&register.unwrap_or('"')) else {
+            return false;
+        };
+        let text = register.text.repeat(count);
+        let line = buffer.char_to_line(*cursor);
+
+        if register.linewise {
+            let target_line = if before { line } else { line + 1 };
+            if target_line < buffer.len_lines() {
+                let at = buffer.line_to_char(target_line);
+                edit(buffer, at..at, &text, actions);
+            } else {
+                // The last line has no newline to put the lines after.
+                let at = buffer.len_chars();
+                let text = format!("\n{}", text.strip_suffix('\n').unwrap_or(&text));
+                edit(buffer, at..at, &text, actions);
+            }
+            *cursor = first_non_blank(buffer, target_line);
+        } else {
+            let line_range = buffer.line_char_range(line);
+            let at = if before || line_range.is_empty() {
+                *cursor
+            } else {
+                (*cursor + 1).min(line_range.end)
+            };
+            edit(buffer, at..at, &text, actions);
+            *cursor = (at + text.chars().count()).saturating_sub(1);
+        }
+        true
+    }
+}
+
+/// Makes an edit to the copy of the buffer the keys are handled against, and has the editor make
+/// it too.
+fn edit(buffer: &mut Buffer, range: Range<usize>, text: &str, actions: &mut Vec<Action>) {
+    if range.is_empty() && text.is_empty() {
+        return;
+    }
+
+    buffer.delete_char_range(range.clone());
+    buffer.insert_text_at(&mut CCursor::new(range.start), text);
+    actions.push(Action::Edit {
+        range,
+        text: text.to_owned(),
+    });
+}
+
+fn select(index: usize) -> Action {
+    Action::Select(CCursorRange::one(CCursor::new(index)))
+}
+
+/// `index` moved onto the last character of its line if it's past it, since the cursor is on a
+/// character rather than between two outside of insert mode.
+fn clamp_normal(buffer: &Buffer, index: usize) -> usize {
+    let range = buffer.line_char_range(buffer.char_to_line(index.min(buffer.len_chars())));
+    if range.is_empty() {
+        range.start
+    } else {
+        index.clamp(range.start, range.end - 1)
+    }
+}
+
+fn first_non_blank(buffer: &Buffer, line: usize) -> usize {
+    let range = buffer.line_char_range(line);
+    (range.start + buffer.indent_len(line)).min(range.end)
+}
+
+/// Whether a character is whitespace (0), part of a word (1), or punctuation (2). Words are
+/// runs of characters of the same class.
+fn class(c: Option<char>) -> u8 {
+    match c {
+        None => 0,
+        Some(c) if c.is_whitespace() => 0,
+        Some(c) if c.is_alphanumeric() || c == '_' => 1,
+        Some(_) => 2,
+    }
+}
+
+/// The start of the word after the one at `index`. Empty lines count as words.
+fn next_word_start(buffer: &Buffer, index: usize) -> usize {
+    let len = buffer.len_chars();
+    let class_at = |i| class(buffer.char_at(i));
+
+    let mut i = index;
+    let start_class = class_at(i);
+    if start_class != 0 {
+        while i < len && class_at(i) == start_class {
+            i += 1;
+        }
+    }
+    while i < len && class_at(i) == 0 {
+        if buffer.char_at(i) == Some('\n') && buffer.char_at(i + 1) == Some('\n') {
+            return i + 1;
+        }
+        i += 1;
+    }
+    i
+}
+
+/// The last character of the word at `index`.
+fn word_end(buffer: &Buffer, index: usize) -> usize {
+    let class_at = |i| class(buffer.char_at(i));
+
+    let mut i = index;
+    while i + 1 < buffer.len_chars() && class_at(i + 1) == class_at(index) {
+        i += 1;
+    }
+    i
+}
+
+/// The last character of the word ending after `index`.
+fn next_word_end(buffer: &Buffer, index: usize) -> usize {
+    let len = buffer.len_chars();
+    let mut i = index + 1;
+    while i < len && class(buffer.char_at(i)) == 0 {
+        i += 1;
+    }
+    if i >= len {
+        return len.saturating_sub(1).max(index);
+    }
+    word_end(buffer, i)
+}
+
+/// The start of the word starting before `index`.
+fn previous_word_start(buffer: &Buffer, index: usize) -> usize {
+    let class_at = |i| class(buffer.char_at(i));
+    if index == 0 {
+        return 0;
+    }
+
+    let mut i = index - 1;
+    while i > 0 && class_at(i) == 0 {
+        i -= 1;
+    }
+    let word_class = class_at(i);
+    while i > 0 && class_at(i - 1) == word_class {
+        i -= 1;
+    }
+    i
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Types `keys` into a new Vim mode with the cursor at `cursor` in `text`, with Escape written
+    /// as `\x1b`, returning the text and cursor after, and the actions that weren't edits or
+    /// cursor movements.
+    fn type_keys(text: &str, cursor: usize, keys: &str) -> (String, usize, Vec<Action>) {
+        type_keys_into(Vim::default(), text, cursor, keys)
+    }
+
+    /// Types `keys` like `type_keys` does, into `vim` as it is.
+    fn type_keys_into(
+        mut vim: Vim,
+        text: &str,
+        cursor: usize,
+        keys: &str,
+    ) -> (String, usize, Vec<Action>) {
+        let mut buffer = Buffer::new(text);
+        let mut cursor = cursor;
+        let mut other = Vec::new();
+
+        for c in keys.chars() {
+            let key = match c {
+                '\x1b' => VimKey::Escape,
+                '\n' => VimKey::Enter,
+                c => VimKey::Char(c),
+            };
+
+            let ccursor = CCursor::new(cursor);
+            let indentation = Indentation::default();
+            for action in vim.handle(key, &buffer, ccursor, Language::PlainText, indentation) {
+                match action {
+                    Action::Select(range) => cursor = range.primary.index,
+                    Action::Edit { range, text } => {
+                        buffer.delete_char_range(range.clone());
+                        buffer.insert_text_at(&mut CCursor::new(range.start), &text);
+                    }
+                    action => other.push(action),
+                }
+            }
+        }
+
+        (buffer.slice(0..buffer.len_chars()), cursor, other)
+    }
+
+    fn text_after(text: &str, cursor: usize, keys: &str) -> String {
+        type_keys(text, cursor, keys).0
+    }
+
+    fn cursor_after(text: &str, cursor: usize, keys: &str) -> usize {
+        type_keys(text, cursor, keys).1
+    }
+
+    #[test]
+    fn motions_take_counts() {
+        let text = "one two.three four\nfive";
+        assert_eq!(cursor_after(text, 0, "w"), 4);
+        assert_eq!(cursor_after(text, 0, "2w"), 7);
+        assert_eq!(cursor_after(text, 0, "e"), 2);
+        assert_eq!(cursor_after(text, 8, "b"), 7);
+        assert_eq!(cursor_after(text, 0, "$"), 17);
+        assert_eq!(cursor_after(text, 10, "0"), 0);
+        assert_eq!(cursor_after(text, 0, "G"), 19);
+        assert_eq!(cursor_after(text, 20, "gg"), 0);
+        assert_eq!(cursor_after(text, 0, "fh"), 9);
+        assert_eq!(cursor_after(text, 0, "2fo"), 15);
+        assert_eq!(cursor_after(text, 0, "tw"), 4);
+        assert_eq!(cursor_after(text, 0, "fz"), 0);
+        assert_eq!(cursor_after(text, 2, "j"), 21);
+    }
+
+    #[test]
+    fn operators_apply_to_motions() {
+        let text = "one two three\nfour";
+        assert_eq!(text_after(text, 0, "dw"), "two three\nfour");
+        assert_eq!(text_after(text, 0, "2dw"), "three\nfour");
+        assert_eq!(text_after(text, 0, "d2w"), "three\nfour");
+        assert_eq!(text_after(text, 8, "dw"), "one two \nfour");
+        assert_eq!(text_after(text, 0, "de"), " two three\nfour");
+        assert_eq!(text_after(text, 4, "d$"), "one \nfour");
+        assert_eq!(text_after(text, 4, "d0"), "two three\nfour");
+        assert_eq!(text_after(text, 0, "dtt"), "two three\nfour");
+        assert_eq!(text_after(text, 0, "dft"), "wo three\nfour");
+        assert_eq!(text_after(text, 0, "dd"), "four");
+        assert_eq!(text_after(text, 15, "dd"), "one two three");
+        assert_eq!(text_after(text, 0, "dG"), "");
+        assert_eq!(text_after(text, 0, "cwONE\x1b"), "ONE two three\nfour");
+        assert_eq!(text_after(text, 4, "ccnew\x1b"), "new\nfour");
+        assert_eq!(text_after(text, 0, "x"), "ne two three\nfour");
+        assert_eq!(text_after(text, 0, "3x"), " two three\nfour");
+    }
+
+    #[test]
+    fn yanks_are_put_from_registers() {
+        let text = "one\ntwo";
+        assert_eq!(text_after(text, 0, "yyp"), "one\none\ntwo");
+        assert_eq!(text_after(text, 0, "yyjp"), "one\ntwo\none");
+        assert_eq!(text_after(text, 0, "ywP"), "oneone\ntwo");
+        assert_eq!(text_after(text, 0, "\"ayyjddk\"ap"), "one\none");
+        assert_eq!(text_after(text, 0, "\"ayyjdd\"0P"), "one");
+    }
+
+    #[test]
+    fn clipboard_registers_put_the_clipboard() {
+        let mut vim = Vim::default();
+        vim.set_clipboard("x\r\ny\n");
+        let (text, _, _) = type_keys_into(vim, "one\ntwo", 0, "\"+p");
+        assert_eq!(text, "one\nx\ny\ntwo");
+
+        let mut vim = Vim::default();
+        vim.set_clipboard("ab");
+        let (text, _, _) = type_keys_into(vim, "one", 0, "\"*P");
+        assert_eq!(text, "abone");
+
+        // Yanking into them copies to the clipboard too.
+        let (text, _, other) = type_keys("one", 0, "\"+yw\"+P");
+        assert_eq!(text, "oneone");
+        assert!(other.contains(&Action::Copy("one".to_owned())));
+    }
+
+    #[test]
+    fn dot_repeats_the_last_change() {
+        let text = "one two three four";
+        assert_eq!(text_after(text, 0, "dw."), "three four");
+        assert_eq!(text_after(text, 0, "dw2."), "four");
+        assert_eq!(text_after(text, 0, "cwfoo\x1bw."), "foo foo three four");
+        assert_eq!(
+            text_after(text, 0, "Aend\x1b0."),
+            "one two three fourendend"
+        );
+        assert_eq!(text_after(text, 0, "xw."), "ne wo three four");
+    }
+
+    #[test]
+    fn new_lines_are_indented() {
+        let text = "fn f() {\n    one\n}";
+        assert_eq!(text_after(text, 9, "ox\x1b"), "fn f() {\n    one\n    x\n}");
+        assert_eq!(text_after(text, 0, "ox\x1b"), "fn f() {\n    x\n    one\n}");
+        assert_eq!(
+            text_after(text, 18, "Ox\x1b"),
+            "fn f() {\n    one\n    x\n}"
+        );
+        assert_eq!(text_after(text, 9, "Ox\x1b"), "fn f() {\n    x\n    one\n}");
+        assert_eq!(text_after("  one", 0, "Ox\x1b"), "  x\n  one");
+        assert_eq!(text_after("  one", 3, "a\ntwo\x1b"), "  on\n  twoe");
+        assert_eq!(
+            text_after(text, 9, "ox\x1bj."),
+            "fn f() {\n    one\n    x\n}\nx"
+        );
+    }
+
+    #[test]
+    fn changes_are_marked_from_the_command_to_the_end_of_insert_mode() {
+        let changes = |keys| type_keys("one two\nthree\nfour", 0, keys).2;
+        let change = [Action::StartChange, Action::EndChange];
+
+        assert_eq!(changes("cw"), [Action::StartChange]);
+        assert_eq!(changes("cwONE\x1b"), change);
+        assert_eq!(changes("ofive\x1b"), change);
+        assert_eq!(changes("3J"), change);
+        assert_eq!(changes("dw."), [change.clone(), change].concat());
+        assert_eq!(changes("yyw"), []);
+    }
+
+    #[test]
+    fn visual_mode_selects_characters_and_lines() {
+        let text = "one two\nthree\nfour";
+        assert_eq!(text_after(text, 0, "vwd"), "wo\nthree\nfour");
+        assert_eq!(text_after(text, 0, "Vjd"), "four");
+        assert_eq!(text_after(text, 4, "vey$p"), "one twotwo\nthree\nfour");
+        assert_eq!(text_after(text, 0, "vecONE\x1b"), "ONE two\nthree\nfour");
+    }
+
+    #[test]
+    fn command_line_runs_commands() {
+        assert_eq!(type_keys("", 0, ":w\n").2, [Action::Run(Command::Save)]);
+        assert_eq!(
+            type_keys("", 0, ":wq\n").2,
+            [Action::Run(Command::Save), Action::Run(Command::Quit)]
+        );
+        assert_eq!(cursor_after("one\ntwo", 0, ":2\n"), 4);
+    }
+}